@@ -4,12 +4,14 @@ use axum::{
     routing::post,
     Json, Router,
 };
-use protoviz::descriptor::ProtoDescriptor;
+use protoviz::descriptor::{ProtoDescriptor, StackDescriptor};
 
 #[tokio::main]
 async fn main() {
     // build our application with a route
-    let app = Router::new().route("/", post(handler));
+    let app = Router::new()
+        .route("/", post(handler))
+        .route("/stack", post(stack_handler));
 
     // run it
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
@@ -20,7 +22,19 @@ async fn main() {
 }
 
 async fn handler(Json(descriptor): Json<ProtoDescriptor>) -> Result<Html<String>, StatusCode> {
-    let result = match protoviz::render(&descriptor) {
+    respond(protoviz::render(&descriptor))
+}
+
+async fn stack_handler(
+    Json(descriptor): Json<StackDescriptor>,
+) -> Result<Html<String>, StatusCode> {
+    respond(protoviz::render_stack(&descriptor))
+}
+
+fn respond(
+    result: std::result::Result<String, protoviz::errors::Error>,
+) -> Result<Html<String>, StatusCode> {
+    let result = match result {
         Ok(result) => result,
         Err(e) => {
             println!("Error: {:?}", e);
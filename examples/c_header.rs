@@ -0,0 +1,17 @@
+use std::{env, fs};
+
+use protoviz::import::c::from_c_header;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 4 {
+        eprintln!("Usage: {} <header.h> <struct> <output.svg>", args[0]);
+        std::process::exit(1);
+    }
+
+    let source = fs::read_to_string(&args[1]).expect("Failed to read the header");
+    let descriptor = from_c_header(&source, &args[2]).expect("Failed to import the struct");
+
+    let svg = protoviz::render(&descriptor).expect("Failed to render the image");
+    fs::write(&args[3], svg).expect("Failed to write the image");
+}
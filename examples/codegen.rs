@@ -0,0 +1,34 @@
+use std::{env, fs};
+
+use protoviz::codegen::{c::to_c, lua::to_lua, rust::to_rust, Unit};
+use protoviz::descriptor::ProtoDescriptor;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 5 {
+        eprintln!(
+            "Usage: {} <rust|c|lua> <descriptor.json> <name> <bits|bytes>",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+
+    let json = fs::read_to_string(&args[2]).expect("Failed to read the descriptor");
+    let descriptor: ProtoDescriptor =
+        serde_json::from_str(&json).expect("Failed to parse the descriptor");
+
+    let unit = match args[4].as_str() {
+        "bits" => Unit::Bits,
+        "bytes" => Unit::Bytes,
+        unit => panic!("Unknown unit {}", unit),
+    };
+
+    let code = match args[1].as_str() {
+        "rust" => to_rust(&descriptor, &args[3], unit),
+        "c" => to_c(&descriptor, &args[3], unit),
+        "lua" => to_lua(&descriptor, &args[3], unit),
+        language => panic!("Unknown language {}", language),
+    };
+
+    print!("{}", code.expect("Failed to generate the code"));
+}
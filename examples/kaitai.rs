@@ -0,0 +1,17 @@
+use std::{env, fs};
+
+use protoviz::import::kaitai::from_ksy;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <format.ksy> <output.svg>", args[0]);
+        std::process::exit(1);
+    }
+
+    let yaml = fs::read_to_string(&args[1]).expect("Failed to read the Kaitai Struct file");
+    let descriptor = from_ksy(&yaml).expect("Failed to import the Kaitai Struct file");
+
+    let svg = protoviz::render(&descriptor).expect("Failed to render the image");
+    fs::write(&args[2], svg).expect("Failed to write the image");
+}
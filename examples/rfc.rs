@@ -0,0 +1,18 @@
+use std::{env, fs};
+
+use protoviz::descriptor::ProtoDescriptor;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <diagram.txt> <output.svg>", args[0]);
+        std::process::exit(1);
+    }
+
+    let diagram = fs::read_to_string(&args[1]).expect("Failed to read the diagram");
+    let descriptor =
+        ProtoDescriptor::from_rfc_ascii(&diagram).expect("Failed to parse the diagram");
+
+    let svg = protoviz::render(&descriptor).expect("Failed to render the image");
+    fs::write(&args[2], svg).expect("Failed to write the image");
+}
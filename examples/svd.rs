@@ -0,0 +1,29 @@
+use std::{env, fs, path::Path};
+
+use protoviz::import::svd::from_svd;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <device.svd> <output directory>", args[0]);
+        std::process::exit(1);
+    }
+
+    let xml = fs::read_to_string(&args[1]).expect("Failed to read the SVD file");
+    let registers = from_svd(&xml).expect("Failed to import the SVD file");
+
+    // Render a diagram for each register, in a directory per peripheral
+    for register in registers {
+        let dir = Path::new(&args[2]).join(&register.peripheral);
+        fs::create_dir_all(&dir).expect("Failed to create the output directory");
+
+        match protoviz::render(&register.descriptor) {
+            Ok(svg) => {
+                let path = dir.join(format!("{}.svg", register.name));
+                fs::write(&path, svg).expect("Failed to write the image");
+                println!("{}", path.display());
+            }
+            Err(e) => eprintln!("{}.{}: {:?}", register.peripheral, register.name, e),
+        }
+    }
+}
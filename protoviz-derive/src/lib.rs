@@ -0,0 +1,201 @@
+//! # ProtoViz Derive
+//!
+//! Derive macro implementing `protoviz::ProtoViz` for wire structs, so their diagrams never drift from the types.
+//!
+//! Fields take the size of their type, unless overridden with `#[protoviz(bits = 4)]` or
+//! `#[protoviz(variable = "N")]`. Colors are set with `#[protoviz(color = "#ff0")]`, and doc comments
+//! become the field descriptions. The fields are drawn in declaration order, so the structs are expected
+//! to be `#[repr(C, packed)]`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Expr, Fields, Lit, LitInt, LitStr, Meta};
+
+/// Options of a field, from its `protoviz` attributes and doc comments
+#[derive(Default)]
+struct FieldOptions {
+    bits: Option<usize>,
+    variable: Option<String>,
+    color: Option<(u8, u8, u8)>,
+    description: Option<String>,
+}
+
+#[proc_macro_derive(ProtoViz, attributes(protoviz))]
+pub fn derive_protoviz(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "ProtoViz can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "ProtoViz can only be derived for structs",
+            ))
+        }
+    };
+
+    if fields.is_empty() {
+        return Err(Error::new_spanned(&input.ident, "Struct has no fields"));
+    }
+
+    let options = fields
+        .iter()
+        .map(|field| field_options(&field.attrs))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Lengths are in bits if any field is bit-sized, otherwise in bytes
+    let byte = if options.iter().any(|o| o.bits.is_some()) {
+        8usize
+    } else {
+        1usize
+    };
+
+    let fields = fields.iter().zip(options).map(|(field, options)| {
+        let name = field.ident.as_ref().unwrap().to_string();
+        let ty = &field.ty;
+
+        let length = match (options.bits, options.variable) {
+            (Some(bits), _) => quote! { ::protoviz::descriptor::FieldLength::Fixed(#bits) },
+            (None, Some(variable)) => quote! {
+                ::protoviz::descriptor::FieldLength::Variable(::std::string::String::from(#variable))
+            },
+            (None, None) => quote! {
+                ::protoviz::descriptor::FieldLength::Fixed(::core::mem::size_of::<#ty>() * #byte)
+            },
+        };
+
+        let color = options.color.map(|(r, g, b)| {
+            quote! { field.color = ::std::option::Option::Some(::protoviz::__private::HexColor::rgb(#r, #g, #b)); }
+        });
+
+        let description = options.description.map(|description| {
+            quote! { field.description = ::std::option::Option::Some(::std::string::String::from(#description)); }
+        });
+
+        quote! {
+            let mut field = ::protoviz::descriptor::FieldDescriptor::new(#name, #length);
+            #color
+            #description
+            fields.push(field);
+        }
+    });
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::protoviz::ProtoViz for #ident #ty_generics #where_clause {
+            fn descriptor() -> ::protoviz::descriptor::ProtoDescriptor {
+                let mut fields = ::std::vec::Vec::new();
+                #(#fields)*
+
+                ::protoviz::descriptor::ProtoDescriptor {
+                    fields,
+                    ..::std::default::Default::default()
+                }
+            }
+        }
+    })
+}
+
+/// Parse the `protoviz` attributes and doc comments of a field
+fn field_options(attrs: &[syn::Attribute]) -> Result<FieldOptions, Error> {
+    let mut options = FieldOptions::default();
+    let mut docs = Vec::new();
+
+    for attr in attrs {
+        if attr.path().is_ident("doc") {
+            if let Meta::NameValue(meta) = &attr.meta {
+                if let Expr::Lit(expr) = &meta.value {
+                    if let Lit::Str(doc) = &expr.lit {
+                        docs.push(doc.value().trim().to_owned());
+                    }
+                }
+            }
+            continue;
+        }
+
+        if !attr.path().is_ident("protoviz") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("bits") {
+                let bits = meta.value()?.parse::<LitInt>()?;
+                let value = bits.base10_parse::<usize>()?;
+                if value == 0 {
+                    return Err(Error::new_spanned(bits, "Field length cannot be zero"));
+                }
+                options.bits = Some(value);
+            } else if meta.path.is_ident("variable") {
+                let variable = meta.value()?.parse::<LitStr>()?;
+                if variable.value().is_empty() {
+                    return Err(Error::new_spanned(variable, "Field length cannot be empty"));
+                }
+                options.variable = Some(variable.value());
+            } else if meta.path.is_ident("color") {
+                let color = meta.value()?.parse::<LitStr>()?;
+                options.color = Some(
+                    parse_color(&color.value())
+                        .ok_or_else(|| Error::new_spanned(&color, "Invalid hex color"))?,
+                );
+            } else {
+                return Err(meta.error("Unknown protoviz attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    if options.bits.is_some() && options.variable.is_some() {
+        return Err(Error::new_spanned(
+            &attrs[0],
+            "Field cannot have both a bit length and a variable length",
+        ));
+    }
+
+    let description = docs
+        .iter()
+        .filter(|doc| !doc.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ");
+    options.description = (!description.is_empty()).then_some(description);
+
+    Ok(options)
+}
+
+/// Parse a `#rgb` or `#rrggbb` color
+fn parse_color(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.strip_prefix('#')?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let channel = |i: usize, width: usize| {
+        let value = u8::from_str_radix(&hex[i * width..(i + 1) * width], 16).ok()?;
+        Some(if width == 1 { value * 17 } else { value })
+    };
+
+    let width = match hex.len() {
+        3 => 1,
+        6 => 2,
+        _ => return None,
+    };
+
+    Some((channel(0, width)?, channel(1, width)?, channel(2, width)?))
+}
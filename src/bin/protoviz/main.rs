@@ -0,0 +1,258 @@
+//! Command line interface of ProtoViz
+
+mod pcap;
+
+use std::io::Read;
+use std::path::Path;
+use std::{env, fs, io, process};
+
+use protoviz::codegen::Unit;
+use protoviz::compat::{check, Compatibility};
+use protoviz::decode::{decode, hex_dump};
+use protoviz::descriptor::ProtoDescriptor;
+use protoviz::diff::{diff, diff_descriptor, summary};
+use protoviz::errors::Error;
+use protoviz::lint::{lint, Level, LintConfig};
+use protoviz::render;
+
+const USAGE: &str = "Usage: protoviz <command> [options]
+
+Commands:
+  decode <descriptor> <data> [--unit bits|bytes]
+      Print the data (a file, or - for stdin) as a hex dump annotated with the descriptor fields
+  pcap <capture> --descriptor <descriptor> [--offset <bytes>] [--output <directory> | --html <report>]
+      Decode the payload of every packet of a pcap or pcapng file, starting at the given offset,
+      and render a diagram with its values per packet (in the output directory) or an HTML report
+  diff <old descriptor> <new descriptor> [--output <diagram>]
+      Render the new version with its changes highlighted (in diff.svg by default), and print how
+      the offset of every field shifted
+  check <old descriptor> <new descriptor> [--format text|json]
+      Classify the changes of the new version as compatible or breaking, exiting with status 2
+      if any of them breaks the wire format
+  lint <descriptor> [--allow <rule>] [--warn <rule>] [--deny <rule>] [--format text|json]
+      Flag suspicious parts of the descriptor, exiting with status 2 if a denied rule matches.
+      The rules are undefined-length, unaligned-field, unaligned-total, trailing-wrap and
+      duplicate-name
+
+Descriptors are read as YAML if their extension is .yaml or .yml, and as JSON otherwise.
+Lengths are in bits unless --unit bytes is given.";
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    let result = match args.first().map(String::as_str) {
+        Some("decode") => run_decode(&args[1..]),
+        Some("pcap") => pcap::run(&args[1..]),
+        Some("diff") => run_diff(&args[1..]),
+        Some("check") => run_check(&args[1..]),
+        Some("lint") => run_lint(&args[1..]),
+        Some("-h" | "--help") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(USAGE.to_owned()),
+    };
+
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+fn run_decode(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args, &[])?;
+    let [descriptor, data] = options.paths.as_slice() else {
+        return Err(USAGE.to_owned());
+    };
+
+    let descriptor = load_descriptor(descriptor)?;
+    let data = read_data(data)?;
+
+    let fields = decode(&descriptor, &data, options.unit).map_err(message)?;
+    print!("{}", hex_dump(&fields));
+
+    Ok(())
+}
+
+fn run_diff(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args, &["--output"])?;
+    let [old, new] = options.paths.as_slice() else {
+        return Err(USAGE.to_owned());
+    };
+
+    let old = load_descriptor(old)?;
+    let new = load_descriptor(new)?;
+
+    let output = options.value("--output").unwrap_or("diff.svg");
+    let svg = render(&diff_descriptor(&old, &new).map_err(message)?).map_err(message)?;
+    fs::write(output, svg).map_err(|e| format!("Failed to write {}: {}", output, e))?;
+
+    print!("{}", summary(&diff(&old, &new).map_err(message)?));
+
+    Ok(())
+}
+
+fn run_check(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args, &["--format"])?;
+    let [old, new] = options.paths.as_slice() else {
+        return Err(USAGE.to_owned());
+    };
+
+    let report = check(&load_descriptor(old)?, &load_descriptor(new)?).map_err(message)?;
+
+    match options.value("--format").unwrap_or("text") {
+        "text" => {
+            for finding in &report.findings {
+                let compatibility = match finding.compatibility {
+                    Compatibility::Compatible => "compatible",
+                    Compatibility::Breaking => "breaking",
+                };
+                println!(
+                    "{:10} {:16} {}",
+                    compatibility, finding.rule, finding.message
+                );
+            }
+            if report.is_breaking() {
+                println!("The new version breaks the wire format");
+            } else {
+                println!("The new version is compatible");
+            }
+        }
+        "json" => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        _ => return Err("Format has to be text or json".to_owned()),
+    }
+
+    // Distinct from the errors, so scripts can tell a breaking change from a failed check
+    if report.is_breaking() {
+        process::exit(2);
+    }
+
+    Ok(())
+}
+
+fn run_lint(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args, &["--allow", "--warn", "--deny", "--format"])?;
+    let [descriptor] = options.paths.as_slice() else {
+        return Err(USAGE.to_owned());
+    };
+
+    // Later options override the earlier ones for the same rule
+    let mut config = LintConfig::default();
+    for (name, rule) in &options.values {
+        let level = match *name {
+            "--allow" => Level::Allow,
+            "--warn" => Level::Warn,
+            "--deny" => Level::Deny,
+            _ => continue,
+        };
+        config = config.set(rule.parse().map_err(message)?, level);
+    }
+
+    let lints = lint(&load_descriptor(descriptor)?, options.unit, &config).map_err(message)?;
+
+    match options.value("--format").unwrap_or("text") {
+        "text" => {
+            for lint in &lints {
+                let level = match lint.level {
+                    Level::Deny => "error",
+                    _ => "warning",
+                };
+                println!("{}[{}]: {}", level, lint.rule, lint.message);
+            }
+        }
+        "json" => println!("{}", serde_json::to_string_pretty(&lints).unwrap()),
+        _ => return Err("Format has to be text or json".to_owned()),
+    }
+
+    if lints.iter().any(|lint| lint.level == Level::Deny) {
+        process::exit(2);
+    }
+
+    Ok(())
+}
+
+/// Arguments of a command
+struct Options<'a> {
+    paths: Vec<&'a str>,
+    unit: Unit,
+    values: Vec<(&'a str, &'a str)>, // Options taking a value, other than the unit
+}
+
+impl<'a> Options<'a> {
+    /// Split the arguments into the positional ones, the length unit and the given options
+    fn parse(args: &'a [String], names: &[&str]) -> Result<Self, String> {
+        let mut options = Options {
+            paths: Vec::new(),
+            unit: Unit::Bits,
+            values: Vec::new(),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--unit" => {
+                    options.unit = match args.next().map(String::as_str) {
+                        Some("bits") => Unit::Bits,
+                        Some("bytes") => Unit::Bytes,
+                        _ => return Err("Unit has to be bits or bytes".to_owned()),
+                    }
+                }
+                name if names.contains(&name) => match args.next() {
+                    Some(value) => options.values.push((name, value)),
+                    None => return Err(format!("Option {} needs a value", name)),
+                },
+                option if option.starts_with("--") => {
+                    return Err(format!("Unknown option {}", option))
+                }
+                path => options.paths.push(path),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Value of an option, if it was given
+    fn value(&self, name: &str) -> Option<&'a str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(option, _)| *option == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Read a descriptor, as YAML or JSON depending on its extension
+fn load_descriptor(path: &str) -> Result<ProtoDescriptor, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+    let yaml = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension == "yaml" || extension == "yml");
+
+    if yaml {
+        serde_yaml::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path, e))
+    } else {
+        serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path, e))
+    }
+}
+
+/// Read binary data from a file, or from stdin if the path is "-"
+fn read_data(path: &str) -> Result<Vec<u8>, String> {
+    if path == "-" {
+        let mut data = Vec::new();
+        io::stdin()
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Failed to read stdin: {}", e))?;
+        Ok(data)
+    } else {
+        fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))
+    }
+}
+
+/// Message of a library error
+fn message(error: Error) -> String {
+    match error {
+        Error::FormatError(message) => message,
+        error => error.to_string(),
+    }
+}
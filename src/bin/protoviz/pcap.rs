@@ -0,0 +1,212 @@
+//! Rendering of the packets of a capture file, as diagrams with their values
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use protoviz::codegen::Unit;
+use protoviz::decode::{annotate, decode, hex_dump, DecodedField};
+use protoviz::descriptor::ProtoDescriptor;
+use protoviz::pcap::{read_capture, Packet};
+use protoviz::render_scoped;
+
+use crate::{load_descriptor, message, Options, USAGE};
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args, &["--descriptor", "--offset", "--output", "--html"])?;
+    let [capture] = options.paths.as_slice() else {
+        return Err(USAGE.to_owned());
+    };
+
+    let descriptor_path = options
+        .value("--descriptor")
+        .ok_or_else(|| "Missing --descriptor option".to_owned())?;
+    let descriptor = load_descriptor(descriptor_path)?;
+    let offset = match options.value("--offset") {
+        Some(offset) => offset
+            .parse::<usize>()
+            .map_err(|_| "Offset has to be a number of bytes".to_owned())?,
+        None => 0,
+    };
+
+    let data = fs::read(capture).map_err(|e| format!("Failed to read {}: {}", capture, e))?;
+    let packets = read_capture(&data).map_err(message)?;
+
+    let results = packets
+        .iter()
+        .enumerate()
+        .map(|(i, packet)| render_packet(&descriptor, packet, i + 1, offset, options.unit))
+        .collect::<Vec<_>>();
+
+    if let Some(report) = options.value("--html") {
+        let title = Path::new(capture)
+            .file_name()
+            .map_or(capture.to_string(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        let summary = format!(
+            "{} packets, decoded with {} from byte {}",
+            packets.len(),
+            descriptor_path,
+            offset
+        );
+
+        let html = html_report(&title, &summary, &packets, &results);
+        fs::write(report, html).map_err(|e| format!("Failed to write {}: {}", report, e))?;
+    } else {
+        let directory = options.value("--output").unwrap_or(".");
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("Failed to create {}: {}", directory, e))?;
+
+        for (i, result) in results.iter().enumerate() {
+            match result {
+                Ok((_, svg)) => {
+                    let path = Path::new(directory).join(format!("packet-{:04}.svg", i + 1));
+                    fs::write(&path, svg)
+                        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+                }
+                Err(error) => eprintln!("Packet {}: {}", i + 1, error),
+            }
+        }
+    }
+
+    let rendered = results.iter().filter(|result| result.is_ok()).count();
+    eprintln!("Rendered {} of {} packets", rendered, packets.len());
+
+    Ok(())
+}
+
+/// Decode the payload of a packet, and render it with its values
+///
+/// Each packet is scoped by its number, as the HTML report holds all of them in the same page.
+fn render_packet(
+    descriptor: &ProtoDescriptor,
+    packet: &Packet,
+    number: usize,
+    offset: usize,
+    unit: Unit,
+) -> Result<(Vec<DecodedField>, String), String> {
+    let payload = packet.data.get(offset..).ok_or_else(|| {
+        format!(
+            "Packet of {} bytes is shorter than the offset",
+            packet.data.len()
+        )
+    })?;
+
+    let fields = decode(descriptor, payload, unit).map_err(message)?;
+    let svg = render_scoped(
+        &annotate(descriptor, &fields, unit),
+        &format!("pv-packet-{}", number),
+    )
+    .map_err(message)?;
+
+    Ok((fields, svg))
+}
+
+/// HTML page with the diagram and hex dump of every packet
+fn html_report(
+    title: &str,
+    summary: &str,
+    packets: &[Packet],
+    results: &[Result<(Vec<DecodedField>, String), String>],
+) -> String {
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>").unwrap();
+    writeln!(html, "<html>").unwrap();
+    writeln!(html, "<head>").unwrap();
+    writeln!(html, "<meta charset=\"utf-8\">").unwrap();
+    writeln!(html, "<title>{}</title>", escape(title)).unwrap();
+    writeln!(html, "<style>").unwrap();
+    writeln!(html, "body {{ font-family: sans-serif; margin: 2em; }}").unwrap();
+    writeln!(html, "section {{ margin-bottom: 3em; }}").unwrap();
+    writeln!(html, "svg {{ max-width: 100%; height: auto; }}").unwrap();
+    writeln!(
+        html,
+        "pre {{ background: #f4f4f4; padding: 1em; overflow-x: auto; }}"
+    )
+    .unwrap();
+    writeln!(html, ".error {{ color: #b00020; }}").unwrap();
+    writeln!(html, "</style>").unwrap();
+    writeln!(html, "</head>").unwrap();
+    writeln!(html, "<body>").unwrap();
+    writeln!(html, "<h1>{}</h1>", escape(title)).unwrap();
+    writeln!(html, "<p>{}</p>", escape(summary)).unwrap();
+
+    for (i, (packet, result)) in packets.iter().zip(results).enumerate() {
+        writeln!(html, "<section id=\"packet-{}\">", i + 1).unwrap();
+        writeln!(html, "<h2>Packet {}</h2>", i + 1).unwrap();
+        writeln!(
+            html,
+            "<p>Time {}.{:09}, {} of {} bytes captured</p>",
+            packet.seconds,
+            packet.nanoseconds,
+            packet.data.len(),
+            packet.length
+        )
+        .unwrap();
+
+        match result {
+            Ok((fields, svg)) => {
+                writeln!(html, "{}", svg.trim()).unwrap();
+                writeln!(html, "<details>").unwrap();
+                writeln!(html, "<summary>Hex dump</summary>").unwrap();
+                writeln!(html, "<pre>{}</pre>", escape(&hex_dump(fields))).unwrap();
+                writeln!(html, "</details>").unwrap();
+            }
+            Err(error) => writeln!(html, "<p class=\"error\">{}</p>", escape(error)).unwrap(),
+        }
+
+        writeln!(html, "</section>").unwrap();
+    }
+
+    writeln!(html, "</body>").unwrap();
+    writeln!(html, "</html>").unwrap();
+
+    html
+}
+
+/// Escape the HTML special characters of a text
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_scopes() {
+        let descriptor: ProtoDescriptor = serde_json::from_str(
+            r#"{"fields": [
+                {"name": "kind", "length": 8, "pattern": "hatched"},
+                {"name": "flags", "length": 8, "pattern": "dotted"}
+            ]}"#,
+        )
+        .unwrap();
+        let packet = |data: Vec<u8>| Packet {
+            seconds: 0,
+            nanoseconds: 0,
+            link_type: 1,
+            length: data.len(),
+            data,
+        };
+        let packets = [packet(vec![1, 2]), packet(vec![3, 4])];
+
+        let results = packets
+            .iter()
+            .enumerate()
+            .map(|(i, packet)| render_packet(&descriptor, packet, i + 1, 0, Unit::Bits))
+            .collect::<Vec<_>>();
+        let html = html_report("capture", "2 packets", &packets, &results);
+
+        // Each packet defines and references its own patterns, with styles scoped to its diagram
+        assert!(html.contains("id=\"pv-packet-1-pattern-0\""));
+        assert!(html.contains("id=\"pv-packet-2-pattern-0\""));
+        assert!(html.contains("url(#pv-packet-2-pattern-1)"));
+        assert!(html.contains("class=\"pv-diagram pv-packet-2\""));
+        assert!(!html.contains("\"pv-pattern-0\""));
+    }
+}
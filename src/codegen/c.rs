@@ -0,0 +1,345 @@
+//! C code generator, creating a header with the offset macros and the packing functions of the fields
+
+use std::fmt::Write;
+
+use super::{code_fields, identifier, Unit};
+use crate::descriptor::{BitOrder, Endianness, FieldDescriptor, FieldLength, ProtoDescriptor};
+use crate::errors::Error;
+
+// Shared by the generated headers, so it is guarded separately
+const RUNTIME: &str = r#"#ifndef PROTOVIZ_PACKING
+#define PROTOVIZ_PACKING
+
+static inline uint64_t pv_swap(uint64_t value, unsigned bytes)
+{
+    uint64_t swapped = 0;
+    unsigned i;
+
+    for (i = 0; i < bytes; i++) {
+        swapped = (swapped << 8) | (value & 0xff);
+        value >>= 8;
+    }
+
+    return swapped;
+}
+
+static inline uint64_t pv_unpack(const uint8_t *buf, size_t offset, unsigned bits, int little, int lsb_first)
+{
+    uint64_t value = 0;
+    unsigned i;
+
+    for (i = 0; i < bits; i++) {
+        size_t bit = offset + i;
+
+        if (lsb_first)
+            value |= (uint64_t)((buf[bit / 8] >> (bit % 8)) & 1) << i;
+        else
+            value = (value << 1) | ((buf[bit / 8] >> (7 - bit % 8)) & 1);
+    }
+
+    /* The bit order gives the byte order, unless the value is stored with the other one */
+    if (bits > 8 && bits % 8 == 0 && little != lsb_first)
+        value = pv_swap(value, bits / 8);
+
+    return value;
+}
+
+static inline void pv_pack(uint8_t *buf, size_t offset, unsigned bits, uint64_t value, int little, int lsb_first)
+{
+    unsigned i;
+
+    if (bits > 8 && bits % 8 == 0 && little != lsb_first)
+        value = pv_swap(value, bits / 8);
+
+    for (i = 0; i < bits; i++) {
+        size_t bit = offset + i;
+        uint8_t mask = lsb_first ? (uint8_t)(1u << (bit % 8)) : (uint8_t)(0x80u >> (bit % 8));
+        unsigned set = lsb_first ? (value >> i) & 1 : (value >> (bits - 1 - i)) & 1;
+
+        if (set)
+            buf[bit / 8] |= mask;
+        else
+            buf[bit / 8] &= (uint8_t)~mask;
+    }
+}
+
+#endif /* PROTOVIZ_PACKING */
+"#;
+
+/// Position in bits, as a fixed part and the variable terms multiplied by their number of bits
+#[derive(Clone, Default)]
+struct Offset {
+    bits: usize,
+    terms: Vec<(usize, String)>,
+}
+
+impl Offset {
+    fn add(&mut self, length: &FieldLength, unit: Unit) -> Result<(), Error> {
+        let overflow = || Error::FormatError(format!("Offset after length {} overflows", length));
+        let (fixed, terms) = length.terms()?;
+
+        self.bits = fixed
+            .checked_mul(unit.bits())
+            .and_then(|bits| self.bits.checked_add(bits))
+            .ok_or_else(overflow)?;
+        for (count, name) in terms {
+            let name = identifier(&name);
+            let added = count.checked_mul(unit.bits()).ok_or_else(overflow)?;
+            match self.terms.iter_mut().find(|(_, term)| *term == name) {
+                Some((bits, _)) => *bits = bits.checked_add(added).ok_or_else(overflow)?,
+                None => self.terms.push((added, name)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Names of the variable lengths, used as macro and function parameters
+    fn params(&self) -> Vec<&str> {
+        self.terms.iter().map(|(_, name)| name.as_str()).collect()
+    }
+
+    /// Expression of the position in bytes, the variable terms being whole bytes
+    fn bytes(&self) -> String {
+        let mut parts = Vec::new();
+        if self.bits >= 8 || self.terms.is_empty() {
+            parts.push((self.bits / 8).to_string());
+        }
+
+        for (bits, name) in &self.terms {
+            parts.push(if *bits == 1 {
+                format!("({}) / 8", name)
+            } else if !bits.is_multiple_of(8) {
+                format!("({} * ({})) / 8", bits, name)
+            } else if *bits == 8 {
+                format!("({})", name)
+            } else {
+                format!("{} * ({})", bits / 8, name)
+            });
+        }
+
+        parts.join(" + ")
+    }
+
+    /// Expression of the position in bits
+    fn bit_expr(&self) -> String {
+        let mut parts = vec![self.bits.to_string()];
+        for (bits, name) in &self.terms {
+            parts.push(if *bits == 1 {
+                name.clone()
+            } else {
+                format!("{} * {}", bits, name)
+            });
+        }
+
+        parts.join(" + ")
+    }
+}
+
+/// Generate a C header with the offset, size and mask macros of the protocol fields, and their packing functions
+///
+/// The lengths are interpreted in the given unit. The macros and functions of the fields placed after a variable
+/// length field take the variable lengths as parameters, which have to be whole bytes, and the ones after an optional
+/// field take its presence as a `{field}_present` flag. Each definition gets its own macros, with offsets relative to
+/// the start of the structure.
+pub fn to_c(descriptor: &ProtoDescriptor, name: &str, unit: Unit) -> Result<String, Error> {
+    if descriptor.fields.is_empty() {
+        return Err(Error::FormatError("No fields provided".to_string()));
+    }
+
+    let guard = format!("{}_H", identifier(name).to_uppercase());
+
+    let mut code = String::new();
+    writeln!(
+        code,
+        "/* Generated by protoviz from a protocol descriptor, do not edit */"
+    )
+    .unwrap();
+    writeln!(code, "#ifndef {}", guard).unwrap();
+    writeln!(code, "#define {}", guard).unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "#include <stddef.h>").unwrap();
+    writeln!(code, "#include <stdint.h>").unwrap();
+    writeln!(code).unwrap();
+    code.push_str(RUNTIME);
+
+    generate_block(&mut code, descriptor, name, &descriptor.fields, unit)?;
+
+    for (definition, fields) in &descriptor.definitions {
+        generate_block(&mut code, descriptor, definition, fields, unit)?;
+    }
+
+    writeln!(code).unwrap();
+    writeln!(code, "#endif /* {} */", guard).unwrap();
+
+    Ok(code)
+}
+
+/// Generate the macros and functions of a list of fields
+fn generate_block(
+    code: &mut String,
+    descriptor: &ProtoDescriptor,
+    name: &str,
+    fields: &[FieldDescriptor],
+    unit: Unit,
+) -> Result<(), Error> {
+    // The expanded structures are inlined, and the collapsed ones are kept as a single field
+    let flat = ProtoDescriptor {
+        elements: descriptor.elements.clone(),
+        fields: fields.to_vec(),
+        definitions: descriptor.definitions.clone(),
+        ..Default::default()
+    }
+    .flatten()?;
+
+    let prefix = identifier(name);
+    let upper = prefix.to_uppercase();
+    let mut offset = Offset::default();
+
+    writeln!(code).unwrap();
+    writeln!(code, "/* {} */", name).unwrap();
+
+    for code_field in code_fields(&flat.fields) {
+        let field = code_field.field;
+        let macro_name = format!("{}_{}", upper, code_field.ident.to_uppercase());
+        let length = field.total_length()?;
+
+        writeln!(code).unwrap();
+        match &field.description {
+            Some(description) => writeln!(code, "/* {}: {} */", field.name, description).unwrap(),
+            None => writeln!(code, "/* {} */", field.name).unwrap(),
+        }
+
+        if length == FieldLength::Fixed(0) {
+            return Err(Error::FormatError(format!(
+                "Field {} has no length",
+                field.name
+            )));
+        }
+
+        define(
+            code,
+            &macro_name,
+            "OFFSET",
+            &offset.params(),
+            &offset.bytes(),
+        );
+
+        let mut size = Offset::default();
+        size.add(&length, unit)?;
+
+        // Fields not aligned on bytes get the masks of the bytes they span
+        let bit_offset = offset.bits % 8;
+        let field_bits = match length {
+            FieldLength::Fixed(length) => {
+                Some(length.checked_mul(unit.bits()).ok_or_else(|| {
+                    Error::FormatError(format!("Length of field {} overflows", field.name))
+                })?)
+            }
+            _ => None,
+        };
+
+        match field_bits {
+            Some(bits) if bit_offset != 0 || !bits.is_multiple_of(8) => {
+                let bytes = (bit_offset + bits).div_ceil(8);
+                let shift = match field.effective_bit_order(&descriptor.elements) {
+                    BitOrder::MsbFirst => bytes * 8 - bit_offset - bits,
+                    BitOrder::LsbFirst => bit_offset,
+                };
+
+                define(code, &macro_name, "SIZE", &[], &bytes.to_string());
+                define(code, &macro_name, "BITS", &[], &bits.to_string());
+                if bytes <= 8 {
+                    let mask = (u64::MAX >> (64 - bits)) << shift;
+                    define(code, &macro_name, "SHIFT", &[], &shift.to_string());
+                    define(code, &macro_name, "MASK", &[], &format!("{:#x}u", mask));
+                }
+            }
+            _ => define(code, &macro_name, "SIZE", &size.params(), &size.bytes()),
+        }
+
+        // Single integers get their packing functions
+        if let Some(bits) = field_bits.filter(|bits| *bits <= 64 && field.repeat.is_none()) {
+            writeln!(code).unwrap();
+            let ty = match bits {
+                0..=8 => "uint8_t",
+                9..=16 => "uint16_t",
+                17..=32 => "uint32_t",
+                _ => "uint64_t",
+            };
+            let little = field.effective_endianness(&descriptor.elements) == Endianness::Little;
+            let lsb_first = field.effective_bit_order(&descriptor.elements) == BitOrder::LsbFirst;
+
+            let params = offset
+                .params()
+                .iter()
+                .map(|param| format!(", size_t {}", param))
+                .collect::<String>();
+            let (position, little, lsb_first) = (offset.bit_expr(), little as u8, lsb_first as u8);
+
+            writeln!(
+                code,
+                "static inline {} {}_unpack_{}(const uint8_t *buf{})",
+                ty, prefix, code_field.ident, params
+            )
+            .unwrap();
+            writeln!(code, "{{").unwrap();
+            writeln!(
+                code,
+                "    return ({})pv_unpack(buf, {}, {}, {}, {});",
+                ty, position, bits, little, lsb_first
+            )
+            .unwrap();
+            writeln!(code, "}}").unwrap();
+            writeln!(code).unwrap();
+            writeln!(
+                code,
+                "static inline void {}_pack_{}(uint8_t *buf{}, {} value)",
+                prefix, code_field.ident, params, ty
+            )
+            .unwrap();
+            writeln!(code, "{{").unwrap();
+            writeln!(
+                code,
+                "    pv_pack(buf, {}, {}, value, {}, {});",
+                position, bits, little, lsb_first
+            )
+            .unwrap();
+            writeln!(code, "}}").unwrap();
+        }
+
+        // The fields after an optional one depend on its presence, or on its length if it is variable
+        match (field.is_optional(), field_bits) {
+            (true, Some(bits)) => offset
+                .terms
+                .push((bits, format!("{}_present", code_field.ident))),
+            (true, None) => offset.add(
+                &FieldLength::Variable(format!("{}_length", code_field.ident)),
+                unit,
+            )?,
+            (false, _) => offset.add(&length, unit)?,
+        }
+    }
+
+    writeln!(code).unwrap();
+    define(code, &upper, "SIZE", &offset.params(), &offset.bytes());
+
+    Ok(())
+}
+
+/// Write a macro, taking parameters if it depends on variable lengths
+fn define(code: &mut String, name: &str, suffix: &str, params: &[&str], value: &str) {
+    if params.is_empty() {
+        writeln!(code, "#define {}_{} {}", name, suffix, value).unwrap();
+    } else {
+        writeln!(
+            code,
+            "#define {}_{}({}) ({})",
+            name,
+            suffix,
+            params.join(", "),
+            value
+        )
+        .unwrap();
+    }
+}
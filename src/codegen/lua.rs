@@ -0,0 +1,591 @@
+//! Lua code generator, creating a Wireshark dissector skeleton for a descriptor
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use super::{
+    alternatives_length, code_fields, identifier, resolve_condition, resolve_length, structure,
+    CodeField, Unit,
+};
+use crate::descriptor::{BitOrder, Endianness, FieldDescriptor, FieldLength, ProtoDescriptor};
+use crate::errors::Error;
+
+const KEYWORDS: [&str; 22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// Names used by the generated functions, which the field values can't shadow
+const RESERVED: [&str; 9] = [
+    "buffer", "pinfo", "tree", "subtree", "offset", "start", "proto", "bit", "math",
+];
+
+// Length of the last field, extending until the end of the data
+const UNTIL_END: &str = "buffer:len() - offset";
+
+/// How a field is read
+enum Kind {
+    /// Integer of the given bits, starting `skip` bits into its first byte
+    Uint {
+        bits: usize,
+        skip: usize,
+        little: bool,
+        lsb_first: bool,
+    },
+    /// Bytes of a fixed or variable length expression
+    Bytes(String),
+    /// Structure dissected by its own function
+    Struct(String),
+}
+
+/// Generate a Wireshark Lua dissector for the protocol, with a function for each definition
+///
+/// The lengths are interpreted in the given unit. Each field gets a `ProtoField` of its size (integers of up to 64
+/// bits, with a mask if they don't fill their bytes, and bytes otherwise), and the dissector walks their offsets.
+/// Variable lengths, repeat counts and conditions have to refer to previous fields, except for the last field of the
+/// protocol, which extends until the end of the data. The fields not filling their bytes can't be variable, repeated
+/// or optional. The dissector still has to be registered, for instance on a UDP port.
+pub fn to_lua(descriptor: &ProtoDescriptor, name: &str, unit: Unit) -> Result<String, Error> {
+    if descriptor.fields.is_empty() {
+        return Err(Error::FormatError("No fields provided".to_string()));
+    }
+
+    // Catches the unknown and recursive structures
+    descriptor.flatten()?;
+
+    let proto = identifier(name);
+
+    let mut declarations = String::new();
+    let mut functions = String::new();
+    let mut tables = vec![format!("{}_fields", proto)];
+
+    let (fields, body) =
+        generate_block(descriptor, &proto, &proto, &descriptor.fields, unit, true)?;
+    declarations.push_str(&fields);
+
+    for (definition, definition_fields) in &descriptor.definitions {
+        let ident = identifier(definition);
+        let (fields, body) = generate_block(
+            descriptor,
+            &format!("{}.{}", proto, ident),
+            &ident,
+            definition_fields,
+            unit,
+            false,
+        )?;
+        declarations.push_str(&fields);
+        tables.push(format!("{}_fields", ident));
+
+        writeln!(functions).unwrap();
+        writeln!(
+            functions,
+            "function dissect_{}(buffer, offset, tree)",
+            ident
+        )
+        .unwrap();
+        writeln!(functions, "    local start = offset").unwrap();
+        writeln!(
+            functions,
+            "    local subtree = tree:add(buffer(offset), {})",
+            lua_string(definition)
+        )
+        .unwrap();
+        functions.push_str(&body);
+        writeln!(functions).unwrap();
+        writeln!(functions, "    subtree:set_len(offset - start)").unwrap();
+        writeln!(functions, "    return offset").unwrap();
+        writeln!(functions, "end").unwrap();
+    }
+
+    let mut code = String::new();
+    writeln!(
+        code,
+        "-- Generated by protoviz from a protocol descriptor, do not edit"
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "local proto = Proto({}, {})",
+        lua_string(&proto),
+        lua_string(name)
+    )
+    .unwrap();
+    code.push_str(&declarations);
+
+    writeln!(code).unwrap();
+    writeln!(code, "local fields = {{}}").unwrap();
+    writeln!(
+        code,
+        "for _, list in ipairs({{ {} }}) do",
+        tables.join(", ")
+    )
+    .unwrap();
+    writeln!(code, "    for _, field in pairs(list) do").unwrap();
+    writeln!(code, "        fields[#fields + 1] = field").unwrap();
+    writeln!(code, "    end").unwrap();
+    writeln!(code, "end").unwrap();
+    writeln!(code, "proto.fields = fields").unwrap();
+
+    if !descriptor.definitions.is_empty() {
+        let functions = descriptor
+            .definitions
+            .keys()
+            .map(|definition| format!("dissect_{}", identifier(definition)))
+            .collect::<Vec<_>>();
+
+        // Declared first, as the structures may refer to each other
+        writeln!(code).unwrap();
+        writeln!(code, "local {}", functions.join(", ")).unwrap();
+    }
+    code.push_str(&functions);
+
+    writeln!(code).unwrap();
+    writeln!(code, "function proto.dissector(buffer, pinfo, tree)").unwrap();
+    writeln!(code, "    pinfo.cols.protocol = proto.name").unwrap();
+    writeln!(code, "    local subtree = tree:add(proto, buffer())").unwrap();
+    writeln!(code, "    local offset = 0").unwrap();
+    code.push_str(&body);
+    writeln!(code).unwrap();
+    writeln!(code, "    return offset").unwrap();
+    writeln!(code, "end").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "-- Register the dissector, for instance with:").unwrap();
+    writeln!(code, "-- DissectorTable.get(\"udp.port\"):add(1234, proto)").unwrap();
+
+    Ok(code)
+}
+
+/// Generate the field declarations of a list of fields, and the statements dissecting them
+fn generate_block(
+    descriptor: &ProtoDescriptor,
+    abbrev: &str,
+    name: &str,
+    fields: &[FieldDescriptor],
+    unit: Unit,
+    top_level: bool,
+) -> Result<(String, String), Error> {
+    let fields = code_fields(fields);
+
+    // The values of the fields referenced by the next ones are kept in locals
+    let used = RefCell::new(HashSet::new());
+    let mark = |ident: &str| {
+        used.borrow_mut().insert(ident.to_owned());
+        local_name(ident)
+    };
+    for (i, code_field) in fields.iter().enumerate() {
+        let (field, locals) = (code_field.field, &fields[..i]);
+        resolve_length(&alternatives_length(field)?, 1, locals, mark)?;
+        if let Some(count) = &field.repeat {
+            resolve_length(count, 1, locals, mark)?;
+        }
+        if let Some(condition) = &field.condition {
+            resolve_condition(condition, locals, mark);
+        }
+    }
+    let used = used.into_inner();
+
+    let mut declarations = String::new();
+    let mut body = String::new();
+    writeln!(declarations).unwrap();
+    writeln!(declarations, "local {}_fields = {{", name).unwrap();
+
+    // Bits already read in the current byte
+    let mut skip = 0;
+
+    for (i, code_field) in fields.iter().enumerate() {
+        let field = code_field.field;
+        let locals = &fields[..i];
+        let until_end = top_level && i + 1 == fields.len();
+
+        let kind = field_kind(
+            descriptor,
+            field,
+            locals,
+            unit,
+            skip,
+            until_end && field.repeat.is_none(),
+        )?;
+
+        let whole_bytes = match &kind {
+            Kind::Uint { bits, skip, .. } => *skip == 0 && bits.is_multiple_of(8),
+            _ => skip == 0,
+        };
+        if !whole_bytes && (field.repeat.is_some() || field.is_optional()) {
+            return Err(Error::FormatError(format!(
+                "Field {} is repeated or optional, but does not fill its bytes",
+                field.name
+            )));
+        }
+
+        let repeat = match &field.repeat {
+            None => None,
+            Some(FieldLength::Fixed(count)) => Some(format!("for _ = 1, {} do", count)),
+            Some(count) => match resolve_length(count, 1, locals, local_name)? {
+                Some(count) => Some(format!("for _ = 1, {} do", count)),
+                None if until_end => Some("while offset < buffer:len() do".to_owned()),
+                None => {
+                    return Err(Error::FormatError(format!(
+                        "Repeat count {} of field {} does not refer to a previous field",
+                        count, field.name
+                    )))
+                }
+            },
+        };
+
+        let condition = match &field.condition {
+            Some(condition) => Some(
+                resolve_condition(condition, locals, local_name)
+                    .map(|condition| lua_condition(&condition))
+                    .ok_or_else(|| {
+                        Error::FormatError(format!(
+                            "Unsupported condition {} of field {}",
+                            condition, field.name
+                        ))
+                    })?,
+            ),
+            None if field.optional => {
+                return Err(Error::FormatError(format!(
+                    "Optional field {} has no condition",
+                    field.name
+                )))
+            }
+            None => None,
+        };
+
+        let key = key(&code_field.ident);
+        if let Some(declaration) =
+            proto_field(field, &kind, &format!("{}.{}", abbrev, code_field.ident))
+        {
+            writeln!(declarations, "    {} = {},", key, declaration).unwrap();
+        }
+
+        let mut lines = read_lines(
+            &format!("{}_fields{}", name, access(&code_field.ident)),
+            &kind,
+        );
+        if used.contains(&code_field.ident) {
+            if let Some(value) = value(&kind) {
+                lines.insert(
+                    0,
+                    format!("local {} = {}", local_name(&code_field.ident), value),
+                );
+            }
+        }
+
+        // Fields within a byte only move to the next one once they fill it
+        let advance = match &kind {
+            Kind::Uint {
+                bits, skip: start, ..
+            } => {
+                let end = start + bits;
+                skip = end % 8;
+                (end / 8).to_string()
+            }
+            Kind::Bytes(length) => length.clone(),
+            Kind::Struct(_) => String::new(),
+        };
+        if advance == UNTIL_END {
+            lines.push("offset = buffer:len()".to_owned());
+        } else if !advance.is_empty() && advance != "0" {
+            lines.push(format!("offset = offset + {}", advance));
+        }
+
+        if let Some(repeat) = repeat {
+            lines = wrap(repeat, lines);
+        }
+        if let Some(condition) = condition {
+            lines = wrap(format!("if {} then", condition), lines);
+        }
+
+        writeln!(body).unwrap();
+        match &field.description {
+            Some(description) => writeln!(body, "    -- {}: {}", field.name, description).unwrap(),
+            None => writeln!(body, "    -- {}", field.name).unwrap(),
+        }
+        for line in lines {
+            writeln!(body, "    {}", line).unwrap();
+        }
+    }
+
+    if skip != 0 {
+        return Err(Error::FormatError(format!(
+            "Fields of {} do not end on a byte",
+            name
+        )));
+    }
+
+    writeln!(declarations, "}}").unwrap();
+
+    Ok((declarations, body))
+}
+
+/// How a field starting `skip` bits into a byte is read
+fn field_kind(
+    descriptor: &ProtoDescriptor,
+    field: &FieldDescriptor,
+    locals: &[CodeField],
+    unit: Unit,
+    skip: usize,
+    until_end: bool,
+) -> Result<Kind, Error> {
+    let kind = if let Some((name, _)) = structure(descriptor, field)? {
+        Kind::Struct(identifier(name))
+    } else {
+        match alternatives_length(field)? {
+            FieldLength::Fixed(0) => {
+                return Err(Error::FormatError(format!(
+                    "Field {} has no length",
+                    field.name
+                )))
+            }
+            // Fields with alternatives are kept as bytes, as they are decoded by the user
+            FieldLength::Fixed(length)
+                if length * unit.bits() <= 64
+                    && (skip + length * unit.bits()).div_ceil(8) <= 8
+                    && field.alternatives.is_empty() =>
+            {
+                Kind::Uint {
+                    bits: length * unit.bits(),
+                    skip,
+                    little: field.effective_endianness(&descriptor.elements) == Endianness::Little,
+                    lsb_first: field.effective_bit_order(&descriptor.elements)
+                        == BitOrder::LsbFirst,
+                }
+            }
+            FieldLength::Fixed(length) if (length * unit.bits()).is_multiple_of(8) => {
+                Kind::Bytes((length * unit.bits() / 8).to_string())
+            }
+            FieldLength::Fixed(length) => {
+                return Err(Error::FormatError(format!(
+                    "Field {} of {} bits is neither an integer nor a whole number of bytes",
+                    field.name,
+                    length * unit.bits()
+                )))
+            }
+            length => match resolve_length(&length, 1, locals, local_name)? {
+                Some(length) if unit == Unit::Bits && length.contains(' ') => {
+                    Kind::Bytes(format!("math.floor(({}) / 8)", length))
+                }
+                Some(length) if unit == Unit::Bits => {
+                    Kind::Bytes(format!("math.floor({} / 8)", length))
+                }
+                Some(length) => Kind::Bytes(length),
+                None if until_end => Kind::Bytes(UNTIL_END.to_owned()),
+                None => {
+                    return Err(Error::FormatError(format!(
+                        "Length {} of field {} does not refer to a previous field",
+                        length, field.name
+                    )))
+                }
+            },
+        }
+    };
+
+    if skip != 0 && !matches!(kind, Kind::Uint { .. }) {
+        return Err(Error::FormatError(format!(
+            "Field {} does not start on a byte",
+            field.name
+        )));
+    }
+
+    Ok(kind)
+}
+
+/// Declaration of the `ProtoField` of a field, if it is not a structure
+fn proto_field(field: &FieldDescriptor, kind: &Kind, abbrev: &str) -> Option<String> {
+    let description = match &field.description {
+        Some(description) => lua_string(description),
+        None => "nil".to_owned(),
+    };
+    let (abbrev, name) = (lua_string(abbrev), lua_string(&field.name));
+
+    match kind {
+        Kind::Uint { bits, skip, .. } => {
+            let (bytes, mask) = span(*bits, *skip, kind);
+            let mask = match mask {
+                Some(mask) => format!("{:#x}", mask),
+                None => "nil".to_owned(),
+            };
+
+            Some(if *bits == 1 {
+                format!(
+                    "ProtoField.bool({}, {}, {}, nil, {}, {})",
+                    abbrev,
+                    name,
+                    bytes * 8,
+                    mask,
+                    description
+                )
+            } else {
+                let ty = match bytes {
+                    1 => "uint8",
+                    2 => "uint16",
+                    3 => "uint24",
+                    4 => "uint32",
+                    _ => "uint64",
+                };
+                format!(
+                    "ProtoField.{}({}, {}, base.DEC, nil, {}, {})",
+                    ty, abbrev, name, mask, description
+                )
+            })
+        }
+        Kind::Bytes(_) => Some(format!(
+            "ProtoField.bytes({}, {}, base.NONE, {})",
+            abbrev, name, description
+        )),
+        Kind::Struct(_) => None,
+    }
+}
+
+/// Bytes spanned by an integer, and its mask within them if it does not fill them
+fn span(bits: usize, skip: usize, kind: &Kind) -> (usize, Option<u64>) {
+    let bytes = (skip + bits).div_ceil(8);
+    if skip == 0 && bits.is_multiple_of(8) {
+        return (bytes, None);
+    }
+
+    (
+        bytes,
+        Some((u64::MAX >> (64 - bits)) << shift(bits, skip, bytes, kind)),
+    )
+}
+
+/// Shift of an integer within the bytes it spans, read in the order of its bits
+fn shift(bits: usize, skip: usize, bytes: usize, kind: &Kind) -> usize {
+    match kind {
+        Kind::Uint {
+            lsb_first: true, ..
+        } => skip,
+        _ => bytes * 8 - skip - bits,
+    }
+}
+
+/// Statements adding a field to the tree
+fn read_lines(field: &str, kind: &Kind) -> Vec<String> {
+    match kind {
+        Kind::Uint {
+            bits,
+            skip,
+            little,
+            lsb_first,
+        } => {
+            let (bytes, mask) = span(*bits, *skip, kind);
+            // The masked fields are read in the order of their bits
+            let little = if mask.is_some() { *lsb_first } else { *little };
+            vec![format!(
+                "subtree:{}({}, buffer(offset, {}))",
+                if little { "add_le" } else { "add" },
+                field,
+                bytes
+            )]
+        }
+        Kind::Bytes(length) => vec![format!(
+            "subtree:add({}, buffer(offset, {}))",
+            field, length
+        )],
+        Kind::Struct(ident) => vec![format!(
+            "offset = dissect_{}(buffer, offset, subtree)",
+            ident
+        )],
+    }
+}
+
+/// Expression of the value of an integer field, for the next fields referring to it
+fn value(kind: &Kind) -> Option<String> {
+    let Kind::Uint {
+        bits,
+        skip,
+        little,
+        lsb_first,
+    } = kind
+    else {
+        return None;
+    };
+
+    let (bytes, mask) = span(*bits, *skip, kind);
+    let little = if mask.is_some() { *lsb_first } else { *little };
+    let read = match (bytes, little) {
+        (1..=4, false) => format!("buffer(offset, {}):uint()", bytes),
+        (1..=4, true) => format!("buffer(offset, {}):le_uint()", bytes),
+        (_, false) => format!("buffer(offset, {}):uint64():tonumber()", bytes),
+        (_, true) => format!("buffer(offset, {}):le_uint64():tonumber()", bytes),
+    };
+
+    Some(match mask {
+        None => read,
+        Some(_) => {
+            let shift = shift(*bits, *skip, bytes, kind);
+            let read = if shift == 0 {
+                read
+            } else {
+                format!("math.floor({} / {})", read, 1u64 << shift)
+            };
+            format!("{} % {}", read, 1u64 << bits)
+        }
+    })
+}
+
+/// Indent statements within a block
+fn wrap(header: String, lines: Vec<String>) -> Vec<String> {
+    let mut result = vec![header];
+    result.extend(lines.into_iter().map(|line| format!("    {}", line)));
+    result.push("end".to_owned());
+    result
+}
+
+/// Condition in Lua, where the operators differ from the resolved ones
+fn lua_condition(condition: &str) -> String {
+    let mask = condition
+        .strip_prefix('(')
+        .and_then(|condition| condition.strip_suffix(") != 0"))
+        .and_then(|condition| condition.split_once(" & "));
+
+    match mask {
+        Some((left, right)) => format!("bit.band({}, {}) ~= 0", left, right),
+        None => condition.replace("!=", "~="),
+    }
+}
+
+/// Key of a field in its table, quoting the keywords
+fn key(ident: &str) -> String {
+    if KEYWORDS.contains(&ident) {
+        format!("[{}]", lua_string(ident))
+    } else {
+        ident.to_owned()
+    }
+}
+
+/// Access to a field in its table
+fn access(ident: &str) -> String {
+    if KEYWORDS.contains(&ident) {
+        format!("[{}]", lua_string(ident))
+    } else {
+        format!(".{}", ident)
+    }
+}
+
+/// Name of the local variable holding the value of a field, which can't shadow the generated names
+fn local_name(ident: &str) -> String {
+    if KEYWORDS.contains(&ident) || RESERVED.contains(&ident) {
+        format!("{}_", ident)
+    } else {
+        ident.to_owned()
+    }
+}
+
+/// Quoted Lua string
+fn lua_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
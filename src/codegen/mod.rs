@@ -0,0 +1,217 @@
+//! Generators creating encoding and decoding code from descriptors
+
+pub mod c;
+pub mod lua;
+pub mod rust;
+
+use crate::descriptor::{FieldDescriptor, FieldLength, ProtoDescriptor};
+use crate::errors::Error;
+
+/// Unit of the field lengths of a descriptor, as they don't carry one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Bits,
+    Bytes,
+}
+
+impl Unit {
+    /// Number of bits in a length unit
+    pub fn bits(&self) -> usize {
+        match self {
+            Unit::Bits => 1,
+            Unit::Bytes => 8,
+        }
+    }
+}
+
+/// Field of a generated struct, with its identifier
+struct CodeField<'a> {
+    field: &'a FieldDescriptor,
+    ident: String,
+}
+
+impl CodeField<'_> {
+    /// Whether the field holds a single integer, so other fields can refer to its value
+    fn is_scalar(&self) -> bool {
+        self.field.repeat.is_none()
+            && self.field.condition.is_none()
+            && !self.field.optional
+            && self.field.structure.is_none()
+            && self.field.alternatives.is_empty()
+            && matches!(self.field.length, FieldLength::Fixed(1..))
+    }
+}
+
+/// Name the fields of a struct with unique identifiers
+fn code_fields(fields: &[FieldDescriptor]) -> Vec<CodeField<'_>> {
+    let mut result: Vec<CodeField> = Vec::new();
+
+    for field in fields {
+        let base = identifier(&field.name);
+        let mut ident = base.clone();
+        let mut suffix = 1;
+        while result.iter().any(|f| f.ident == ident) {
+            suffix += 1;
+            ident = format!("{}_{}", base, suffix);
+        }
+
+        result.push(CodeField { field, ident });
+    }
+
+    result
+}
+
+/// Snake case identifier of a name (e.g. "Source Port" into "source_port")
+fn identifier(name: &str) -> String {
+    let mut ident = String::new();
+    let mut previous: Option<char> = None;
+
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            // Split the camel case words (e.g. "srcPort")
+            if c.is_ascii_uppercase() && previous.is_some_and(|p| p.is_ascii_lowercase()) {
+                ident.push('_');
+            }
+            ident.push(c.to_ascii_lowercase());
+        } else if !ident.is_empty() && !ident.ends_with('_') {
+            ident.push('_');
+        }
+        previous = Some(c);
+    }
+
+    let ident = ident.trim_end_matches('_').to_owned();
+    if ident.is_empty() {
+        "field".to_owned()
+    } else if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", ident)
+    } else {
+        ident
+    }
+}
+
+/// Camel case type name of a name (e.g. "ipv4 header" into "Ipv4Header")
+fn type_name(name: &str) -> String {
+    let name = identifier(name)
+        .split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect::<String>();
+
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("T{}", name)
+    } else {
+        name
+    }
+}
+
+/// Fields of the definition referenced by a field, if it has a structure
+fn structure<'a>(
+    descriptor: &'a ProtoDescriptor,
+    field: &FieldDescriptor,
+) -> Result<Option<(&'a str, &'a [FieldDescriptor])>, Error> {
+    match &field.structure {
+        Some(name) => descriptor
+            .definitions
+            .get_key_value(name)
+            .map(|(name, fields)| Some((name.as_str(), fields.as_slice())))
+            .ok_or_else(|| Error::FormatError(format!("Unknown structure {}", name))),
+        None => Ok(None),
+    }
+}
+
+/// Length of a field with alternatives, which have to share the same length
+pub(crate) fn alternatives_length(field: &FieldDescriptor) -> Result<FieldLength, Error> {
+    match (&field.length, field.alternatives.first()) {
+        (FieldLength::Fixed(0), Some(alternative)) => alternative.length(),
+        (length, _) => Ok(length.clone()),
+    }
+}
+
+/// Resolve a length from the previous fields, as the sum of its terms multiplied by `scale`
+///
+/// The variable terms have to name a previous field, which is read through `value` (e.g. to cast it). Returns
+/// `None` if one doesn't.
+fn resolve_length(
+    length: &FieldLength,
+    scale: usize,
+    locals: &[CodeField],
+    value: impl Fn(&str) -> String,
+) -> Result<Option<String>, Error> {
+    let (fixed, terms) = length.terms()?;
+    let scaled = |count: usize| {
+        count
+            .checked_mul(scale)
+            .ok_or_else(|| Error::FormatError(format!("Length {} overflows", length)))
+    };
+
+    let mut parts = Vec::new();
+    if fixed > 0 || terms.is_empty() {
+        parts.push(scaled(fixed)?.to_string());
+    }
+
+    for (count, name) in terms {
+        let Some(local) = locals
+            .iter()
+            .find(|local| local.is_scalar() && (local.field.name == name || local.ident == name))
+        else {
+            return Ok(None);
+        };
+
+        let factor = scaled(count)?;
+        parts.push(if factor == 1 {
+            value(&local.ident)
+        } else {
+            format!("{} * {}", factor, value(&local.ident))
+        });
+    }
+
+    Ok(Some(parts.join(" + ")))
+}
+
+/// Condition of a field (e.g. "flags & 1", "kind == 2" or "present"), with its operand names resolved
+///
+/// Only comparisons between a previous field and a number (or another field) are supported.
+fn resolve_condition(
+    condition: &str,
+    locals: &[CodeField],
+    value: impl Fn(&str) -> String,
+) -> Option<String> {
+    let operand = |text: &str| -> Option<String> {
+        let text = text.trim();
+        if let Some(number) = parse_number(text) {
+            return Some(number.to_string());
+        }
+
+        locals
+            .iter()
+            .find(|local| local.is_scalar() && (local.field.name == text || local.ident == text))
+            .map(|local| value(&local.ident))
+    };
+
+    for op in ["==", "!=", "<=", ">=", "<", ">", "&"] {
+        if let Some((left, right)) = condition.split_once(op) {
+            let expression = format!("{} {} {}", operand(left)?, op, operand(right)?);
+            return Some(if op == "&" {
+                format!("({}) != 0", expression)
+            } else {
+                expression
+            });
+        }
+    }
+
+    operand(condition).map(|operand| format!("{} != 0", operand))
+}
+
+/// Parse a decimal or hexadecimal number
+pub(crate) fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
@@ -0,0 +1,597 @@
+//! Rust code generator, creating a struct per descriptor with its parse and write functions
+
+use std::fmt::Write;
+
+use super::{
+    alternatives_length, code_fields, resolve_condition, resolve_length, structure, type_name,
+    CodeField, Unit,
+};
+use crate::descriptor::{BitOrder, Endianness, FieldDescriptor, FieldLength, ProtoDescriptor};
+use crate::errors::Error;
+
+const KEYWORDS: [&str; 52] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while", "yield", "union",
+];
+
+// Names used by the generated functions, which the fields can't shadow
+const RESERVED: [&str; 11] = [
+    "input",
+    "offset",
+    "out",
+    "items",
+    "item",
+    "value",
+    "read_uint",
+    "read_array",
+    "read_bytes",
+    "write_uint",
+    "write_bytes",
+];
+
+// Helpers of the generated functions, emitted only when they are called
+const RUNTIME: [(&str, &str); 5] = [
+    (
+        "read_uint",
+        r#"
+fn read_uint(
+    input: &[u8],
+    offset: &mut usize,
+    bits: usize,
+    little: bool,
+    lsb_first: bool,
+    field: &'static str,
+) -> Result<u64, ParseError> {
+    if *offset + bits > input.len() * 8 {
+        return Err(ParseError::Truncated(field));
+    }
+
+    let mut value = 0u64;
+    for i in 0..bits {
+        let bit = *offset + i;
+        if lsb_first {
+            value |= u64::from((input[bit / 8] >> (bit % 8)) & 1) << i;
+        } else {
+            value = (value << 1) | u64::from((input[bit / 8] >> (7 - bit % 8)) & 1);
+        }
+    }
+    *offset += bits;
+
+    // The bit order gives the byte order, unless the value is stored with the other one
+    if bits > 8 && bits % 8 == 0 && little != lsb_first {
+        value = value.swap_bytes() >> (64 - bits);
+    }
+
+    Ok(value)
+}
+"#,
+    ),
+    (
+        "read_array",
+        r#"
+fn read_array<const N: usize>(
+    input: &[u8],
+    offset: &mut usize,
+    field: &'static str,
+) -> Result<[u8; N], ParseError> {
+    let mut bytes = [0; N];
+    for byte in bytes.iter_mut() {
+        *byte = read_uint(input, offset, 8, false, false, field)? as u8;
+    }
+
+    Ok(bytes)
+}
+"#,
+    ),
+    (
+        "read_bytes",
+        r#"
+fn read_bytes(
+    input: &[u8],
+    offset: &mut usize,
+    bits: usize,
+    field: &'static str,
+) -> Result<Vec<u8>, ParseError> {
+    if bits % 8 != 0 {
+        return Err(ParseError::Unaligned(field));
+    }
+
+    (0..bits / 8)
+        .map(|_| read_uint(input, offset, 8, false, false, field).map(|byte| byte as u8))
+        .collect()
+}
+"#,
+    ),
+    (
+        "write_uint",
+        r#"
+fn write_uint(
+    out: &mut Vec<u8>,
+    offset: &mut usize,
+    bits: usize,
+    mut value: u64,
+    little: bool,
+    lsb_first: bool,
+) {
+    if bits > 8 && bits % 8 == 0 && little != lsb_first {
+        value = value.swap_bytes() >> (64 - bits);
+    }
+
+    let length = (*offset + bits).div_ceil(8);
+    if out.len() < length {
+        out.resize(length, 0);
+    }
+
+    for i in 0..bits {
+        let bit = *offset + i;
+        if lsb_first {
+            out[bit / 8] |= (((value >> i) & 1) as u8) << (bit % 8);
+        } else {
+            out[bit / 8] |= (((value >> (bits - 1 - i)) & 1) as u8) << (7 - bit % 8);
+        }
+    }
+    *offset += bits;
+}
+"#,
+    ),
+    (
+        "write_bytes",
+        r#"
+fn write_bytes(out: &mut Vec<u8>, offset: &mut usize, bytes: &[u8]) {
+    for byte in bytes {
+        write_uint(out, offset, 8, u64::from(*byte), false, false);
+    }
+}
+"#,
+    ),
+];
+
+/// Value held by a field, before being repeated or made optional
+enum Kind {
+    Uint {
+        bits: usize,
+        little: bool,
+        lsb_first: bool,
+    },
+    Array(usize),   // Fixed number of bytes
+    Bytes(String),  // Expression of the number of bits
+    Struct(String), // Type name
+}
+
+/// How a field is repeated
+enum Repeat {
+    Count(String),
+    UntilEnd,
+}
+
+/// Generate the Rust code parsing and writing the protocol, with a struct for it and for each definition
+///
+/// The lengths are interpreted in the given unit. Variable lengths, repeat counts and conditions have to
+/// refer to previous fields, except for the last field of the protocol, which extends until the end of the data.
+pub fn to_rust(descriptor: &ProtoDescriptor, name: &str, unit: Unit) -> Result<String, Error> {
+    if descriptor.fields.is_empty() {
+        return Err(Error::FormatError("No fields provided".to_string()));
+    }
+
+    // Catches the unknown and recursive structures
+    descriptor.flatten()?;
+
+    let mut code = String::from(
+        "// Generated by protoviz from a protocol descriptor, do not edit\n\n\
+         /// Error returned when the data does not match the format\n\
+         #[derive(Debug, Clone, Copy, PartialEq, Eq)]\n\
+         pub enum ParseError {\n    \
+             /// The data ended in the middle of the named field\n    \
+             Truncated(&'static str),\n    \
+             /// The length of the named field is not a whole number of bytes\n    \
+             Unaligned(&'static str),\n\
+         }\n",
+    );
+
+    generate_struct(
+        &mut code,
+        descriptor,
+        &type_name(name),
+        &descriptor.fields,
+        unit,
+        true,
+    )?;
+
+    for (definition, fields) in &descriptor.definitions {
+        generate_struct(
+            &mut code,
+            descriptor,
+            &type_name(definition),
+            fields,
+            unit,
+            false,
+        )?;
+    }
+
+    // Going backwards, so that the helpers calling the previous ones are checked first
+    let mut used = [false; RUNTIME.len()];
+    for i in (0..RUNTIME.len()).rev() {
+        let call = format!("{}(", RUNTIME[i].0);
+        used[i] = code.contains(&call)
+            || RUNTIME
+                .iter()
+                .zip(used)
+                .any(|((_, helper), used)| used && helper.contains(&call));
+    }
+    for ((_, helper), used) in RUNTIME.iter().zip(used) {
+        if used {
+            code.push_str(helper);
+        }
+    }
+
+    Ok(code)
+}
+
+/// Generate a struct, with the functions reading and writing it
+fn generate_struct(
+    code: &mut String,
+    descriptor: &ProtoDescriptor,
+    name: &str,
+    fields: &[FieldDescriptor],
+    unit: Unit,
+    top_level: bool,
+) -> Result<(), Error> {
+    let fields = code_fields(fields);
+
+    let mut members = Vec::new();
+    let mut reads = Vec::new();
+    let mut writes = Vec::new();
+
+    for (i, code_field) in fields.iter().enumerate() {
+        let field = code_field.field;
+        let locals = &fields[..i];
+        let until_end = top_level && i + 1 == fields.len();
+
+        let kind = field_kind(
+            descriptor,
+            field,
+            locals,
+            unit,
+            until_end && field.repeat.is_none(),
+        )?;
+
+        let repeat = match &field.repeat {
+            None => None,
+            Some(FieldLength::Fixed(count)) => Some(Repeat::Count(count.to_string())),
+            Some(count) => match resolve_length(count, 1, locals, cast)? {
+                Some(count) => Some(Repeat::Count(count)),
+                None if until_end => Some(Repeat::UntilEnd),
+                None => {
+                    return Err(Error::FormatError(format!(
+                        "Repeat count {} of field {} does not refer to a previous field",
+                        count, field.name
+                    )))
+                }
+            },
+        };
+
+        let condition = match &field.condition {
+            Some(condition) => Some(resolve_condition(condition, locals, local_name).ok_or_else(
+                || {
+                    Error::FormatError(format!(
+                        "Unsupported condition {} of field {}",
+                        condition, field.name
+                    ))
+                },
+            )?),
+            None if field.optional => {
+                return Err(Error::FormatError(format!(
+                    "Optional field {} has no condition",
+                    field.name
+                )))
+            }
+            None => None,
+        };
+
+        let mut ty = match &kind {
+            Kind::Uint { bits, .. } => uint_type(*bits).to_owned(),
+            Kind::Array(bytes) => format!("[u8; {}]", bytes),
+            Kind::Bytes(_) => "Vec<u8>".to_owned(),
+            Kind::Struct(name) => name.clone(),
+        };
+        if repeat.is_some() {
+            ty = format!("Vec<{}>", ty);
+        }
+        if condition.is_some() {
+            ty = format!("Option<{}>", ty);
+        }
+
+        let ident = raw(&code_field.ident);
+        if let Some(description) = &field.description {
+            members.push(format!("    /// {}", description));
+        }
+        members.push(format!("    pub {}: {},", ident, ty));
+
+        reads.extend(read_lines(code_field, &kind, &repeat, &condition));
+        writes.extend(write_lines(code_field, &kind, &repeat, &condition));
+    }
+
+    let names = fields
+        .iter()
+        .map(|field| {
+            let (ident, local) = (raw(&field.ident), local_name(&field.ident));
+            if ident == local {
+                ident
+            } else {
+                format!("{}: {}", ident, local)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    writeln!(code).unwrap();
+    writeln!(code, "#[derive(Debug, Clone, PartialEq, Eq)]").unwrap();
+    writeln!(code, "pub struct {} {{", name).unwrap();
+    for member in members {
+        writeln!(code, "{}", member).unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "impl {} {{", name).unwrap();
+    writeln!(code, "    /// Parse the struct from the start of the input").unwrap();
+    writeln!(
+        code,
+        "    pub fn parse(input: &[u8]) -> Result<Self, ParseError> {{"
+    )
+    .unwrap();
+    writeln!(code, "        Self::read(input, &mut 0)").unwrap();
+    writeln!(code, "    }}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "    /// Append the encoded struct to the output").unwrap();
+    writeln!(code, "    pub fn write(&self, out: &mut Vec<u8>) {{").unwrap();
+    writeln!(code, "        let mut offset = out.len() * 8;").unwrap();
+    writeln!(code, "        self.encode(out, &mut offset);").unwrap();
+    writeln!(code, "    }}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(
+        code,
+        "    fn read(input: &[u8], offset: &mut usize) -> Result<Self, ParseError> {{"
+    )
+    .unwrap();
+    for line in reads {
+        writeln!(code, "        {}", line).unwrap();
+    }
+    writeln!(code).unwrap();
+    let literal = format!("        Ok(Self {{ {} }})", names.join(", "));
+    if literal.len() <= 100 {
+        writeln!(code, "{}", literal).unwrap();
+    } else {
+        writeln!(code, "        Ok(Self {{").unwrap();
+        for name in names {
+            writeln!(code, "            {},", name).unwrap();
+        }
+        writeln!(code, "        }})").unwrap();
+    }
+    writeln!(code, "    }}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(
+        code,
+        "    fn encode(&self, out: &mut Vec<u8>, offset: &mut usize) {{"
+    )
+    .unwrap();
+    for line in writes {
+        writeln!(code, "        {}", line).unwrap();
+    }
+    writeln!(code, "    }}").unwrap();
+    writeln!(code, "}}").unwrap();
+
+    Ok(())
+}
+
+/// Kind of value held by a field
+fn field_kind(
+    descriptor: &ProtoDescriptor,
+    field: &FieldDescriptor,
+    locals: &[CodeField],
+    unit: Unit,
+    until_end: bool,
+) -> Result<Kind, Error> {
+    if let Some((name, _)) = structure(descriptor, field)? {
+        return Ok(Kind::Struct(type_name(name)));
+    }
+
+    match alternatives_length(field)? {
+        FieldLength::Fixed(0) => Err(Error::FormatError(format!(
+            "Field {} has no length",
+            field.name
+        ))),
+        // Fields with alternatives are kept as bytes, as they are decoded by the caller
+        FieldLength::Fixed(length)
+            if length * unit.bits() <= 64 && field.alternatives.is_empty() =>
+        {
+            Ok(Kind::Uint {
+                bits: length * unit.bits(),
+                little: field.effective_endianness(&descriptor.elements) == Endianness::Little,
+                lsb_first: field.effective_bit_order(&descriptor.elements) == BitOrder::LsbFirst,
+            })
+        }
+        FieldLength::Fixed(length) if (length * unit.bits()).is_multiple_of(8) => {
+            Ok(Kind::Array(length * unit.bits() / 8))
+        }
+        FieldLength::Fixed(length) => Err(Error::FormatError(format!(
+            "Field {} of {} bits is neither an integer nor a whole number of bytes",
+            field.name,
+            length * unit.bits()
+        ))),
+        length => match resolve_length(&length, unit.bits(), locals, cast)? {
+            Some(bits) => Ok(Kind::Bytes(bits)),
+            None if until_end => Ok(Kind::Bytes("input.len() * 8 - *offset".to_owned())),
+            None => Err(Error::FormatError(format!(
+                "Length {} of field {} does not refer to a previous field",
+                length, field.name
+            ))),
+        },
+    }
+}
+
+/// Statements reading a field into its local variable
+fn read_lines(
+    field: &CodeField,
+    kind: &Kind,
+    repeat: &Option<Repeat>,
+    condition: &Option<String>,
+) -> Vec<String> {
+    let name = &field.field.name;
+    let local = local_name(&field.ident);
+
+    let value = match kind {
+        Kind::Uint {
+            bits,
+            little,
+            lsb_first,
+        } => {
+            let read = format!(
+                "read_uint(input, offset, {}, {}, {}, {:?})?",
+                bits, little, lsb_first, name
+            );
+            match uint_type(*bits) {
+                "u64" => read,
+                ty => format!("{} as {}", read, ty),
+            }
+        }
+        Kind::Array(_) => format!("read_array(input, offset, {:?})?", name),
+        Kind::Bytes(bits) => format!("read_bytes(input, offset, {}, {:?})?", bits, name),
+        Kind::Struct(ty) => format!("{}::read(input, offset)?", ty),
+    };
+
+    let repeated = |target: &str| {
+        let header = match repeat {
+            Some(Repeat::Count(count)) => format!("for _ in 0..{} {{", count),
+            _ => "while *offset < input.len() * 8 {".to_owned(),
+        };
+        vec![
+            format!("let mut {} = Vec::new();", target),
+            header,
+            format!("    {}.push({});", target, value),
+            "}".to_owned(),
+        ]
+    };
+
+    match (repeat, condition) {
+        (None, None) => vec![format!("let {} = {};", local, value)],
+        (Some(_), None) => repeated(&local),
+        (None, Some(condition)) => vec![
+            format!("let {} = if {} {{", local, condition),
+            format!("    Some({})", value),
+            "} else {".to_owned(),
+            "    None".to_owned(),
+            "};".to_owned(),
+        ],
+        (Some(_), Some(condition)) => {
+            let mut lines = vec![format!("let {} = if {} {{", local, condition)];
+            lines.extend(
+                repeated("items")
+                    .into_iter()
+                    .map(|line| format!("    {}", line)),
+            );
+            lines.extend([
+                "    Some(items)".to_owned(),
+                "} else {".to_owned(),
+                "    None".to_owned(),
+                "};".to_owned(),
+            ]);
+            lines
+        }
+    }
+}
+
+/// Statements writing a field from the struct
+fn write_lines(
+    field: &CodeField,
+    kind: &Kind,
+    repeat: &Option<Repeat>,
+    condition: &Option<String>,
+) -> Vec<String> {
+    let member = format!("self.{}", raw(&field.ident));
+
+    // Writes a value, given as a place (e.g. "self.length") or a reference (e.g. "item")
+    let write = |value: &str, reference: bool| match kind {
+        Kind::Uint {
+            bits,
+            little,
+            lsb_first,
+        } => {
+            let value = if reference {
+                format!("*{}", value)
+            } else {
+                value.to_owned()
+            };
+            let value = match uint_type(*bits) {
+                "u64" => value,
+                _ => format!("u64::from({})", value),
+            };
+            format!(
+                "write_uint(out, offset, {}, {}, {}, {});",
+                bits, value, little, lsb_first
+            )
+        }
+        Kind::Array(_) | Kind::Bytes(_) if reference => {
+            format!("write_bytes(out, offset, {});", value)
+        }
+        Kind::Array(_) | Kind::Bytes(_) => format!("write_bytes(out, offset, &{});", value),
+        Kind::Struct(_) => format!("{}.encode(out, offset);", value),
+    };
+
+    match (repeat, condition) {
+        (None, None) => vec![write(&member, false)],
+        (Some(_), None) => vec![
+            format!("for item in &{} {{", member),
+            format!("    {}", write("item", true)),
+            "}".to_owned(),
+        ],
+        (None, Some(_)) => vec![
+            format!("if let Some(value) = &{} {{", member),
+            format!("    {}", write("value", true)),
+            "}".to_owned(),
+        ],
+        (Some(_), Some(_)) => vec![
+            format!("if let Some(items) = &{} {{", member),
+            "    for item in items {".to_owned(),
+            format!("        {}", write("item", true)),
+            "    }".to_owned(),
+            "}".to_owned(),
+        ],
+    }
+}
+
+/// Smallest unsigned integer type holding the given number of bits
+fn uint_type(bits: usize) -> &'static str {
+    match bits {
+        0..=8 => "u8",
+        9..=16 => "u16",
+        17..=32 => "u32",
+        _ => "u64",
+    }
+}
+
+/// Local variable of a length or count, cast to usize
+fn cast(local: &str) -> String {
+    format!("{} as usize", local_name(local))
+}
+
+/// Identifier of a struct member, escaping the keywords
+fn raw(ident: &str) -> String {
+    if ["self", "super", "crate"].contains(&ident) {
+        format!("{}_", ident)
+    } else if KEYWORDS.contains(&ident) {
+        format!("r#{}", ident)
+    } else {
+        ident.to_owned()
+    }
+}
+
+/// Name of the local variable holding a field while reading, which can't shadow the generated names
+fn local_name(ident: &str) -> String {
+    if RESERVED.contains(&ident) {
+        format!("{}_", ident)
+    } else {
+        raw(ident)
+    }
+}
@@ -0,0 +1,253 @@
+//! Compatibility checker, classifying the wire format changes between two versions of a protocol
+
+use serde::Serialize;
+
+use crate::descriptor::{FieldDescriptor, FieldLength, ProtoDescriptor};
+use crate::diff::{merge, Change};
+use crate::errors::Error;
+
+/// Enum to hold whether a change keeps the data of one version readable by the other
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Compatibility {
+    Compatible,
+    Breaking,
+}
+
+/// Struct to hold a change found between the two versions
+#[derive(Debug, Serialize, Clone)]
+pub struct Finding {
+    pub rule: String, // Identifier of the kind of change (e.g. "field-appended")
+    pub field: String,
+    pub compatibility: Compatibility,
+    pub message: String,
+}
+
+/// Struct to hold the result of a compatibility check
+#[derive(Debug, Serialize, Clone)]
+pub struct CompatibilityReport {
+    pub compatibility: Compatibility, // Breaking if any of the findings is
+    pub findings: Vec<Finding>,
+}
+
+impl CompatibilityReport {
+    /// Whether any change breaks the wire format
+    pub fn is_breaking(&self) -> bool {
+        self.compatibility == Compatibility::Breaking
+    }
+}
+
+/// Compare two versions of a protocol, classifying each change as backward-compatible or breaking
+///
+/// Fields are matched by name, as in [`crate::diff::diff`]. Appending fields at the end, using reserved bits (of
+/// fields named reserved, padding or unused, or drawn with a fill pattern) and changing the reserved fields are
+/// compatible. Removing, resizing, moving or inserting fields is breaking, as well as shifting their offsets or
+/// changing their byte order, bit order or presence.
+///
+/// The findings use these rule identifiers: `field-appended`, `reserved-used`, `reserved-changed`, `field-removed`,
+/// `field-resized`, `field-moved`, `field-shifted`, `field-inserted`, `encoding-changed` and `presence-changed`.
+pub fn check(old: &ProtoDescriptor, new: &ProtoDescriptor) -> Result<CompatibilityReport, Error> {
+    let old_fields = old.flatten()?.fields;
+    let new_fields = new.flatten()?.fields;
+    let matches = merge(&old_fields, &new_fields)?;
+
+    // Bits of the old version that receivers ignore, as merged ranges
+    let mut reserved = matches
+        .iter()
+        .filter(|m| m.old.is_some_and(is_reserved))
+        .filter_map(|m| match (&m.diff.old_offset, &m.diff.old_length) {
+            (Some(FieldLength::Fixed(offset)), Some(FieldLength::Fixed(length))) => {
+                Some((*offset, offset + length))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    reserved.sort();
+    let reserved =
+        reserved
+            .into_iter()
+            .fold(Vec::<(usize, usize)>::new(), |mut ranges, (start, end)| {
+                match ranges.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => ranges.push((start, end)),
+                }
+                ranges
+            });
+
+    // Fields after the last one of the old version are appended
+    let tail = matches
+        .iter()
+        .rposition(|m| m.old.is_some() && m.new.is_some())
+        .map_or(0, |i| i + 1);
+
+    let mut findings = Vec::new();
+    for (i, m) in matches.iter().enumerate() {
+        let diff = &m.diff;
+        let mut finding = |rule: &str, compatibility, message: String| {
+            findings.push(Finding {
+                rule: rule.to_owned(),
+                field: diff.name.clone(),
+                compatibility,
+                message,
+            })
+        };
+
+        let (old_offset, new_offset) = (optional(&diff.old_offset), optional(&diff.new_offset));
+        let (old_length, new_length) = (optional(&diff.old_length), optional(&diff.new_length));
+
+        if diff.change == Change::Added {
+            let in_reserved = match (&diff.new_offset, &diff.new_length) {
+                (Some(FieldLength::Fixed(offset)), Some(FieldLength::Fixed(length))) => reserved
+                    .iter()
+                    .any(|(start, end)| start <= offset && offset + length <= *end),
+                _ => false,
+            };
+
+            if i >= tail {
+                finding(
+                    "field-appended",
+                    Compatibility::Compatible,
+                    format!("Field {} was appended at offset {}", diff.name, new_offset),
+                );
+            } else if in_reserved && m.new.is_some_and(is_reserved) {
+                finding(
+                    "reserved-changed",
+                    Compatibility::Compatible,
+                    format!(
+                        "Reserved field {} was added at offset {}",
+                        diff.name, new_offset
+                    ),
+                );
+            } else if in_reserved {
+                finding(
+                    "reserved-used",
+                    Compatibility::Compatible,
+                    format!(
+                        "Field {} uses reserved bits at offset {}",
+                        diff.name, new_offset
+                    ),
+                );
+            } else {
+                finding(
+                    "field-inserted",
+                    Compatibility::Breaking,
+                    format!("Field {} was inserted at offset {}", diff.name, new_offset),
+                );
+            }
+            continue;
+        }
+
+        // The reserved fields can change freely, as long as the other fields keep their place
+        if m.old.is_some_and(is_reserved) {
+            if diff.change != Change::Unchanged {
+                finding(
+                    "reserved-changed",
+                    Compatibility::Compatible,
+                    format!(
+                        "Reserved field {} was {}",
+                        diff.name,
+                        change_text(diff.change)
+                    ),
+                );
+            }
+            continue;
+        }
+
+        let (Some(old_field), Some(new_field)) = (m.old, m.new) else {
+            finding(
+                "field-removed",
+                Compatibility::Breaking,
+                format!("Field {} was removed from offset {}", diff.name, old_offset),
+            );
+            continue;
+        };
+
+        if diff.old_length != diff.new_length {
+            finding(
+                "field-resized",
+                Compatibility::Breaking,
+                format!(
+                    "Field {} changed length from {} to {}",
+                    diff.name, old_length, new_length
+                ),
+            );
+        }
+
+        if diff.change == Change::Moved {
+            finding(
+                "field-moved",
+                Compatibility::Breaking,
+                format!(
+                    "Field {} moved from offset {} to {}",
+                    diff.name, old_offset, new_offset
+                ),
+            );
+        } else if diff.old_offset != diff.new_offset {
+            finding(
+                "field-shifted",
+                Compatibility::Breaking,
+                format!(
+                    "Field {} shifted from offset {} to {}",
+                    diff.name, old_offset, new_offset
+                ),
+            );
+        }
+
+        if old_field.effective_endianness(&old.elements)
+            != new_field.effective_endianness(&new.elements)
+            || old_field.effective_bit_order(&old.elements)
+                != new_field.effective_bit_order(&new.elements)
+        {
+            finding(
+                "encoding-changed",
+                Compatibility::Breaking,
+                format!("Field {} changed its byte or bit order", diff.name),
+            );
+        }
+
+        if old_field.is_optional() != new_field.is_optional()
+            || old_field.condition != new_field.condition
+        {
+            finding(
+                "presence-changed",
+                Compatibility::Breaking,
+                format!("Field {} changed when it is present", diff.name),
+            );
+        }
+    }
+
+    let compatibility = findings
+        .iter()
+        .map(|finding| finding.compatibility)
+        .max()
+        .unwrap_or(Compatibility::Compatible);
+
+    Ok(CompatibilityReport {
+        compatibility,
+        findings,
+    })
+}
+
+/// Whether the bits of a field are reserved, so receivers ignore them
+fn is_reserved(field: &FieldDescriptor) -> bool {
+    let name = field.name.to_lowercase();
+    field.pattern.is_some()
+        || ["reserved", "rsvd", "padding", "unused"]
+            .iter()
+            .any(|word| name.contains(word))
+}
+
+fn optional(length: &Option<FieldLength>) -> String {
+    length
+        .as_ref()
+        .map_or("none".to_owned(), |length| length.to_string())
+}
+
+fn change_text(change: Change) -> &'static str {
+    match change {
+        Change::Removed => "removed",
+        Change::Resized => "resized",
+        Change::Moved => "moved",
+        _ => "changed",
+    }
+}
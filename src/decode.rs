@@ -0,0 +1,484 @@
+//! Decoder, splitting binary data into the fields of a descriptor
+
+use std::fmt::Write;
+use std::ops::Range;
+
+use serde::Serialize;
+
+use crate::codegen::{alternatives_length, parse_number, Unit};
+use crate::descriptor::{
+    BitOrder, Endianness, FieldDescriptor, FieldLength, FieldRef, GroupDescriptor, GroupPosition,
+    ProtoDescriptor,
+};
+use crate::errors::Error;
+
+/// Field decoded from the data, with its structure fields or repetitions as children
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DecodedField {
+    pub name: String,
+    pub bits: Range<usize>, // Bits of the data taken by the field
+    pub raw: Vec<u8>,       // Bytes of the data spanned by the field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<u64>, // Value of the fixed length fields of up to 64 bits
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<DecodedField>, // Fields of the structure, or repetitions of the field
+}
+
+impl DecodedField {
+    /// Bytes of the data spanned by the field
+    pub fn bytes(&self) -> Range<usize> {
+        self.bits.start / 8..self.bits.end.div_ceil(8)
+    }
+}
+
+/// Decode the data into the protocol fields, whose lengths are interpreted in the given unit
+///
+/// Fields are placed one after the other, as in the diagram positions. Variable lengths, repeat counts and conditions
+/// are resolved from the values of the previous fields, except for the last field of the protocol, which extends until
+/// the end of the data. Optional fields without a condition are decoded if there is data left. Fixed length fields are
+/// read as integers with their byte and bit order, while variable fields and fields with alternatives are kept as bytes.
+pub fn decode(
+    descriptor: &ProtoDescriptor,
+    data: &[u8],
+    unit: Unit,
+) -> Result<Vec<DecodedField>, Error> {
+    if descriptor.fields.is_empty() {
+        return Err(Error::FormatError("No fields provided".to_string()));
+    }
+
+    // Catches the unknown and recursive structures
+    descriptor.flatten()?;
+
+    let decoder = Decoder {
+        descriptor,
+        data,
+        unit,
+    };
+
+    decoder.fields(&descriptor.fields, &mut 0, &mut Vec::new(), true)
+}
+
+/// Annotated hex dump of the decoded fields, with a line per field (and per 8 bytes of it)
+pub fn hex_dump(fields: &[DecodedField]) -> String {
+    let mut dump = String::new();
+    dump_fields(&mut dump, fields, 0);
+    dump
+}
+
+fn dump_fields(dump: &mut String, fields: &[DecodedField], depth: usize) {
+    for field in fields {
+        let mut label = format!("{}{}", "  ".repeat(depth), field.name);
+        if let Some(value) = field.value {
+            if value < 10 {
+                write!(label, " = {}", value).unwrap();
+            } else {
+                write!(label, " = {} ({:#x})", value, value).unwrap();
+            }
+        }
+
+        // Fields within a byte show which of its bits they take
+        if !field.bits.start.is_multiple_of(8) || !field.bits.end.is_multiple_of(8) {
+            let first = field.bits.start % 8;
+            write!(
+                label,
+                " (bits {}-{})",
+                first,
+                first + field.bits.len().max(1) - 1
+            )
+            .unwrap();
+        }
+
+        let start = field.bytes().start;
+        if !field.children.is_empty() || field.raw.is_empty() {
+            writeln!(dump, "{:06x}  {:25}{}", start, "", label).unwrap();
+            dump_fields(dump, &field.children, depth + 1);
+            continue;
+        }
+
+        for (i, chunk) in field.raw.chunks(8).enumerate() {
+            let hex = chunk
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let label = if i == 0 { label.as_str() } else { "" };
+            let line = format!("{:06x}  {:25}{}", start + i * 8, hex, label);
+            writeln!(dump, "{}", line.trim_end()).unwrap();
+        }
+    }
+}
+
+/// Descriptor of the decoded data, to render it with the field values
+///
+/// The fields are named after their values, the absent ones are removed, and the repetitions and structures are
+/// expanded, with a group for each structure. Fields keep their variable lengths, so the long ones are not drawn at
+/// full size, while the fixed lengths (including the alternatives) take their decoded length.
+pub fn annotate(
+    descriptor: &ProtoDescriptor,
+    decoded: &[DecodedField],
+    unit: Unit,
+) -> ProtoDescriptor {
+    let mut annotated = ProtoDescriptor {
+        elements: descriptor.elements.clone(),
+        style: descriptor.style.clone(),
+        ..Default::default()
+    };
+
+    annotate_fields(
+        descriptor,
+        &descriptor.fields,
+        decoded,
+        unit,
+        &mut annotated,
+    );
+
+    annotated
+}
+
+fn annotate_fields(
+    descriptor: &ProtoDescriptor,
+    fields: &[FieldDescriptor],
+    decoded: &[DecodedField],
+    unit: Unit,
+    annotated: &mut ProtoDescriptor,
+) {
+    // The decoded fields keep the descriptor order, without the absent ones
+    let mut decoded = decoded.iter().peekable();
+
+    for field in fields {
+        let Some(item) = decoded.next_if(|item| item.name == field.name) else {
+            continue;
+        };
+
+        if field.repeat.is_some() {
+            for child in &item.children {
+                annotate_field(descriptor, field, child, unit, annotated);
+            }
+        } else {
+            annotate_field(descriptor, field, item, unit, annotated);
+        }
+    }
+}
+
+/// Add a single occurrence of a field, expanding its structure
+fn annotate_field(
+    descriptor: &ProtoDescriptor,
+    field: &FieldDescriptor,
+    item: &DecodedField,
+    unit: Unit,
+    annotated: &mut ProtoDescriptor,
+) {
+    if let Some(fields) = field
+        .structure
+        .as_ref()
+        .and_then(|name| descriptor.definitions.get(name))
+    {
+        let first = annotated.fields.len();
+        annotate_fields(descriptor, fields, &item.children, unit, annotated);
+        if annotated.fields.len() == first {
+            return;
+        }
+
+        // The structure options apply to the fields that don't override them
+        for sub_field in &mut annotated.fields[first..] {
+            sub_field.color = sub_field.color.or(field.color);
+            sub_field.pattern = sub_field.pattern.or(field.pattern);
+        }
+        annotated.fields.last_mut().unwrap().wrap |= field.wrap;
+
+        annotated.groups.push(GroupDescriptor {
+            name: item.name.clone(),
+            start: FieldRef::Index(first),
+            end: FieldRef::Index(annotated.fields.len() - 1),
+            position: GroupPosition::Above,
+        });
+        return;
+    }
+
+    let mut annotated_field = field.clone();
+    annotated_field.name = match (item.value, item.raw.len()) {
+        (Some(value), _) => format!("{} = {}", item.name, value),
+        (None, 1..=8) => {
+            let hex = item.raw.iter().map(|byte| format!("{:02x}", byte));
+            format!("{} = 0x{}", item.name, hex.collect::<String>())
+        }
+        (None, bytes) => format!("{} ({} bytes)", item.name, bytes),
+    };
+    // The length was resolved when decoding, so it can't fail here
+    annotated_field.length = match alternatives_length(field) {
+        Ok(FieldLength::Variable(length)) => FieldLength::Variable(length),
+        _ => FieldLength::Fixed(item.bits.len() / unit.bits()),
+    };
+    annotated_field.optional = false;
+    annotated_field.condition = None;
+    annotated_field.repeat = None;
+    annotated_field.alternatives = Vec::new();
+
+    annotated.fields.push(annotated_field);
+}
+
+struct Decoder<'a> {
+    descriptor: &'a ProtoDescriptor,
+    data: &'a [u8],
+    unit: Unit,
+}
+
+impl Decoder<'_> {
+    /// Decode a list of fields from the offset, in bits, with the values of the fields read so far
+    fn fields(
+        &self,
+        fields: &[FieldDescriptor],
+        offset: &mut usize,
+        values: &mut Vec<(String, u64)>,
+        top_level: bool,
+    ) -> Result<Vec<DecodedField>, Error> {
+        let mut decoded = Vec::new();
+
+        for (i, field) in fields.iter().enumerate() {
+            let until_end = top_level && i + 1 == fields.len();
+
+            let present = match &field.condition {
+                Some(condition) => evaluate(condition, values).ok_or_else(|| {
+                    Error::FormatError(format!(
+                        "Condition {} of field {} could not be resolved",
+                        condition, field.name
+                    ))
+                })?,
+                None if field.optional => *offset < self.data.len() * 8,
+                None => true,
+            };
+            if !present {
+                continue;
+            }
+
+            let start = *offset;
+            let count = match &field.repeat {
+                None => None,
+                Some(count) => match resolve(count, 1, values, &field.name)? {
+                    // Every occurrence takes data, so the count can't exceed the bits left
+                    Some(count) if count > (self.data.len() * 8).saturating_sub(*offset) => {
+                        return Err(Error::FormatError(format!(
+                            "Repeat count {} of field {} exceeds the data left",
+                            count, field.name
+                        )))
+                    }
+                    Some(count) => Some(count),
+                    None if until_end => None,
+                    None => {
+                        return Err(Error::FormatError(format!(
+                            "Repeat count {} of field {} could not be resolved",
+                            count, field.name
+                        )))
+                    }
+                },
+            };
+
+            let field = match (&field.repeat, count) {
+                (None, _) => {
+                    let (value, children) = self.field(field, offset, values, until_end)?;
+                    if let Some(value) = value {
+                        values.push((field.name.clone(), value));
+                    }
+
+                    self.decoded(&field.name, start..*offset, value, children)
+                }
+                (Some(_), count) => {
+                    let mut children = Vec::new();
+                    // Repeats until the end of the data if the count is unknown
+                    while count.map_or(*offset < self.data.len() * 8, |count| {
+                        children.len() < count
+                    }) {
+                        let item_start = *offset;
+                        let (value, grandchildren) = self.field(field, offset, values, false)?;
+                        if *offset == item_start {
+                            return Err(Error::FormatError(format!(
+                                "Field {} repeats without taking any data",
+                                field.name
+                            )));
+                        }
+
+                        let name = format!("{}[{}]", field.name, children.len());
+                        children.push(self.decoded(
+                            &name,
+                            item_start..*offset,
+                            value,
+                            grandchildren,
+                        ));
+                    }
+
+                    self.decoded(&field.name, start..*offset, None, children)
+                }
+            };
+
+            decoded.push(field);
+        }
+
+        Ok(decoded)
+    }
+
+    /// Decode a single occurrence of a field, returning its value or its structure fields
+    fn field(
+        &self,
+        field: &FieldDescriptor,
+        offset: &mut usize,
+        values: &[(String, u64)],
+        until_end: bool,
+    ) -> Result<(Option<u64>, Vec<DecodedField>), Error> {
+        if let Some(name) = &field.structure {
+            let fields = self
+                .descriptor
+                .definitions
+                .get(name)
+                .ok_or_else(|| Error::FormatError(format!("Unknown structure {}", name)))?;
+
+            // The structure fields can refer to the fields before it
+            let children = self.fields(fields, offset, &mut values.to_vec(), false)?;
+            return Ok((None, children));
+        }
+
+        let available = self.data.len() * 8 - (*offset).min(self.data.len() * 8);
+        let bits = match alternatives_length(field)? {
+            FieldLength::Fixed(0) => {
+                return Err(Error::FormatError(format!(
+                    "Field {} has no length",
+                    field.name
+                )))
+            }
+            length => match resolve(&length, self.unit.bits(), values, &field.name)? {
+                Some(bits) => bits,
+                None if until_end => available,
+                None => {
+                    return Err(Error::FormatError(format!(
+                        "Length {} of field {} could not be resolved",
+                        length, field.name
+                    )))
+                }
+            },
+        };
+
+        if bits > available {
+            return Err(Error::FormatError(format!(
+                "Data too short for field {}, which needs {} bits at bit {} of {}",
+                field.name,
+                bits,
+                offset,
+                self.data.len() * 8
+            )));
+        }
+
+        // Variable fields and fields with alternatives are kept as bytes, as they are not integers
+        let integer =
+            matches!(field.length, FieldLength::Fixed(1..)) && field.alternatives.is_empty();
+        let value = (integer && bits <= 64).then(|| {
+            read_uint(
+                self.data,
+                *offset,
+                bits,
+                field.effective_endianness(&self.descriptor.elements) == Endianness::Little,
+                field.effective_bit_order(&self.descriptor.elements) == BitOrder::LsbFirst,
+            )
+        });
+        *offset += bits;
+
+        Ok((value, Vec::new()))
+    }
+
+    fn decoded(
+        &self,
+        name: &str,
+        bits: Range<usize>,
+        value: Option<u64>,
+        children: Vec<DecodedField>,
+    ) -> DecodedField {
+        let raw = self.data[bits.start / 8..bits.end.div_ceil(8)].to_vec();
+
+        DecodedField {
+            name: name.to_owned(),
+            bits,
+            raw,
+            value,
+            children,
+        }
+    }
+}
+
+/// Read an integer, in the bit order of the field and then swapped to its byte order
+fn read_uint(data: &[u8], offset: usize, bits: usize, little: bool, lsb_first: bool) -> u64 {
+    let mut value = 0u64;
+    for i in 0..bits {
+        let bit = offset + i;
+        if lsb_first {
+            value |= u64::from((data[bit / 8] >> (bit % 8)) & 1) << i;
+        } else {
+            value = (value << 1) | u64::from((data[bit / 8] >> (7 - bit % 8)) & 1);
+        }
+    }
+
+    // The bit order gives the byte order, unless the value is stored with the other one
+    if bits > 8 && bits.is_multiple_of(8) && little != lsb_first {
+        value = value.swap_bytes() >> (64 - bits);
+    }
+
+    value
+}
+
+/// Resolve a length from the values of the previous fields, multiplied by `scale`
+///
+/// Returns `None` if a value is missing, and an error if the length overflows, as the values come from the data.
+fn resolve(
+    length: &FieldLength,
+    scale: usize,
+    values: &[(String, u64)],
+    field: &str,
+) -> Result<Option<usize>, Error> {
+    let overflow = || Error::FormatError(format!("Length {} of field {} overflows", length, field));
+    let (fixed, terms) = length.terms()?;
+
+    let mut total = fixed;
+    for (count, name) in terms {
+        let Some(value) = value(&name, values) else {
+            return Ok(None);
+        };
+        total = usize::try_from(value)
+            .ok()
+            .and_then(|value| value.checked_mul(count))
+            .and_then(|term| total.checked_add(term))
+            .ok_or_else(overflow)?;
+    }
+
+    total.checked_mul(scale).map(Some).ok_or_else(overflow)
+}
+
+/// Evaluate a condition (e.g. "flags & 1", "kind == 2" or "present") from the values of the previous fields
+fn evaluate(condition: &str, values: &[(String, u64)]) -> Option<bool> {
+    let operand = |text: &str| {
+        let text = text.trim();
+        parse_number(text).or_else(|| value(text, values))
+    };
+
+    for op in ["==", "!=", "<=", ">=", "<", ">", "&"] {
+        if let Some((left, right)) = condition.split_once(op) {
+            let (left, right) = (operand(left)?, operand(right)?);
+            return Some(match op {
+                "==" => left == right,
+                "!=" => left != right,
+                "<=" => left <= right,
+                ">=" => left >= right,
+                "<" => left < right,
+                ">" => left > right,
+                _ => left & right != 0,
+            });
+        }
+    }
+
+    operand(condition).map(|value| value != 0)
+}
+
+/// Value of the latest field with the given name
+fn value(name: &str, values: &[(String, u64)]) -> Option<u64> {
+    values
+        .iter()
+        .rev()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| *value)
+}
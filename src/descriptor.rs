@@ -1,6 +1,10 @@
+use std::{collections::BTreeMap, fmt};
+
 use hex_color::HexColor;
 use serde::{Deserialize, Serialize};
 
+use crate::errors::Error;
+
 /// Enum to hold the length of a field
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde(untagged)]
@@ -9,28 +13,365 @@ pub enum FieldLength {
     Variable(String),
 }
 
-impl ToString for FieldLength {
-    fn to_string(&self) -> String {
+impl Default for FieldLength {
+    /// Zero length, meaning the length is derived from the field structure
+    fn default() -> Self {
+        FieldLength::Fixed(0)
+    }
+}
+
+impl FieldLength {
+    /// Length of two fields placed one after the other
+    pub fn add(&self, other: &FieldLength) -> Result<FieldLength, Error> {
+        let (fixed, mut terms) = self.terms()?;
+        let (other_fixed, other_terms) = other.terms()?;
+
+        let fixed = fixed
+            .checked_add(other_fixed)
+            .ok_or_else(|| overflow(self, other))?;
+        for (count, name) in other_terms {
+            push_term(&mut terms, count, name).ok_or_else(|| overflow(self, other))?;
+        }
+
+        Ok(FieldLength::from_terms(fixed, terms))
+    }
+
+    /// Length of the field repeated `count` times
+    pub fn repeat(&self, count: &FieldLength) -> Result<FieldLength, Error> {
+        let (fixed, terms) = self.terms()?;
+        let (count_fixed, count_terms) = count.terms()?;
+
+        let mut result = Vec::new();
+        for (length_count, length) in [(fixed, String::new())].into_iter().chain(terms) {
+            for (count_count, count_name) in [(count_fixed, String::new())]
+                .iter()
+                .cloned()
+                .chain(count_terms.iter().cloned())
+            {
+                let name = match (count_name.is_empty(), length.is_empty()) {
+                    (true, _) => length.clone(),
+                    (false, true) => count_name,
+                    (false, false) => format!("{}×{}", count_name, length),
+                };
+
+                length_count
+                    .checked_mul(count_count)
+                    .and_then(|product| push_term(&mut result, product, name))
+                    .ok_or_else(|| {
+                        Error::FormatError(format!(
+                            "Length {} repeated {} times overflows",
+                            self, count
+                        ))
+                    })?;
+            }
+        }
+
+        // The unnamed term holds the fixed part of the product
+        let fixed = result
+            .iter()
+            .position(|(_, name)| name.is_empty())
+            .map(|i| result.remove(i).0)
+            .unwrap_or(0);
+
+        Ok(FieldLength::from_terms(fixed, result))
+    }
+
+    /// Split the length into its fixed part and its variable terms (e.g. "4+2N" into 4 and [(2, "N")])
+    pub(crate) fn terms(&self) -> Result<(usize, Vec<(usize, String)>), Error> {
         match self {
-            FieldLength::Fixed(length) => length.to_string(),
-            FieldLength::Variable(name) => name.clone(),
+            FieldLength::Fixed(length) => Ok((*length, Vec::new())),
+            FieldLength::Variable(length) => {
+                let invalid = || Error::FormatError(format!("Length {} is too large", length));
+                let mut fixed = 0usize;
+                let mut terms = Vec::new();
+
+                for term in length.split('+').map(str::trim).filter(|t| !t.is_empty()) {
+                    if term.chars().all(|c| c.is_ascii_digit()) {
+                        fixed = term
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|value| fixed.checked_add(value))
+                            .ok_or_else(invalid)?;
+                    } else {
+                        let (count, name) = variable_parts(term).ok_or_else(invalid)?;
+                        push_term(&mut terms, count, name.to_owned()).ok_or_else(invalid)?;
+                    }
+                }
+
+                Ok((fixed, terms))
+            }
+        }
+    }
+
+    /// Create a length from its fixed part and variable terms
+    fn from_terms(fixed: usize, terms: Vec<(usize, String)>) -> FieldLength {
+        let terms = terms
+            .into_iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| {
+                if count == 1 {
+                    name
+                } else {
+                    format!("{}{}", count, name)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if terms.is_empty() {
+            FieldLength::Fixed(fixed)
+        } else if fixed > 0 {
+            FieldLength::Variable(format!("{}+{}", fixed, terms.join("+")))
+        } else {
+            FieldLength::Variable(terms.join("+"))
+        }
+    }
+}
+
+fn overflow(length: &FieldLength, other: &FieldLength) -> Error {
+    Error::FormatError(format!("Length {} + {} overflows", length, other))
+}
+
+/// Add a variable term to a list, merging it with an existing term of the same name (`None` on overflow)
+fn push_term(terms: &mut Vec<(usize, String)>, count: usize, name: String) -> Option<()> {
+    match terms.iter_mut().find(|(_, n)| *n == name) {
+        Some((c, _)) => *c = c.checked_add(count)?,
+        None => terms.push((count, name)),
+    }
+    Some(())
+}
+
+/// Split a variable length into its multiplier and name (e.g. "4N" into 4 and "N"), `None` if the multiplier
+/// overflows
+fn variable_parts(length: &str) -> Option<(usize, &str)> {
+    let length = length.trim();
+    let first_letter = length
+        .chars()
+        .position(|c| !c.is_ascii_digit())
+        .unwrap_or(0);
+
+    if first_letter == 0 {
+        Some((1, length))
+    } else {
+        let count = length[..first_letter].parse::<usize>().ok()?;
+
+        Some((count, &length[first_letter..]))
+    }
+}
+
+impl fmt::Display for FieldLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldLength::Fixed(length) => write!(f, "{}", length),
+            FieldLength::Variable(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Enum to hold the fill pattern of a field
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FillPattern {
+    Hatched,
+    Dotted,
+    Crosshatch,
+}
+
+/// Enum to hold the byte order of a field
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Endianness {
+    #[serde(alias = "be")]
+    Big,
+    #[serde(alias = "le")]
+    Little,
+}
+
+/// Enum to hold the order in which the bits of a field are transmitted
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum BitOrder {
+    #[serde(alias = "msb_first", alias = "msb")]
+    MsbFirst,
+    #[serde(alias = "lsb_first", alias = "lsb")]
+    LsbFirst,
+}
+
+/// Enum to hold the access type of a register field
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+pub enum Access {
+    #[serde(rename = "rw", alias = "RW")]
+    ReadWrite,
+    #[serde(rename = "ro", alias = "RO")]
+    ReadOnly,
+    #[serde(rename = "wo", alias = "WO")]
+    WriteOnly,
+    #[serde(rename = "w1c", alias = "W1C")]
+    WriteOneToClear,
+    #[serde(rename = "w1s", alias = "W1S")]
+    WriteOneToSet,
+    #[serde(rename = "rc", alias = "RC")]
+    ReadToClear,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::ReadWrite => write!(f, "RW"),
+            Access::ReadOnly => write!(f, "RO"),
+            Access::WriteOnly => write!(f, "WO"),
+            Access::WriteOneToClear => write!(f, "W1C"),
+            Access::WriteOneToSet => write!(f, "W1S"),
+            Access::ReadToClear => write!(f, "RC"),
+        }
+    }
+}
+
+/// Enum to hold the reset value of a register field
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum ResetValue {
+    Number(u64),
+    Text(String), // Reset value that isn't a plain number (e.g. "0b101" or "undefined")
+}
+
+impl fmt::Display for ResetValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetValue::Number(value) => write!(f, "{:#x}", value),
+            ResetValue::Text(value) => write!(f, "{}", value),
         }
     }
 }
 
 /// Struct to hold the options for a field
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct FieldDescriptor {
     pub name: String,
-    pub length: FieldLength,
+    #[serde(default)]
+    pub length: FieldLength, // Length of the field (derived from the structure if zero)
     #[serde(default)]
     pub wrap: bool, // Whether to wrap at the end of the field
     #[serde(skip_serializing_if = "Option::is_none")]
     pub color: Option<HexColor>, // Color of the field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<FillPattern>, // Fill pattern of the field (drawn over its color)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub optional: bool, // Whether the field may be absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>, // Condition for the field to be present (implies optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<FieldLength>, // Number of times the field is repeated (fixed or symbolic)
+    #[serde(rename = "struct", skip_serializing_if = "Option::is_none")]
+    pub structure: Option<String>, // Name of the definition describing the field contents
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub expand: bool, // Whether to draw the structure fields inline instead of a single box
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<AlternativeDescriptor>, // Layouts of the field, selected by a discriminator
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endianness: Option<Endianness>, // Byte order of the field (defaults to the protocol order)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_order: Option<BitOrder>, // Bit order of the field (defaults to the protocol order)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access: Option<Access>, // Access type of the register field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset: Option<ResetValue>, // Reset value of the register field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>, // Description of the field, shown as a tooltip
+}
+
+impl FieldDescriptor {
+    /// Create a field with the given name and length, using the default options
+    pub fn new(name: impl Into<String>, length: FieldLength) -> Self {
+        Self {
+            name: name.into(),
+            length,
+            wrap: false,
+            color: None,
+            pattern: None,
+            optional: false,
+            condition: None,
+            repeat: None,
+            structure: None,
+            expand: false,
+            alternatives: Vec::new(),
+            endianness: None,
+            bit_order: None,
+            access: None,
+            reset: None,
+            description: None,
+        }
+    }
+
+    /// Whether the field may be absent from the protocol
+    pub fn is_optional(&self) -> bool {
+        self.optional || self.condition.is_some()
+    }
+
+    /// Byte order of the field, falling back to the protocol order
+    pub fn effective_endianness(&self, elements: &ElementsDescriptor) -> Endianness {
+        self.endianness.unwrap_or(if elements.network_order {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        })
+    }
+
+    /// Bit order of the field, falling back to the protocol order
+    pub fn effective_bit_order(&self, elements: &ElementsDescriptor) -> BitOrder {
+        self.bit_order.unwrap_or(if elements.network_order {
+            BitOrder::MsbFirst
+        } else {
+            BitOrder::LsbFirst
+        })
+    }
+
+    /// Register annotation of the field, showing its access type and reset value
+    pub fn register_info(&self) -> Option<String> {
+        match (&self.access, &self.reset) {
+            (Some(access), Some(reset)) => Some(format!("{} · {}", access, reset)),
+            (Some(access), None) => Some(access.to_string()),
+            (None, Some(reset)) => Some(reset.to_string()),
+            (None, None) => None,
+        }
+    }
+
+    /// Length of the field, taking into account its repetitions
+    pub fn total_length(&self) -> Result<FieldLength, Error> {
+        match &self.repeat {
+            Some(count) => self.length.repeat(count),
+            None => Ok(self.length.clone()),
+        }
+    }
+}
+
+/// Struct to hold an alternative layout of a field
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AlternativeDescriptor {
+    pub name: String, // Discriminator value selecting the alternative
+    pub fields: Vec<FieldDescriptor>,
+}
+
+impl AlternativeDescriptor {
+    /// Length of the alternative, adding up its fields
+    pub fn length(&self) -> Result<FieldLength, Error> {
+        self.fields
+            .iter()
+            .try_fold(FieldLength::Fixed(0), |length, field| {
+                length.add(&field.total_length()?)
+            })
+    }
+}
+
+/// Enum to hold how the bits of a protocol are numbered
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BitNumbering {
+    Msb0, // The most significant bit is bit 0 (RFC style)
+    Lsb0, // The least significant bit is bit 0 (hardware register style)
 }
 
 /// Struct to hold the options for the image elements
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ElementsDescriptor {
     #[serde(default = "default_true", alias = "is_network")]
     /// Whether it is a network protocol (big endian)
@@ -50,6 +391,20 @@ pub struct ElementsDescriptor {
     #[serde(default = "default_true")]
     /// Whether to show the wrap line
     pub start_symbol: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Bit numbering used to label the fields with their bit range, instead of their position
+    pub bit_numbering: Option<BitNumbering>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    /// Whether to draw a hardware register, labelling the fields with their bit range (LSB0 by default)
+    pub register: bool,
+}
+
+impl ElementsDescriptor {
+    /// Bit numbering used for the bit ranges, if they are shown
+    pub fn bit_numbering(&self) -> Option<BitNumbering> {
+        self.bit_numbering
+            .or(self.register.then_some(BitNumbering::Lsb0))
+    }
 }
 
 impl Default for ElementsDescriptor {
@@ -61,6 +416,8 @@ impl Default for ElementsDescriptor {
             field_length: true,
             wrap_line: true,
             start_symbol: true,
+            bit_numbering: None,
+            register: false,
         }
     }
 }
@@ -69,38 +426,157 @@ fn default_true() -> bool {
     true
 }
 
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Built-in color themes
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Theme {
+    #[default]
+    Light,
+    Dark,
+    #[serde(alias = "high_contrast")]
+    HighContrast,
+    #[serde(alias = "print_grayscale")]
+    PrintGrayscale,
+}
+
+/// Struct to hold the colors defined by a theme
+#[derive(Debug, Serialize, PartialEq, Clone, Copy)]
+pub struct ThemeColors {
+    pub background_color: HexColor,
+    pub field_color: HexColor,
+    pub text_color: HexColor,
+    pub subtitle_color: HexColor,
+}
+
+impl Theme {
+    /// Colors used by the theme
+    pub fn colors(&self) -> ThemeColors {
+        match self {
+            Theme::Light => ThemeColors {
+                background_color: default_white(),
+                field_color: default_white(),
+                text_color: default_black(),
+                subtitle_color: default_black(),
+            },
+            Theme::Dark => ThemeColors {
+                background_color: HexColor::rgb(30, 30, 30),
+                field_color: HexColor::rgb(45, 45, 45),
+                text_color: HexColor::rgb(230, 230, 230),
+                subtitle_color: HexColor::rgb(180, 180, 180),
+            },
+            Theme::HighContrast => ThemeColors {
+                background_color: default_black(),
+                field_color: default_black(),
+                text_color: default_white(),
+                subtitle_color: HexColor::rgb(255, 255, 0),
+            },
+            Theme::PrintGrayscale => ThemeColors {
+                background_color: default_white(),
+                field_color: HexColor::rgb(240, 240, 240),
+                text_color: default_black(),
+                subtitle_color: HexColor::rgb(80, 80, 80),
+            },
+        }
+    }
+}
+
 /// Struct to hold the options for the image style
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(from = "StyleDescriptorDef")]
 pub struct StyleDescriptor {
-    #[serde(default = "default_white")]
+    /// Theme used for the colors that are not explicitly set
+    pub theme: Theme,
     /// Background color of the image
     pub background_color: HexColor,
-    #[serde(default = "default_white")]
     /// Color of the field background
     pub field_color: HexColor,
-    #[serde(default = "default_black")]
     /// Text color of the image (field names + stroke)
     pub text_color: HexColor,
-    #[serde(default = "default_black")]
     /// Color of the subtitle text (field length and position)
     pub subtitle_color: HexColor,
-    #[serde(default = "default_50")]
     /// Width of a field unit in the image
     pub unit_width: usize,
-    #[serde(default = "default_3")]
     /// Units of a dynamic field
     pub dyn_units: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Theme applied when the viewer prefers a dark color scheme
+    pub dark_theme: Option<Theme>,
+    /// Whether to style the image through CSS classes instead of inline attributes
+    pub css_classes: bool,
 }
 
-impl Default for StyleDescriptor {
-    fn default() -> Self {
+impl StyleDescriptor {
+    /// Create a style using the colors of the given theme
+    pub fn from_theme(theme: Theme) -> Self {
+        let colors = theme.colors();
+
         Self {
-            background_color: default_white(),
-            field_color: default_white(),
-            text_color: default_black(),
-            subtitle_color: default_black(),
+            theme,
+            background_color: colors.background_color,
+            field_color: colors.field_color,
+            text_color: colors.text_color,
+            subtitle_color: colors.subtitle_color,
             unit_width: default_50(),
             dyn_units: default_3(),
+            dark_theme: None,
+            css_classes: false,
+        }
+    }
+
+    /// Colors currently used by the style
+    pub fn colors(&self) -> ThemeColors {
+        ThemeColors {
+            background_color: self.background_color,
+            field_color: self.field_color,
+            text_color: self.text_color,
+            subtitle_color: self.subtitle_color,
+        }
+    }
+}
+
+impl Default for StyleDescriptor {
+    fn default() -> Self {
+        Self::from_theme(Theme::default())
+    }
+}
+
+/// Deserialization helper for StyleDescriptor, so explicit colors override the theme ones
+#[derive(Deserialize)]
+struct StyleDescriptorDef {
+    #[serde(default)]
+    theme: Theme,
+    background_color: Option<HexColor>,
+    field_color: Option<HexColor>,
+    text_color: Option<HexColor>,
+    subtitle_color: Option<HexColor>,
+    #[serde(default = "default_50")]
+    unit_width: usize,
+    #[serde(default = "default_3")]
+    dyn_units: usize,
+    #[serde(default)]
+    dark_theme: Option<Theme>,
+    #[serde(default)]
+    css_classes: bool,
+}
+
+impl From<StyleDescriptorDef> for StyleDescriptor {
+    fn from(def: StyleDescriptorDef) -> Self {
+        let colors = def.theme.colors();
+
+        Self {
+            theme: def.theme,
+            background_color: def.background_color.unwrap_or(colors.background_color),
+            field_color: def.field_color.unwrap_or(colors.field_color),
+            text_color: def.text_color.unwrap_or(colors.text_color),
+            subtitle_color: def.subtitle_color.unwrap_or(colors.subtitle_color),
+            unit_width: def.unit_width,
+            dyn_units: def.dyn_units,
+            dark_theme: def.dark_theme,
+            css_classes: def.css_classes,
         }
     }
 }
@@ -121,6 +597,35 @@ fn default_3() -> usize {
     3
 }
 
+/// Enum to hold a reference to a field, by its index or name
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum FieldRef {
+    Index(usize),
+    Name(String),
+}
+
+/// Enum to hold where a group bracket is drawn
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupPosition {
+    #[default]
+    Above,
+    Below,
+}
+
+/// Struct to hold the options for a group of fields
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GroupDescriptor {
+    pub name: String,
+    #[serde(alias = "from")]
+    pub start: FieldRef, // First field of the group
+    #[serde(alias = "to")]
+    pub end: FieldRef, // Last field of the group
+    #[serde(default)]
+    pub position: GroupPosition, // Whether the bracket is drawn above or below the rows
+}
+
 /// Struct to hold the options for a protocol
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct ProtoDescriptor {
@@ -132,4 +637,247 @@ pub struct ProtoDescriptor {
     pub style: StyleDescriptor,
     /// List of fields the protocol contains
     pub fields: Vec<FieldDescriptor>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    /// Reusable structures, referenced by the fields through their name
+    pub definitions: BTreeMap<String, Vec<FieldDescriptor>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Groups of fields, drawn as labelled brackets
+    pub groups: Vec<GroupDescriptor>,
+}
+
+/// Struct to hold a layer of an encapsulation diagram
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LayerDescriptor {
+    #[serde(flatten)]
+    /// Protocol drawn in the layer
+    pub protocol: ProtoDescriptor,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Field carrying the next layer, connected to it in the image
+    pub payload: Option<FieldRef>,
+}
+
+/// Struct to hold the layers of an encapsulation diagram, from the outermost to the innermost protocol
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct StackDescriptor {
+    /// List of layers, drawn from top to bottom
+    pub layers: Vec<LayerDescriptor>,
+}
+
+/// Group of consecutive fields, drawn with a labelled bracket
+#[derive(Debug, Clone)]
+pub(crate) struct FieldGroup {
+    pub name: String,
+    pub first: usize,
+    pub last: usize,
+    pub position: GroupPosition,
+}
+
+/// Protocol with its structures expanded
+#[derive(Debug)]
+pub(crate) struct FlatDescriptor {
+    pub fields: Vec<FieldDescriptor>,
+    pub groups: Vec<FieldGroup>,
+    pub spans: Vec<(usize, usize)>, // Range of flat fields each descriptor field expands to
+}
+
+impl ProtoDescriptor {
+    /// Expand the structure fields, returning the flat list of fields and the groups to be drawn
+    pub(crate) fn flatten(&self) -> Result<FlatDescriptor, Error> {
+        let mut fields = Vec::new();
+        let mut groups = Vec::new();
+        let mut spans = Vec::new();
+
+        for field in &self.fields {
+            let first = fields.len();
+            self.flatten_field(field, &mut fields, &mut groups, &mut Vec::new())?;
+            spans.push((first, fields.len() - 1));
+        }
+
+        for group in &self.groups {
+            let start = self.field_index(&group.start)?;
+            let end = self.field_index(&group.end)?;
+
+            if start > end {
+                return Err(Error::FormatError(format!(
+                    "Group {} ends before it starts",
+                    group.name
+                )));
+            }
+
+            groups.push(FieldGroup {
+                name: group.name.clone(),
+                first: spans[start].0,
+                last: spans[end].1,
+                position: group.position,
+            });
+        }
+
+        Ok(FlatDescriptor {
+            fields,
+            groups,
+            spans,
+        })
+    }
+
+    /// Index of the field being referenced
+    pub(crate) fn field_index(&self, field: &FieldRef) -> Result<usize, Error> {
+        match field {
+            FieldRef::Index(index) if *index < self.fields.len() => Ok(*index),
+            FieldRef::Index(index) => Err(Error::FormatError(format!(
+                "Field index {} is out of range",
+                index
+            ))),
+            FieldRef::Name(name) => self
+                .fields
+                .iter()
+                .position(|field| field.name == *name)
+                .ok_or_else(|| Error::FormatError(format!("Unknown field {}", name))),
+        }
+    }
+
+    fn flatten_field(
+        &self,
+        field: &FieldDescriptor,
+        fields: &mut Vec<FieldDescriptor>,
+        groups: &mut Vec<FieldGroup>,
+        stack: &mut Vec<String>,
+    ) -> Result<(), Error> {
+        let name = match &field.structure {
+            Some(_) if !field.alternatives.is_empty() => {
+                return Err(Error::FormatError(format!(
+                    "Field {} cannot have both a structure and alternatives",
+                    field.name
+                )));
+            }
+            Some(name) => name,
+            None => {
+                fields.push(self.flatten_alternatives(field, stack)?);
+                return Ok(());
+            }
+        };
+
+        if stack.contains(name) {
+            return Err(Error::FormatError(format!(
+                "Structure {} cannot contain itself",
+                name
+            )));
+        }
+
+        let definition = self
+            .definitions
+            .get(name)
+            .ok_or_else(|| Error::FormatError(format!("Unknown structure {}", name)))?;
+
+        if definition.is_empty() {
+            return Err(Error::FormatError(format!(
+                "Structure {} has no fields",
+                name
+            )));
+        }
+
+        stack.push(name.clone());
+
+        if field.expand {
+            if field.repeat.is_some() {
+                return Err(Error::FormatError(
+                    "Repeated structures cannot be expanded".to_string(),
+                ));
+            }
+
+            let first = fields.len();
+            for sub_field in definition {
+                // The structure options apply to the fields that don't override them
+                let mut sub_field = sub_field.clone();
+                sub_field.color = sub_field.color.or(field.color);
+                sub_field.pattern = sub_field.pattern.or(field.pattern);
+                sub_field.optional |= field.is_optional();
+
+                self.flatten_field(&sub_field, fields, groups, stack)?;
+            }
+
+            fields.last_mut().unwrap().wrap |= field.wrap;
+
+            groups.push(FieldGroup {
+                name: field.name.clone(),
+                first,
+                last: fields.len() - 1,
+                position: GroupPosition::Above,
+            });
+        } else {
+            let mut collapsed = field.clone();
+            collapsed.structure = None;
+
+            if collapsed.length == FieldLength::Fixed(0) {
+                let mut sub_fields = Vec::new();
+                for sub_field in definition {
+                    self.flatten_field(sub_field, &mut sub_fields, &mut Vec::new(), stack)?;
+                }
+
+                collapsed.length = sub_fields
+                    .iter()
+                    .try_fold(FieldLength::Fixed(0), |length, sub_field| {
+                        length.add(&sub_field.total_length()?)
+                    })?;
+            }
+
+            fields.push(collapsed);
+        }
+
+        stack.pop();
+
+        Ok(())
+    }
+
+    /// Expand the structures of the field alternatives, deriving the field length from them if needed
+    fn flatten_alternatives(
+        &self,
+        field: &FieldDescriptor,
+        stack: &mut Vec<String>,
+    ) -> Result<FieldDescriptor, Error> {
+        let mut field = field.clone();
+
+        for alternative in &mut field.alternatives {
+            if alternative.fields.is_empty() {
+                return Err(Error::FormatError(format!(
+                    "Alternative {} of field {} has no fields",
+                    alternative.name, field.name
+                )));
+            }
+
+            let mut sub_fields = Vec::new();
+            for sub_field in &alternative.fields {
+                self.flatten_field(sub_field, &mut sub_fields, &mut Vec::new(), stack)?;
+            }
+
+            if sub_fields
+                .iter()
+                .any(|sub_field| !sub_field.alternatives.is_empty())
+            {
+                return Err(Error::FormatError(
+                    "Alternatives cannot be nested".to_string(),
+                ));
+            }
+
+            alternative.fields = sub_fields;
+        }
+
+        if field.length == FieldLength::Fixed(0) && !field.alternatives.is_empty() {
+            let length = field.alternatives[0].length()?;
+
+            let mut lengths = Vec::new();
+            for alternative in &field.alternatives {
+                lengths.push(alternative.length()?);
+            }
+            if lengths.iter().any(|l| *l != length) {
+                return Err(Error::FormatError(format!(
+                    "Alternatives of field {} have different lengths, so its length must be set",
+                    field.name
+                )));
+            }
+
+            field.length = length;
+        }
+
+        Ok(field)
+    }
 }
@@ -0,0 +1,281 @@
+//! Comparison of two versions of a protocol, as a list of field changes and a highlighted diagram
+
+use std::fmt::Write;
+
+use hex_color::HexColor;
+use serde::Serialize;
+
+use crate::descriptor::{FieldDescriptor, FieldLength, ProtoDescriptor};
+use crate::errors::Error;
+
+const ADDED_COLOR: HexColor = HexColor::rgb(0xb7, 0xe1, 0xa1);
+const REMOVED_COLOR: HexColor = HexColor::rgb(0xf4, 0xa6, 0xa6);
+const RESIZED_COLOR: HexColor = HexColor::rgb(0xff, 0xd2, 0x7f);
+const MOVED_COLOR: HexColor = HexColor::rgb(0xa8, 0xc8, 0xf0);
+
+/// Enum to hold how a field changed between the two versions
+#[derive(Debug, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Unchanged,
+    Added,
+    Removed,
+    Resized,
+    Moved, // Placed after a different field, and possibly resized
+}
+
+/// Struct to hold the change of a field, with its offset and length in each version
+#[derive(Debug, Serialize, Clone)]
+pub struct FieldDiff {
+    pub name: String,
+    pub change: Change,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_offset: Option<FieldLength>, // Offset of the field in the old version, if it was there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_offset: Option<FieldLength>, // Offset of the field in the new version, if it is there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_length: Option<FieldLength>, // Length of the field in the old version, including its repetitions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_length: Option<FieldLength>, // Length of the field in the new version, including its repetitions
+}
+
+impl FieldDiff {
+    /// Number of units the field moved by, if both offsets are known and fixed
+    pub fn shift(&self) -> Option<i64> {
+        match (&self.old_offset, &self.new_offset) {
+            (Some(FieldLength::Fixed(old)), Some(FieldLength::Fixed(new))) => {
+                Some(*new as i64 - *old as i64)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Compare two versions of a protocol, with their structures expanded
+///
+/// Fields are matched by name, keeping the longest common sequence in place. The other fields of the new version are
+/// moved if the old version had a field with the same name, and added otherwise. The changes follow the order of the
+/// new version, with the removed fields where they used to be.
+pub fn diff(old: &ProtoDescriptor, new: &ProtoDescriptor) -> Result<Vec<FieldDiff>, Error> {
+    Ok(merge(&old.flatten()?.fields, &new.flatten()?.fields)?
+        .into_iter()
+        .map(|field| field.diff)
+        .collect())
+}
+
+/// Descriptor of the new version with the changes highlighted, to render them in a single diagram
+///
+/// Added fields are green, removed ones red and struck through, resized ones orange with their old length, and moved
+/// ones blue. Removed fields are drawn as optional, so the positions show the offsets with and without them.
+pub fn diff_descriptor(
+    old: &ProtoDescriptor,
+    new: &ProtoDescriptor,
+) -> Result<ProtoDescriptor, Error> {
+    let mut definitions = new.definitions.clone();
+    for (name, fields) in &old.definitions {
+        definitions
+            .entry(name.clone())
+            .or_insert_with(|| fields.clone());
+    }
+
+    let fields = merge(&old.flatten()?.fields, &new.flatten()?.fields)?
+        .into_iter()
+        .map(|FieldMatch { diff, old, new }| {
+            let mut field = new.or(old).unwrap().clone();
+            match diff.change {
+                Change::Unchanged => {}
+                Change::Added => field.color = Some(ADDED_COLOR),
+                Change::Removed => {
+                    // Combining long stroke overlays, as the diagrams have no text decoration
+                    field.name = field.name.chars().flat_map(|c| [c, '\u{336}']).collect();
+                    field.color = Some(REMOVED_COLOR);
+                    field.optional = true;
+                }
+                Change::Resized => {
+                    field.name =
+                        format!("{} (was {})", field.name, diff.old_length.as_ref().unwrap());
+                    field.color = Some(RESIZED_COLOR);
+                }
+                Change::Moved if diff.old_length != diff.new_length => {
+                    field.name = format!(
+                        "{} (moved, was {})",
+                        field.name,
+                        diff.old_length.as_ref().unwrap()
+                    );
+                    field.color = Some(MOVED_COLOR);
+                }
+                Change::Moved => {
+                    field.name = format!("{} (moved)", field.name);
+                    field.color = Some(MOVED_COLOR);
+                }
+            }
+            field
+        })
+        .collect();
+
+    Ok(ProtoDescriptor {
+        elements: new.elements.clone(),
+        style: new.style.clone(),
+        fields,
+        definitions,
+        groups: Vec::new(),
+    })
+}
+
+/// Textual summary of the changes, with how the offset of every field shifted
+pub fn summary(diffs: &[FieldDiff]) -> String {
+    let mut summary = String::new();
+
+    for diff in diffs {
+        let (symbol, change) = match diff.change {
+            Change::Unchanged => (' ', "unchanged"),
+            Change::Added => ('+', "added"),
+            Change::Removed => ('-', "removed"),
+            Change::Resized => ('~', "resized"),
+            Change::Moved => ('>', "moved"),
+        };
+
+        let mut line = format!("{} {:9} {}", symbol, change, diff.name);
+        match (&diff.old_length, &diff.new_length) {
+            (Some(old), Some(new)) if old != new => write!(line, ", length {} -> {}", old, new),
+            (Some(length), _) | (_, Some(length)) => write!(line, ", length {}", length),
+            _ => Ok(()),
+        }
+        .unwrap();
+
+        match (&diff.old_offset, &diff.new_offset, diff.shift()) {
+            (Some(offset), Some(_), Some(0)) => write!(line, ", offset {}", offset),
+            (Some(old), Some(new), Some(shift)) => {
+                write!(line, ", offset {} -> {} ({:+})", old, new, shift)
+            }
+            (Some(old), Some(new), None) if old == new => write!(line, ", offset {}", old),
+            (Some(old), Some(new), None) => write!(line, ", offset {} -> {}", old, new),
+            (Some(offset), None, _) | (None, Some(offset), _) => {
+                write!(line, ", offset {}", offset)
+            }
+            (None, None, _) => Ok(()),
+        }
+        .unwrap();
+
+        writeln!(summary, "{}", line).unwrap();
+    }
+
+    summary
+}
+
+/// Field matched between the two versions, with its change
+pub(crate) struct FieldMatch<'a> {
+    pub diff: FieldDiff,
+    pub old: Option<&'a FieldDescriptor>,
+    pub new: Option<&'a FieldDescriptor>,
+}
+
+/// Match the fields of both versions, returning their changes
+pub(crate) fn merge<'a>(
+    old: &'a [FieldDescriptor],
+    new: &'a [FieldDescriptor],
+) -> Result<Vec<FieldMatch<'a>>, Error> {
+    let (old_lengths, old_offsets) = layout(old)?;
+    let (new_lengths, new_offsets) = layout(new)?;
+
+    // Longest common sequence of names, computed from the end
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i].name == new[j].name {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i].name == new[j].name {
+            pairs.push((Some(i), Some(j)));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            pairs.push((Some(i), None));
+            i += 1;
+        } else {
+            pairs.push((None, Some(j)));
+            j += 1;
+        }
+    }
+    pairs.extend((i..old.len()).map(|i| (Some(i), None)));
+    pairs.extend((j..new.len()).map(|j| (None, Some(j))));
+
+    // The fields only found on one side are moved if the other side has one with the same name
+    let mut unmatched_old = pairs
+        .iter()
+        .filter_map(|pair| match pair {
+            (Some(i), None) => Some(*i),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let mut moved = Vec::new();
+    for pair in pairs.iter_mut() {
+        if let (None, Some(j)) = *pair {
+            if let Some(k) = unmatched_old
+                .iter()
+                .position(|i| old[*i].name == new[j].name)
+            {
+                let i = unmatched_old.remove(k);
+                moved.push(i);
+                *pair = (Some(i), Some(j));
+            }
+        }
+    }
+
+    Ok(pairs
+        .into_iter()
+        .filter(|pair| !matches!(pair, (Some(i), None) if moved.contains(i)))
+        .map(|pair| {
+            let old_side = pair.0.map(|i| (&old_offsets[i], old_lengths[i].clone()));
+            let new_side = pair.1.map(|j| (&new_offsets[j], new_lengths[j].clone()));
+
+            let change = match pair {
+                (Some(i), Some(_)) if moved.contains(&i) => Change::Moved,
+                (Some(_), Some(_))
+                    if old_side.as_ref().unwrap().1 != new_side.as_ref().unwrap().1 =>
+                {
+                    Change::Resized
+                }
+                (Some(_), Some(_)) => Change::Unchanged,
+                (Some(_), None) => Change::Removed,
+                _ => Change::Added,
+            };
+
+            let (old, new) = (pair.0.map(|i| &old[i]), pair.1.map(|j| &new[j]));
+            let diff = FieldDiff {
+                name: new.or(old).unwrap().name.clone(),
+                change,
+                old_offset: old_side.as_ref().map(|(offset, _)| (*offset).clone()),
+                new_offset: new_side.as_ref().map(|(offset, _)| (*offset).clone()),
+                old_length: old_side.map(|(_, length)| length),
+                new_length: new_side.map(|(_, length)| length),
+            };
+
+            FieldMatch { diff, old, new }
+        })
+        .collect())
+}
+
+/// Length and offset of every field, including the optional ones
+fn layout(fields: &[FieldDescriptor]) -> Result<(Vec<FieldLength>, Vec<FieldLength>), Error> {
+    let mut lengths = Vec::new();
+    let mut offsets = Vec::new();
+    let mut offset = FieldLength::Fixed(0);
+
+    for field in fields {
+        let length = field.total_length()?;
+        offsets.push(offset.clone());
+        offset = offset.add(&length)?;
+        lengths.push(length);
+    }
+
+    Ok((lengths, offsets))
+}
@@ -0,0 +1,1139 @@
+//! Import struct definitions from C header files
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::descriptor::{
+    AlternativeDescriptor, FieldDescriptor, FieldLength, FillPattern, ProtoDescriptor,
+};
+use crate::errors::Error;
+
+const PADDING_NAME: &str = "padding";
+const POINTER_LENGTH: &str = "sizeof(void*)";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(usize),
+    Literal(String), // Numeric literal that isn't an integer (e.g. a float constant)
+    Str,
+    Punct(char),
+    Comment(String),
+    Pack(PackAction),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PackAction {
+    Set(Option<usize>),
+    Push(Option<usize>),
+    Pop,
+}
+
+#[derive(Debug, Clone)]
+struct Spanned {
+    token: Token,
+    line: usize,
+}
+
+/// Type of a struct member
+#[derive(Debug, Clone)]
+enum CType {
+    Primitive(usize),     // Size in bytes
+    PointerSized(String), // Size depending on the platform, named after the type
+    Record(String),       // Struct or union, by its name
+}
+
+/// Number of elements of an array dimension
+#[derive(Debug, Clone)]
+enum Count {
+    Fixed(usize),
+    Symbol(String),
+    Flexible,
+}
+
+#[derive(Debug, Clone)]
+struct CMember {
+    name: Option<String>, // Anonymous members have their fields merged into the parent
+    kind: CType,
+    dims: Vec<Count>,
+    bits: Option<usize>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct CRecord {
+    name: String,
+    union: bool,
+    packed: bool,
+    pack: Option<usize>,
+    members: Vec<CMember>,
+}
+
+/// Size (in bits, if known) and alignment (in bytes) of a type
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    bits: Option<usize>,
+    align: usize,
+}
+
+/// Import a struct (by its tag or typedef name) from a C header, with the structs it contains as definitions
+pub fn from_c_header(source: &str, name: &str) -> Result<ProtoDescriptor, Error> {
+    let mut parser = Parser::new(tokenize(source)?);
+    parser.parse()?;
+
+    let root = parser
+        .resolve(name)
+        .ok_or_else(|| Error::FormatError(format!("Unknown struct {}", name)))?;
+
+    // Lengths are in bits if the imported structs have bitfields, otherwise in bytes
+    let unit = if parser.has_bitfields(root, &mut Vec::new()) {
+        1
+    } else {
+        8
+    };
+
+    let mut builder = Builder {
+        parser: &parser,
+        unit,
+        definitions: BTreeMap::new(),
+        layouts: HashMap::new(),
+        stack: Vec::new(),
+    };
+
+    let (fields, _) = builder.record(root)?;
+    if fields.is_empty() {
+        return Err(Error::FormatError(format!("Struct {} has no fields", name)));
+    }
+
+    Ok(ProtoDescriptor {
+        fields,
+        definitions: builder.definitions,
+        ..Default::default()
+    })
+}
+
+/// Split the source into tokens, handling the preprocessor directives
+fn tokenize(source: &str) -> Result<Vec<Spanned>, Error> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut defines = HashMap::new();
+    let mut line = 1;
+    let mut line_start = true;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == '\n' {
+            line += 1;
+            line_start = true;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' && line_start {
+            // Directives span the whole line, including the escaped line breaks
+            let mut directive = String::new();
+            while i < chars.len() && chars[i] != '\n' {
+                if chars[i] == '\\' && chars.get(i + 1) == Some(&'\n') {
+                    line += 1;
+                    i += 2;
+                    continue;
+                }
+                directive.push(chars[i]);
+                i += 1;
+            }
+
+            if let Some(token) = directive_token(&directive[1..], &mut defines) {
+                tokens.push(Spanned { token, line });
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            let start = i + 2;
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+
+            let text = chars[start..i].iter().collect::<String>();
+            tokens.push(Spanned {
+                token: Token::Comment(comment_text(&text)),
+                line,
+            });
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            let start = i + 2;
+            let start_line = line;
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+
+            let text = chars[start..i.min(chars.len())].iter().collect::<String>();
+            i += 2;
+            tokens.push(Spanned {
+                token: Token::Comment(comment_text(&text)),
+                line: start_line,
+            });
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Spanned {
+                token: Token::Str,
+                line,
+            });
+            line_start = false;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+
+            let ident = chars[start..i].iter().collect::<String>();
+            let token = match defines.get(&ident) {
+                Some(value) => Token::Number(*value),
+                None => Token::Ident(ident),
+            };
+            tokens.push(Spanned { token, line });
+            line_start = false;
+        } else if c.is_ascii_digit() {
+            // Preprocessing number, including the floats and their exponents (e.g. 1.5e-3f)
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || chars[i] == '.'
+                    || (matches!(chars[i], '+' | '-')
+                        && matches!(chars[i - 1], 'e' | 'E' | 'p' | 'P')))
+            {
+                i += 1;
+            }
+
+            let number = chars[start..i].iter().collect::<String>();
+            let token = match parse_number(&number) {
+                Ok(value) => Token::Number(value),
+                Err(_) => Token::Literal(number),
+            };
+            tokens.push(Spanned { token, line });
+            line_start = false;
+        } else {
+            tokens.push(Spanned {
+                token: Token::Punct(c),
+                line,
+            });
+            line_start = false;
+            i += 1;
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Handle a preprocessor directive, keeping the numeric defines and the pack pragmas
+fn directive_token(directive: &str, defines: &mut HashMap<String, usize>) -> Option<Token> {
+    let mut words = directive.split_whitespace();
+
+    match words.next()? {
+        "define" => {
+            let name = words.next()?;
+            let value = words.collect::<Vec<_>>().join(" ");
+            let value = value.trim_start_matches('(').trim_end_matches(')');
+
+            if let Ok(value) = parse_number(value) {
+                defines.insert(name.to_owned(), value);
+            }
+            None
+        }
+        "pragma" => {
+            let rest = words.collect::<String>();
+            let args = rest.strip_prefix("pack(")?.strip_suffix(')')?;
+            let mut args = args.split(',').map(str::trim);
+
+            let action = match args.next()? {
+                "push" => PackAction::Push(args.next().and_then(|v| parse_number(v).ok())),
+                "pop" => PackAction::Pop,
+                "" => PackAction::Set(None),
+                value => PackAction::Set(Some(parse_number(value).ok()?)),
+            };
+            Some(Token::Pack(action))
+        }
+        _ => None,
+    }
+}
+
+/// Parse an integer literal, ignoring its suffixes
+fn parse_number(value: &str) -> Result<usize, Error> {
+    let value = value.trim().trim_end_matches(['u', 'U', 'l', 'L']);
+
+    let result = if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        usize::from_str_radix(hex, 16)
+    } else if value.len() > 1 && value.starts_with('0') {
+        usize::from_str_radix(&value[1..], 8)
+    } else {
+        value.parse()
+    };
+
+    result.map_err(|_| invalid_number(value))
+}
+
+fn too_large() -> Error {
+    Error::FormatError("Struct size overflows".to_string())
+}
+
+fn invalid_number(value: &str) -> Error {
+    Error::FormatError(format!("Invalid number {}", value))
+}
+
+/// Text of a comment, without the decorations of doc comments
+fn comment_text(text: &str) -> String {
+    text.lines()
+        .map(|line| line.trim().trim_start_matches(['*', '/', '!', '<']).trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    pos: usize,
+    pack: Option<usize>,
+    pack_stack: Vec<Option<usize>>,
+    records: Vec<CRecord>,
+    names: HashMap<String, usize>, // Index of the records by tag and typedef name
+    aliases: HashMap<String, CType>, // Typedefs of other types
+    anonymous: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Spanned>) -> Self {
+        Self {
+            tokens,
+            pos: 0,
+            pack: None,
+            pack_stack: Vec::new(),
+            records: Vec::new(),
+            names: HashMap::new(),
+            aliases: HashMap::new(),
+            anonymous: 0,
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Option<&CRecord> {
+        match (self.names.get(name), self.aliases.get(name)) {
+            (Some(&i), _) => Some(&self.records[i]),
+            (None, Some(CType::Record(tag))) => self.names.get(tag).map(|&i| &self.records[i]),
+            _ => None,
+        }
+    }
+
+    /// Whether a record, or one of the records it contains, has bitfields
+    fn has_bitfields<'a>(&'a self, record: &'a CRecord, seen: &mut Vec<&'a str>) -> bool {
+        if seen.contains(&record.name.as_str()) {
+            return false;
+        }
+        seen.push(&record.name);
+
+        record.members.iter().any(|member| {
+            member.bits.is_some()
+                || match &member.kind {
+                    CType::Record(name) => self
+                        .resolve(name)
+                        .is_some_and(|inner| self.has_bitfields(inner, seen)),
+                    _ => false,
+                }
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|t| t.token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos.saturating_sub(1))
+            .map_or(0, |t| t.line)
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), Error> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(Error::FormatError(format!(
+                "Expected '{}' on line {}",
+                punct,
+                self.line()
+            )))
+        }
+    }
+
+    fn is_ident(&self, ident: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(i)) if i == ident)
+    }
+
+    /// Parse the top level declarations, keeping the structs, unions and typedefs
+    fn parse(&mut self) -> Result<(), Error> {
+        while let Some(token) = self.peek().cloned() {
+            match token {
+                Token::Pack(action) => {
+                    self.pos += 1;
+                    self.apply_pack(action);
+                }
+                Token::Comment(_) | Token::Punct('}') | Token::Punct(';') => self.pos += 1,
+                Token::Ident(ident) if ident == "extern" => {
+                    // Skip linkage specifications, keeping their contents
+                    self.pos += 1;
+                    if self.peek() == Some(&Token::Str) {
+                        self.pos += 1;
+                        self.eat('{');
+                    } else {
+                        self.skip_declaration();
+                    }
+                }
+                Token::Ident(ident) if ident == "typedef" => {
+                    self.pos += 1;
+                    self.typedef()?;
+                }
+                Token::Ident(ident) if ident == "struct" || ident == "union" => {
+                    // Declarations of functions and variables using a struct are skipped
+                    let start = self.pos;
+                    let kind = self.type_specifier()?;
+                    if matches!(kind, Some((CType::Record(_), true))) && self.eat(';') {
+                        continue;
+                    }
+                    self.pos = start;
+                    self.skip_declaration();
+                }
+                _ => self.skip_declaration(),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_pack(&mut self, action: PackAction) {
+        match action {
+            PackAction::Set(pack) => self.pack = pack,
+            PackAction::Push(pack) => {
+                self.pack_stack.push(self.pack);
+                if pack.is_some() {
+                    self.pack = pack;
+                }
+            }
+            PackAction::Pop => self.pack = self.pack_stack.pop().flatten(),
+        }
+    }
+
+    /// Skip a declaration, up to its semicolon or the end of a function body
+    fn skip_declaration(&mut self) {
+        let mut depth = 0;
+        let mut previous = None;
+
+        while let Some(token) = self.next() {
+            match token {
+                Token::Punct('{') => depth += 1,
+                Token::Punct('}') => {
+                    depth -= 1;
+                    if depth <= 0 && previous == Some(Token::Punct(')')) {
+                        return;
+                    }
+                }
+                Token::Punct(';') if depth <= 0 => return,
+                Token::Punct(')') if depth == 0 => {
+                    previous = Some(Token::Punct(')'));
+                    continue;
+                }
+                _ => {}
+            }
+
+            if depth == 0 {
+                previous = Some(token);
+            }
+        }
+    }
+
+    fn typedef(&mut self) -> Result<(), Error> {
+        let (kind, _) = self.type_specifier()?.ok_or_else(|| {
+            Error::FormatError(format!("Invalid typedef on line {}", self.line()))
+        })?;
+
+        // Typedefs of pointers and arrays are not structs, so they are only kept as aliases
+        loop {
+            let pointer = self.eat('*');
+            let name = match self.next() {
+                Some(Token::Ident(name)) => name,
+                _ => {
+                    self.skip_declaration();
+                    return Ok(());
+                }
+            };
+
+            if self.peek() == Some(&Token::Punct('(')) {
+                // Function pointer types are pointer sized
+                self.skip_declaration();
+                self.aliases
+                    .insert(name, CType::PointerSized(POINTER_LENGTH.to_owned()));
+                return Ok(());
+            }
+
+            self.skip_attributes();
+
+            let kind = if pointer {
+                CType::PointerSized(POINTER_LENGTH.to_owned())
+            } else {
+                kind.clone()
+            };
+
+            match &kind {
+                CType::Record(record) if !self.names.contains_key(&name) => {
+                    match self.names.get(record) {
+                        Some(&index) => {
+                            self.names.insert(name.clone(), index);
+
+                            // Anonymous structs are named after their typedef
+                            if self.records[index].name.starts_with('(') {
+                                self.records[index].name = name;
+                            }
+                        }
+                        // Forward typedef of a struct defined later, resolved through its tag
+                        None => {
+                            self.aliases.insert(name, kind.clone());
+                        }
+                    }
+                }
+                _ => {
+                    self.aliases.insert(name, kind);
+                }
+            }
+
+            if !self.eat(',') {
+                break;
+            }
+        }
+
+        self.expect(';')
+    }
+
+    /// Parse the type of a declaration, returning it and whether it defined a struct
+    fn type_specifier(&mut self) -> Result<Option<(CType, bool)>, Error> {
+        let mut words: Vec<String> = Vec::new();
+        let mut packed = false;
+
+        while let Some(Token::Ident(ident)) = self.peek().cloned() {
+            match ident.as_str() {
+                "const" | "volatile" | "static" | "register" | "__extension__" | "restrict"
+                | "__restrict" | "inline" | "extern" => self.pos += 1,
+                "__packed" => {
+                    self.pos += 1;
+                    packed = true;
+                }
+                "__attribute__" | "__declspec" | "alignas" | "_Alignas" => {
+                    packed |= self.skip_attributes();
+                }
+                "struct" | "union" if words.is_empty() => {
+                    self.pos += 1;
+                    return self.record_specifier(ident == "union", packed).map(Some);
+                }
+                "enum" if words.is_empty() => {
+                    self.pos += 1;
+                    if let Some(Token::Ident(_)) = self.peek() {
+                        self.pos += 1;
+                    }
+                    if self.eat('{') {
+                        self.skip_block();
+                    }
+                    return Ok(Some((CType::Primitive(4), false)));
+                }
+                _ => {
+                    // A word following a complete type is the declarator name
+                    if words
+                        .first()
+                        .is_some_and(|first| !is_type_word(first) || !is_type_word(&ident))
+                    {
+                        break;
+                    }
+                    words.push(ident);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        if words.is_empty() {
+            return Ok(None);
+        }
+
+        self.primitive(&words).map(|kind| Some((kind, false)))
+    }
+
+    /// Parse a struct or union specifier, with or without a body
+    fn record_specifier(&mut self, union: bool, mut packed: bool) -> Result<(CType, bool), Error> {
+        packed |= self.skip_attributes();
+
+        let tag = match self.peek() {
+            Some(Token::Ident(tag)) => {
+                let tag = tag.clone();
+                self.pos += 1;
+                Some(tag)
+            }
+            _ => None,
+        };
+
+        packed |= self.skip_attributes();
+
+        if !self.eat('{') {
+            let tag = tag.ok_or_else(|| {
+                Error::FormatError(format!("Expected a struct name on line {}", self.line()))
+            })?;
+            return Ok((CType::Record(tag), false));
+        }
+
+        let pack = self.pack;
+        let members = self.members()?;
+        packed |= self.skip_attributes();
+
+        let name = tag.clone().unwrap_or_else(|| {
+            self.anonymous += 1;
+            format!("(anonymous {})", self.anonymous)
+        });
+
+        self.records.push(CRecord {
+            name: name.clone(),
+            union,
+            packed,
+            pack,
+            members,
+        });
+        self.names.insert(name.clone(), self.records.len() - 1);
+
+        Ok((CType::Record(name), true))
+    }
+
+    /// Parse the members of a struct body, up to its closing brace
+    fn members(&mut self) -> Result<Vec<CMember>, Error> {
+        let mut members = Vec::new();
+        let mut doc: Option<String> = None;
+
+        loop {
+            match self.peek().cloned() {
+                None => {
+                    return Err(Error::FormatError(
+                        "Unexpected end of file in a struct".to_string(),
+                    ))
+                }
+                Some(Token::Punct('}')) => {
+                    self.pos += 1;
+                    return Ok(members);
+                }
+                Some(Token::Comment(text)) => {
+                    self.pos += 1;
+                    doc = Some(match doc {
+                        Some(doc) => format!("{} {}", doc, text),
+                        None => text,
+                    });
+                }
+                Some(Token::Pack(action)) => {
+                    self.pos += 1;
+                    self.apply_pack(action);
+                }
+                Some(Token::Punct(';')) => self.pos += 1,
+                _ => {
+                    let first = members.len();
+                    self.member(&mut members)?;
+
+                    // A comment on the same line as the member describes it
+                    let line = self.line();
+                    if let Some(Spanned {
+                        token: Token::Comment(text),
+                        line: comment_line,
+                    }) = self.tokens.get(self.pos).cloned()
+                    {
+                        if comment_line == line {
+                            self.pos += 1;
+                            doc = Some(text);
+                        }
+                    }
+
+                    for member in &mut members[first..] {
+                        member.description = member.description.take().or(doc.clone());
+                    }
+                    doc = None;
+                }
+            }
+        }
+    }
+
+    /// Parse a member declaration, which may declare several members
+    fn member(&mut self, members: &mut Vec<CMember>) -> Result<(), Error> {
+        let (kind, _) = self.type_specifier()?.ok_or_else(|| {
+            Error::FormatError(format!("Invalid struct member on line {}", self.line()))
+        })?;
+
+        // Anonymous structs and unions
+        if self.eat(';') {
+            members.push(CMember {
+                name: None,
+                kind,
+                dims: Vec::new(),
+                bits: None,
+                description: None,
+            });
+            return Ok(());
+        }
+
+        loop {
+            let mut pointer = false;
+            while self.eat('*') {
+                pointer = true;
+                while self.is_ident("const") || self.is_ident("volatile") {
+                    self.pos += 1;
+                }
+            }
+
+            let name = match self.next() {
+                Some(Token::Ident(name)) => Some(name),
+                // Unnamed bitfields are padding
+                Some(Token::Punct(':')) => {
+                    self.pos -= 1;
+                    None
+                }
+                _ => {
+                    return Err(Error::FormatError(format!(
+                        "Expected a member name on line {}",
+                        self.line()
+                    )))
+                }
+            };
+
+            let mut dims = Vec::new();
+            while self.eat('[') {
+                dims.push(match self.next() {
+                    Some(Token::Punct(']')) => {
+                        self.pos -= 1;
+                        Count::Flexible
+                    }
+                    Some(Token::Number(count)) => Count::Fixed(count),
+                    Some(Token::Ident(symbol)) => Count::Symbol(symbol),
+                    Some(Token::Literal(literal)) => return Err(invalid_number(&literal)),
+                    _ => {
+                        return Err(Error::FormatError(format!(
+                            "Unsupported array size on line {}",
+                            self.line()
+                        )))
+                    }
+                });
+
+                // Array sizes with expressions are kept as a symbol
+                if !self.eat(']') {
+                    let mut expression = match dims.pop() {
+                        Some(Count::Fixed(count)) => count.to_string(),
+                        Some(Count::Symbol(symbol)) => symbol,
+                        _ => String::new(),
+                    };
+                    while let Some(token) = self.next() {
+                        match token {
+                            Token::Punct(']') => break,
+                            Token::Punct(c) => expression.push(c),
+                            Token::Number(n) => expression.push_str(&n.to_string()),
+                            Token::Literal(literal) => return Err(invalid_number(&literal)),
+                            Token::Ident(i) => expression.push_str(&i),
+                            _ => {}
+                        }
+                    }
+                    dims.push(Count::Symbol(expression));
+                }
+            }
+
+            let bits = if self.eat(':') {
+                match self.next() {
+                    Some(Token::Number(bits)) => Some(bits),
+                    Some(Token::Literal(literal)) => return Err(invalid_number(&literal)),
+                    _ => {
+                        return Err(Error::FormatError(format!(
+                            "Unsupported bitfield width on line {}",
+                            self.line()
+                        )))
+                    }
+                }
+            } else {
+                None
+            };
+
+            self.skip_attributes();
+
+            members.push(CMember {
+                name,
+                kind: if pointer {
+                    CType::PointerSized(POINTER_LENGTH.to_owned())
+                } else {
+                    kind.clone()
+                },
+                dims,
+                bits,
+                description: None,
+            });
+
+            if !self.eat(',') {
+                break;
+            }
+        }
+
+        self.expect(';')
+    }
+
+    /// Skip the attributes, returning whether they mark the struct as packed
+    fn skip_attributes(&mut self) -> bool {
+        let mut packed = false;
+
+        while let Some(Token::Ident(ident)) = self.peek() {
+            match ident.as_str() {
+                "__packed" => {
+                    packed = true;
+                    self.pos += 1;
+                }
+                "__attribute__" | "__declspec" | "alignas" | "_Alignas" => {
+                    self.pos += 1;
+                    if !self.eat('(') {
+                        continue;
+                    }
+
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match self.next() {
+                            Some(Token::Punct('(')) => depth += 1,
+                            Some(Token::Punct(')')) => depth -= 1,
+                            Some(Token::Ident(i)) if i == "packed" || i == "__packed__" => {
+                                packed = true
+                            }
+                            None => break,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        packed
+    }
+
+    fn skip_block(&mut self) {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next() {
+                Some(Token::Punct('{')) => depth += 1,
+                Some(Token::Punct('}')) => depth -= 1,
+                None => break,
+                _ => {}
+            }
+        }
+    }
+
+    /// Type made of the given words (e.g. "unsigned long long" or a typedef name)
+    fn primitive(&self, words: &[String]) -> Result<CType, Error> {
+        let words = words.iter().map(String::as_str).collect::<Vec<_>>();
+        let longs = words.iter().filter(|w| **w == "long").count();
+
+        let size = match words.as_slice() {
+            [name] if self.aliases.contains_key(*name) => return Ok(self.aliases[*name].clone()),
+            [name] if self.names.contains_key(*name) => {
+                return Ok(CType::Record(self.records[self.names[*name]].name.clone()))
+            }
+            [name] => match *name {
+                "char" | "int8_t" | "uint8_t" | "bool" | "_Bool" => 1,
+                "short" | "int16_t" | "uint16_t" => 2,
+                "int" | "signed" | "unsigned" | "int32_t" | "uint32_t" | "float" => 4,
+                "double" | "int64_t" | "uint64_t" => 8,
+                "long" | "size_t" | "ssize_t" | "uintptr_t" | "intptr_t" | "ptrdiff_t" => {
+                    return Ok(CType::PointerSized(format!("sizeof({})", name)))
+                }
+                name => return Err(Error::FormatError(format!("Unknown type {}", name))),
+            },
+            _ if words.contains(&"double") => {
+                return Ok(CType::PointerSized("sizeof(long double)".to_owned()))
+            }
+            _ if words.contains(&"char") => 1,
+            _ if words.contains(&"short") => 2,
+            _ if longs >= 2 => 8,
+            _ if longs == 1 => return Ok(CType::PointerSized("sizeof(long)".to_owned())),
+            _ => 4,
+        };
+
+        Ok(CType::Primitive(size))
+    }
+}
+
+/// Whether a word can be part of a multi-word primitive type
+fn is_type_word(word: &str) -> bool {
+    matches!(
+        word,
+        "signed" | "unsigned" | "short" | "long" | "int" | "char" | "double"
+    )
+}
+
+/// Build the descriptor fields of the parsed structs
+struct Builder<'a> {
+    parser: &'a Parser,
+    unit: usize, // Bits per length unit
+    definitions: BTreeMap<String, Vec<FieldDescriptor>>,
+    layouts: HashMap<String, Layout>,
+    stack: Vec<String>,
+}
+
+impl Builder<'_> {
+    /// Layout of a type
+    fn layout(&mut self, kind: &CType) -> Result<Layout, Error> {
+        match kind {
+            CType::Primitive(size) => Ok(Layout {
+                bits: Some(size * 8),
+                align: *size,
+            }),
+            CType::PointerSized(_) => Ok(Layout {
+                bits: None,
+                align: 1,
+            }),
+            CType::Record(name) => {
+                if !self.layouts.contains_key(name) {
+                    let record = self
+                        .parser
+                        .resolve(name)
+                        .ok_or_else(|| Error::FormatError(format!("Unknown struct {}", name)))?;
+                    let (fields, layout) = self.record(record)?;
+
+                    if !record.union {
+                        self.definitions.insert(name.clone(), fields);
+                    }
+                    self.layouts.insert(name.clone(), layout);
+                }
+
+                Ok(self.layouts[name])
+            }
+        }
+    }
+
+    /// Create the fields of a struct, adding the padding required by the alignment of its members
+    fn record(&mut self, record: &CRecord) -> Result<(Vec<FieldDescriptor>, Layout), Error> {
+        if self.stack.contains(&record.name) {
+            return Err(Error::FormatError(format!(
+                "Struct {} cannot contain itself",
+                record.name
+            )));
+        }
+        self.stack.push(record.name.clone());
+
+        let max_align = if record.packed {
+            1
+        } else {
+            record.pack.unwrap_or(usize::MAX)
+        };
+
+        let mut fields = Vec::new();
+        let mut alternatives = Vec::new();
+        let mut offset = Some(0);
+        let mut size = Some(0);
+        let mut align = 1;
+
+        for member in &record.members {
+            let layout = self.layout(&member.kind)?;
+            let member_align = layout.align.min(max_align);
+            align = align.max(member_align);
+
+            if record.union {
+                let field = self.member_field(member, layout)?;
+                size = match (size, field.total_length()?) {
+                    (Some(size), FieldLength::Fixed(length)) => Some(size.max(self.bits(length)?)),
+                    _ => None,
+                };
+                alternatives.push(AlternativeDescriptor {
+                    name: field.name.clone(),
+                    fields: vec![field],
+                });
+                continue;
+            }
+
+            // Move the member to its aligned offset, or to the next storage unit for bitfields
+            if let (Some(current), Some(bits)) = (offset, member.bits) {
+                let storage = match layout.bits {
+                    Some(0) => {
+                        return Err(Error::FormatError(format!(
+                            "Bitfield {} of struct {} has an empty type",
+                            member.name.as_deref().unwrap_or_default(),
+                            record.name
+                        )))
+                    }
+                    bits => bits.unwrap_or(8),
+                };
+                let aligned = if record.packed || record.pack == Some(1) {
+                    current
+                } else if bits == 0 || current / storage != (current + bits - 1) / storage {
+                    current
+                        .checked_next_multiple_of(storage)
+                        .ok_or_else(too_large)?
+                } else {
+                    current
+                };
+                self.push_padding(&mut fields, aligned - current);
+                offset = Some(aligned);
+            } else if let Some(current) = offset {
+                let aligned = current
+                    .checked_next_multiple_of(member_align * 8)
+                    .ok_or_else(too_large)?;
+                self.push_padding(&mut fields, aligned - current);
+                offset = Some(aligned);
+            }
+
+            // Anonymous structs have their fields merged
+            if member.name.is_none() && member.bits.is_none() {
+                if let CType::Record(name) = &member.kind {
+                    let inner = self.parser.resolve(name).unwrap();
+                    if !inner.union {
+                        fields.extend(self.definitions.remove(name).unwrap_or_default());
+                        offset = match offset.zip(layout.bits) {
+                            Some((offset, bits)) => {
+                                Some(offset.checked_add(bits).ok_or_else(too_large)?)
+                            }
+                            None => None,
+                        };
+                        continue;
+                    }
+                }
+            }
+
+            let field = self.member_field(member, layout)?;
+            let length = field.total_length()?;
+            if member.bits != Some(0) {
+                fields.push(field);
+            }
+
+            offset = match (offset, length) {
+                (Some(offset), FieldLength::Fixed(length)) => Some(
+                    offset
+                        .checked_add(self.bits(length)?)
+                        .ok_or_else(too_large)?,
+                ),
+                _ => None,
+            };
+        }
+
+        if record.union {
+            let length = match size {
+                Some(size) => FieldLength::Fixed(
+                    size.checked_next_multiple_of(align * 8)
+                        .ok_or_else(too_large)?
+                        / self.unit,
+                ),
+                None => FieldLength::Variable(format!("sizeof({})", record.name)),
+            };
+
+            let mut field = FieldDescriptor::new(record.name.clone(), length);
+            field.alternatives = alternatives;
+            fields.push(field);
+        } else {
+            // Pad the struct to a multiple of its alignment
+            size = match offset {
+                Some(offset) => Some(
+                    offset
+                        .checked_next_multiple_of(align * 8)
+                        .ok_or_else(too_large)?,
+                ),
+                None => None,
+            };
+            if let (Some(offset), Some(size)) = (offset, size) {
+                self.push_padding(&mut fields, size - offset);
+            }
+        }
+
+        self.stack.pop();
+
+        Ok((fields, Layout { bits: size, align }))
+    }
+
+    /// Create the field of a struct member
+    fn member_field(&mut self, member: &CMember, layout: Layout) -> Result<FieldDescriptor, Error> {
+        let name = member
+            .name
+            .clone()
+            .unwrap_or_else(|| PADDING_NAME.to_owned());
+
+        let mut field = FieldDescriptor::new(name.clone(), FieldLength::Fixed(0));
+        field.description = member.description.clone();
+
+        let length = match (&member.kind, layout.bits) {
+            _ if member.bits.is_some() => FieldLength::Fixed(member.bits.unwrap() / self.unit),
+            (CType::PointerSized(size), _) => FieldLength::Variable(size.clone()),
+            (CType::Record(record), _) => {
+                let inner = self.parser.resolve(record).unwrap();
+                if inner.union {
+                    // Unions are drawn as a field with an alternative per member
+                    let (mut union_fields, _) = self.record(inner)?;
+                    let union_field = union_fields.pop().unwrap();
+                    field.alternatives = union_field.alternatives;
+                    union_field.length
+                } else {
+                    field.structure = Some(record.clone());
+                    FieldLength::Fixed(0)
+                }
+            }
+            (CType::Primitive(_), Some(bits)) => FieldLength::Fixed(bits / self.unit),
+            (CType::Primitive(_), None) => unreachable!(),
+        };
+
+        if member.bits.is_none() {
+            field.pattern = None;
+        } else if member.name.is_none() {
+            field.pattern = Some(FillPattern::Hatched);
+        }
+
+        // Arrays of primitives are drawn as a single field, and arrays of structs are repeated
+        let mut count = FieldLength::Fixed(1);
+        for dim in &member.dims {
+            count = count.repeat(&match dim {
+                Count::Fixed(count) => FieldLength::Fixed(*count),
+                Count::Symbol(symbol) => FieldLength::Variable(symbol.clone()),
+                Count::Flexible => FieldLength::Variable(format!("len_{}", name)),
+            })?;
+        }
+
+        match (&length, &count) {
+            (_, FieldLength::Fixed(1)) => field.length = length,
+            (FieldLength::Fixed(0), _) => field.repeat = Some(count),
+            _ => field.length = length.repeat(&count)?,
+        }
+
+        Ok(field)
+    }
+
+    /// Number of bits of a length in the unit of the descriptor
+    fn bits(&self, length: usize) -> Result<usize, Error> {
+        length.checked_mul(self.unit).ok_or_else(too_large)
+    }
+
+    /// Add a padding field with the given number of bits
+    fn push_padding(&self, fields: &mut Vec<FieldDescriptor>, bits: usize) {
+        if bits > 0 {
+            let mut field =
+                FieldDescriptor::new(PADDING_NAME, FieldLength::Fixed(bits / self.unit));
+            field.pattern = Some(FillPattern::Hatched);
+            fields.push(field);
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Import the sequences of a Kaitai Struct format description (.ksy)
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::descriptor::{
+    AlternativeDescriptor, ElementsDescriptor, Endianness, FieldDescriptor, FieldLength,
+    ProtoDescriptor,
+};
+use crate::errors::Error;
+
+// Symbolic lengths of the fields that extend until the end of the stream or a terminator
+const EOS_LENGTH: &str = "eos";
+const UNTIL_COUNT: &str = "until";
+
+#[derive(Debug, Deserialize)]
+struct KsyFile {
+    #[serde(default)]
+    meta: KsyMeta,
+    #[serde(default)]
+    seq: Vec<KsyAttribute>,
+    #[serde(default)]
+    types: BTreeMap<String, KsyType>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct KsyMeta {
+    endian: Option<Value>, // Either "le"/"be" or a switch, which is taken as big-endian
+}
+
+#[derive(Debug, Deserialize)]
+struct KsyType {
+    #[serde(default)]
+    seq: Vec<KsyAttribute>,
+    #[serde(default)]
+    types: BTreeMap<String, KsyType>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KsyAttribute {
+    id: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<KsyTypeRef>,
+    size: Option<KsyExpr>,
+    #[serde(default, rename = "size-eos")]
+    size_eos: bool,
+    contents: Option<Value>,
+    doc: Option<String>,
+    repeat: Option<String>,
+    #[serde(rename = "repeat-expr")]
+    repeat_expr: Option<KsyExpr>,
+    #[serde(rename = "if")]
+    condition: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KsyTypeRef {
+    Name(String),
+    Switch {
+        #[serde(rename = "switch-on")]
+        switch_on: Value,
+        cases: serde_yaml::Mapping,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KsyExpr {
+    Value(usize),
+    Expression(String),
+}
+
+/// Options shared by all the fields of the format
+#[derive(Debug, Clone, Copy)]
+struct Defaults {
+    bits: bool, // Whether the lengths are in bits (if the format has bit-sized integers) instead of bytes
+    endianness: Endianness,
+}
+
+/// Import the top level sequence of a Kaitai Struct file, with the user types as definitions
+pub fn from_ksy(yaml: &str) -> Result<ProtoDescriptor, Error> {
+    let ksy: KsyFile = serde_yaml::from_str(yaml)
+        .map_err(|e| Error::FormatError(format!("Invalid Kaitai Struct file: {}", e)))?;
+
+    if ksy.seq.is_empty() {
+        return Err(Error::FormatError(
+            "Kaitai Struct file has no sequence".to_string(),
+        ));
+    }
+
+    // Nested types are moved to the top level, as definitions are referenced by name
+    let mut types = BTreeMap::new();
+    collect_types(&ksy.types, &mut types);
+
+    let defaults = Defaults {
+        bits: ksy.seq.iter().any(is_bit_sized)
+            || types.values().flat_map(|t| &t.seq).any(is_bit_sized),
+        endianness: match ksy.meta.endian.as_ref().and_then(Value::as_str) {
+            Some("le") => Endianness::Little,
+            _ => Endianness::Big,
+        },
+    };
+
+    let definitions = types
+        .iter()
+        .map(|(name, ksy_type)| Ok((name.to_string(), sequence(&ksy_type.seq, defaults)?)))
+        .collect::<Result<_, Error>>()?;
+
+    Ok(ProtoDescriptor {
+        elements: ElementsDescriptor {
+            network_order: defaults.endianness == Endianness::Big,
+            ..Default::default()
+        },
+        fields: sequence(&ksy.seq, defaults)?,
+        definitions,
+        ..Default::default()
+    })
+}
+
+fn collect_types<'a>(
+    types: &'a BTreeMap<String, KsyType>,
+    collected: &mut BTreeMap<&'a str, &'a KsyType>,
+) {
+    for (name, ksy_type) in types {
+        collected.insert(name, ksy_type);
+        collect_types(&ksy_type.types, collected);
+    }
+}
+
+fn is_bit_sized(attribute: &KsyAttribute) -> bool {
+    match &attribute.kind {
+        Some(KsyTypeRef::Name(name)) => bit_width(name).is_some(),
+        _ => false,
+    }
+}
+
+/// Convert a sequence into fields
+fn sequence(seq: &[KsyAttribute], defaults: Defaults) -> Result<Vec<FieldDescriptor>, Error> {
+    seq.iter()
+        .enumerate()
+        .map(|(i, attribute)| field(attribute, i, defaults))
+        .collect()
+}
+
+/// Convert an attribute into a field
+fn field(
+    attribute: &KsyAttribute,
+    index: usize,
+    defaults: Defaults,
+) -> Result<FieldDescriptor, Error> {
+    let name = attribute
+        .id
+        .clone()
+        .unwrap_or_else(|| format!("unnamed{}", index));
+    let byte = if defaults.bits { 8 } else { 1 };
+
+    let mut field = FieldDescriptor::new(name.clone(), FieldLength::Fixed(0));
+    field.description = attribute.doc.as_ref().map(|doc| doc.trim().to_owned());
+    field.condition = attribute.condition.as_ref().map(expression);
+
+    // An explicit size takes precedence over the size of the type
+    let size = if attribute.size_eos {
+        Some(FieldLength::Variable(EOS_LENGTH.to_owned()))
+    } else {
+        attribute.size.as_ref().map(|size| match size {
+            KsyExpr::Value(size) => FieldLength::Fixed(size * byte),
+            KsyExpr::Expression(size) => FieldLength::Variable(size.clone()),
+        })
+    };
+
+    match &attribute.kind {
+        Some(KsyTypeRef::Name(kind)) if kind == "str" || kind == "strz" => {
+            // Strings without an explicit size extend until their terminator
+            field.length = FieldLength::Variable(format!("len_{}", name));
+        }
+        Some(KsyTypeRef::Name(kind)) => {
+            let typed = field_of_type(kind, defaults);
+            field.length = typed.length;
+            field.endianness = typed.endianness;
+            field.structure = typed.structure;
+        }
+        Some(KsyTypeRef::Switch { switch_on, cases }) => {
+            if cases.is_empty() {
+                return Err(Error::FormatError(format!(
+                    "Attribute {} has no cases",
+                    name
+                )));
+            }
+
+            for (case, kind) in cases {
+                let kind = kind.as_str().ok_or_else(|| {
+                    Error::FormatError(format!("Invalid type in the cases of {}", name))
+                })?;
+
+                let case_field = field_of_type(kind, defaults);
+
+                field.alternatives.push(AlternativeDescriptor {
+                    name: format!("{} == {}", expression(switch_on), expression(case)),
+                    fields: vec![case_field],
+                });
+            }
+
+            // Alternatives with different (or not yet known) lengths can't define the length of the field
+            if size.is_none() {
+                let lengths = field
+                    .alternatives
+                    .iter()
+                    .map(|alternative| alternative.fields[0].length.clone())
+                    .collect::<Vec<_>>();
+
+                if lengths.iter().any(|length| *length != lengths[0])
+                    || lengths[0] == FieldLength::Fixed(0)
+                {
+                    field.length = FieldLength::Variable(format!("len_{}", name));
+                }
+            }
+        }
+        None => {
+            if let Some(contents) = &attribute.contents {
+                field.length = FieldLength::Fixed(contents_length(contents)? * byte);
+            } else if size.is_none() {
+                return Err(Error::FormatError(format!(
+                    "Attribute {} has no type or size",
+                    name
+                )));
+            }
+        }
+    }
+
+    if let Some(size) = size {
+        field.length = size;
+    }
+
+    field.repeat = match attribute.repeat.as_deref() {
+        Some("expr") => Some(
+            match attribute.repeat_expr.as_ref().ok_or_else(|| {
+                Error::FormatError(format!("Attribute {} has no repeat-expr", name))
+            })? {
+                KsyExpr::Value(count) => FieldLength::Fixed(*count),
+                KsyExpr::Expression(count) => FieldLength::Variable(count.clone()),
+            },
+        ),
+        Some("eos") => Some(FieldLength::Variable(EOS_LENGTH.to_owned())),
+        Some("until") => Some(FieldLength::Variable(UNTIL_COUNT.to_owned())),
+        Some(repeat) => {
+            return Err(Error::FormatError(format!(
+                "Unknown repeat type {}",
+                repeat
+            )))
+        }
+        None => None,
+    };
+
+    Ok(field)
+}
+
+/// Field holding a value of the given type, named after it
+fn field_of_type(kind: &str, defaults: Defaults) -> FieldDescriptor {
+    let mut field = FieldDescriptor::new(kind, FieldLength::Fixed(0));
+
+    if let Some(bits) = bit_width(kind) {
+        field.length = FieldLength::Fixed(bits);
+    } else if let Some((bytes, endianness)) = primitive(kind) {
+        field.length = FieldLength::Fixed(if defaults.bits { bytes * 8 } else { bytes });
+        // Only the types overriding the default endianness are marked
+        field.endianness = endianness.filter(|endianness| *endianness != defaults.endianness);
+    } else {
+        // User types keep their name, with the length derived from the definition
+        field.structure = Some(user_type(kind).to_owned());
+    }
+
+    field
+}
+
+/// Size in bytes and explicit endianness of a primitive type (e.g. "u4le")
+fn primitive(kind: &str) -> Option<(usize, Option<Endianness>)> {
+    let (kind, endianness) = if let Some(kind) = kind.strip_suffix("le") {
+        (kind, Some(Endianness::Little))
+    } else if let Some(kind) = kind.strip_suffix("be") {
+        (kind, Some(Endianness::Big))
+    } else {
+        (kind, None)
+    };
+
+    let bytes = match kind {
+        "u1" | "s1" => 1,
+        "u2" | "s2" => 2,
+        "u4" | "s4" | "f4" => 4,
+        "u8" | "s8" | "f8" => 8,
+        _ => return None,
+    };
+
+    Some((bytes, endianness))
+}
+
+/// Width of a bit-sized integer type (e.g. "b3")
+fn bit_width(kind: &str) -> Option<usize> {
+    let kind = kind
+        .strip_suffix("le")
+        .or_else(|| kind.strip_suffix("be"))
+        .unwrap_or(kind);
+
+    kind.strip_prefix('b')?.parse().ok()
+}
+
+/// Name of a user type, without its parameters (e.g. "chunk(4)")
+fn user_type(kind: &str) -> &str {
+    kind.split('(').next().unwrap_or(kind).trim()
+}
+
+/// Length of the fixed contents of an attribute (a string or an array of bytes and strings)
+fn contents_length(contents: &Value) -> Result<usize, Error> {
+    match contents {
+        Value::String(text) => Ok(text.len()),
+        Value::Sequence(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::String(text) => Ok(text.len()),
+                Value::Number(_) => Ok(1),
+                _ => Err(Error::FormatError("Invalid contents".to_string())),
+            })
+            .sum(),
+        _ => Err(Error::FormatError("Invalid contents".to_string())),
+    }
+}
+
+/// Text of an expression, which may have been parsed as another scalar
+fn expression(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Number(number) => number.to_string(),
+        Value::Bool(value) => value.to_string(),
+        value => serde_yaml::to_string(value)
+            .unwrap_or_default()
+            .trim()
+            .to_owned(),
+    }
+}
@@ -0,0 +1,11 @@
+//! Importers creating descriptors from existing protocol and register definitions
+
+#[cfg(feature = "svd")]
+pub mod svd;
+
+#[cfg(feature = "kaitai")]
+pub mod kaitai;
+
+pub mod c;
+
+mod rfc;
@@ -0,0 +1,345 @@
+//! Import the ASCII packet diagrams of RFC-style specifications
+
+use crate::descriptor::{FieldDescriptor, FieldLength, ProtoDescriptor, StyleDescriptor};
+use crate::errors::Error;
+
+// Width of a bit in the diagrams without a bit ruler
+const DEFAULT_BIT_WIDTH: usize = 2;
+// Width of a bit unit when rendering the imported diagram, so 32 bit rows stay readable
+const RFC_UNIT_WIDTH: usize = 25;
+
+/// Characters drawing the left and right edges of a row, the ones other than '|' marking variable lengths
+const EDGES: [char; 5] = ['|', '/', '\\', '~', ':'];
+
+impl ProtoDescriptor {
+    /// Reconstruct the fields of an RFC-style ASCII packet diagram, with the lengths in bits
+    pub fn from_rfc_ascii(diagram: &str) -> Result<Self, Error> {
+        let lines = diagram
+            .lines()
+            .map(|line| expand_tabs(line.trim_end()))
+            .collect::<Vec<_>>();
+
+        let first_border = lines
+            .iter()
+            .position(|line| is_border(line))
+            .ok_or_else(|| Error::FormatError("No border found in the diagram".to_string()))?;
+        let origin = lines[first_border].iter().position(|c| *c == '+').unwrap();
+        let bit_width = bit_width(&lines[..first_border]);
+
+        // The diagram goes on while the lines start at the left border
+        let lines = lines[first_border..]
+            .iter()
+            .take_while(|line| {
+                line.get(origin)
+                    .is_some_and(|c| *c == '+' || EDGES.contains(c))
+            })
+            .collect::<Vec<_>>();
+
+        // Split the diagram in rows of bits, each one made of the lines between two separators
+        let mut separators = Vec::new();
+        let mut rows: Vec<Vec<&Vec<char>>> = Vec::new();
+        for line in lines {
+            if line[origin] == '+' {
+                if rows.last().is_some_and(Vec::is_empty) {
+                    separators.pop();
+                    rows.pop();
+                }
+                separators.push(line);
+                rows.push(Vec::new());
+            } else {
+                rows.last_mut().unwrap().push(line);
+            }
+        }
+
+        // The closing border starts an empty row, which a truncated diagram doesn't have
+        if !rows.last().is_some_and(Vec::is_empty) {
+            return Err(Error::FormatError(
+                "Diagram has no closing border".to_string(),
+            ));
+        }
+        rows.pop();
+
+        if rows.is_empty() {
+            return Err(Error::FormatError(
+                "No fields found in the diagram".to_string(),
+            ));
+        }
+
+        let diagram = Diagram {
+            origin,
+            bit_width,
+            widths: rows
+                .iter()
+                .enumerate()
+                .map(|(r, row)| row_width(origin, bit_width, row, &separators[r..=r + 1]))
+                .collect(),
+            separators,
+            rows,
+        };
+
+        let fields = diagram.fields()?;
+        if fields.is_empty() {
+            return Err(Error::FormatError(
+                "No fields found in the diagram".to_string(),
+            ));
+        }
+
+        Ok(ProtoDescriptor {
+            fields,
+            style: StyleDescriptor {
+                unit_width: RFC_UNIT_WIDTH,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+}
+
+/// Rows of a diagram, with the separators between them
+struct Diagram<'a> {
+    origin: usize,
+    bit_width: usize,
+    separators: Vec<&'a Vec<char>>,
+    rows: Vec<Vec<&'a Vec<char>>>,
+    widths: Vec<usize>, // Number of bits in each row
+}
+
+impl Diagram<'_> {
+    /// Column of the border on the left of the given bit
+    fn column(&self, bit: usize) -> usize {
+        self.origin + bit * self.bit_width
+    }
+
+    /// Group the bits in fields, merging the ones not separated by a border
+    fn fields(&self) -> Result<Vec<FieldDescriptor>, Error> {
+        let starts = self
+            .widths
+            .iter()
+            .scan(0, |start, width| {
+                let row_start = *start;
+                *start += width;
+                Some(row_start)
+            })
+            .collect::<Vec<_>>();
+        let total = starts.last().unwrap() + self.widths.last().unwrap();
+
+        let mut parents = (0..total).collect::<Vec<_>>();
+
+        for (r, row) in self.rows.iter().enumerate() {
+            for bit in 0..self.widths[r] {
+                // Bits of the same row are merged if no line has a border between them
+                let column = self.column(bit + 1);
+                if bit + 1 < self.widths[r] && row.iter().all(|line| line.get(column) != Some(&'|'))
+                {
+                    union(&mut parents, starts[r] + bit, starts[r] + bit + 1);
+                }
+
+                // Bits of consecutive rows are merged if the separator has no border between them
+                if r > 0 && bit < self.widths[r - 1] && !self.has_border(self.separators[r], bit) {
+                    union(&mut parents, starts[r - 1] + bit, starts[r] + bit);
+                }
+            }
+        }
+
+        // Bits of each field, in order
+        let mut groups: Vec<(usize, Vec<(usize, usize)>)> = Vec::new();
+        for (r, width) in self.widths.iter().enumerate() {
+            for bit in 0..*width {
+                let root = find(&mut parents, starts[r] + bit);
+                match groups
+                    .iter_mut()
+                    .find(|(group_root, _)| *group_root == root)
+                {
+                    Some((_, bits)) => bits.push((r, bit)),
+                    None => groups.push((root, vec![(r, bit)])),
+                }
+            }
+        }
+
+        let mut fields = Vec::new();
+        for (i, (_, bits)) in groups.iter().enumerate() {
+            let name = self
+                .field_name(bits)
+                .unwrap_or_else(|| format!("unnamed{}", i));
+
+            // Fields have to cover a contiguous range of bits
+            let offsets = bits
+                .iter()
+                .map(|(r, bit)| starts[*r] + bit)
+                .collect::<Vec<_>>();
+            if offsets.windows(2).any(|pair| pair[1] != pair[0] + 1) {
+                return Err(Error::FormatError(format!(
+                    "Field {} is not contiguous",
+                    name
+                )));
+            }
+
+            let length = if self.is_variable(bits) {
+                FieldLength::Variable(format!("len_{}", name.to_lowercase().replace(' ', "_")))
+            } else {
+                FieldLength::Fixed(bits.len())
+            };
+
+            let (last_row, last_bit) = *bits.last().unwrap();
+            let mut field = FieldDescriptor::new(name, length);
+            field.wrap = last_bit + 1 == self.widths[last_row] && i + 1 < groups.len();
+            fields.push(field);
+        }
+
+        Ok(fields)
+    }
+
+    /// Whether the separator has a border over the given bit
+    fn has_border(&self, separator: &[char], bit: usize) -> bool {
+        (self.column(bit) + 1..self.column(bit + 1))
+            .any(|column| separator.get(column) == Some(&'-'))
+    }
+
+    /// Text written over the bits of a field, joining its lines
+    fn field_name(&self, bits: &[(usize, usize)]) -> Option<String> {
+        let mut parts = Vec::new();
+        let single_bit = bits.iter().all(|(_, bit)| *bit == bits[0].1);
+
+        for (r, row) in self.rows.iter().enumerate() {
+            let row_bits = bits.iter().filter(|(bit_row, _)| *bit_row == r);
+            let (Some(first), Some(last)) = (
+                row_bits.clone().map(|b| b.1).min(),
+                row_bits.map(|b| b.1).max(),
+            ) else {
+                continue;
+            };
+            let columns = self.column(first) + 1..self.column(last + 1);
+
+            // Text can also be written over the separators inside of a field (e.g. long addresses)
+            let mut lines = row.clone();
+            if r > 0
+                && bits
+                    .iter()
+                    .any(|(bit_row, bit)| *bit_row == r - 1 && *bit == first)
+            {
+                lines.insert(0, self.separators[r]);
+            }
+
+            for line in lines {
+                let text = columns
+                    .clone()
+                    .filter_map(|column| line.get(column))
+                    .collect::<String>();
+                let text = text.trim_matches(|c: char| c.is_whitespace() || c == '-' || c == '+');
+                if !text.is_empty() {
+                    parts.push(text.split_whitespace().collect::<Vec<_>>().join(" "));
+                }
+            }
+        }
+
+        // Names written vertically over a single bit (e.g. the TCP flags) are joined without spaces
+        let name = parts.join(if single_bit { "" } else { " " });
+        (!name.is_empty()).then_some(name)
+    }
+
+    /// Whether the edges of the field mark it as having a variable length
+    fn is_variable(&self, bits: &[(usize, usize)]) -> bool {
+        bits.iter().any(|(r, bit)| {
+            let edges = [
+                (*bit == 0).then(|| self.column(0)),
+                (*bit + 1 == self.widths[*r]).then(|| self.column(bit + 1)),
+            ];
+
+            self.rows[*r].iter().any(|line| {
+                edges.iter().flatten().any(|column| {
+                    line.get(*column)
+                        .is_some_and(|c| *c != '|' && EDGES.contains(c))
+                })
+            })
+        })
+    }
+}
+
+/// Whether the line is a border made of `+-+-+` segments
+fn is_border(line: &[char]) -> bool {
+    let text = line.iter().collect::<String>();
+    let text = text.trim();
+
+    text.len() > 2
+        && text.starts_with('+')
+        && text.ends_with('+')
+        && text.contains('-')
+        && text.chars().all(|c| c == '+' || c == '-')
+}
+
+/// Width in characters of a bit, taken from the spacing of the bit ruler if there is one
+fn bit_width(header: &[Vec<char>]) -> usize {
+    let ruler = header.iter().rev().find(|line| {
+        line.iter().any(char::is_ascii_digit)
+            && line.iter().all(|c| c.is_ascii_digit() || *c == ' ')
+    });
+
+    let digits = ruler
+        .map(|line| {
+            line.iter()
+                .enumerate()
+                .filter(|(_, c)| c.is_ascii_digit())
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    match digits.as_slice() {
+        [first, second, ..] if second > first => second - first,
+        _ => DEFAULT_BIT_WIDTH,
+    }
+}
+
+/// Number of bits of a row, from its rightmost edge
+fn row_width(
+    origin: usize,
+    bit_width: usize,
+    row: &[&Vec<char>],
+    separators: &[&Vec<char>],
+) -> usize {
+    let edge = |line: &Vec<char>, edges: &[char]| {
+        line.iter()
+            .enumerate()
+            .skip(origin + 1)
+            .filter(|(i, c)| (i - origin).is_multiple_of(bit_width) && edges.contains(c))
+            .map(|(i, _)| i)
+            .max()
+    };
+
+    let content = row.iter().filter_map(|line| edge(line, &EDGES)).max();
+    let borders = separators
+        .iter()
+        .filter_map(|line| edge(line, &['+']))
+        .min();
+
+    // Rows narrower than their borders (e.g. a last partial row) end at their right edge
+    let end = content.or(borders).unwrap_or(origin);
+    (end - origin) / bit_width
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    parents[b.max(a)] = b.min(a);
+}
+
+/// Replace the tabs by spaces, as the diagrams are aligned on columns
+fn expand_tabs(line: &str) -> Vec<char> {
+    let mut chars = Vec::new();
+    for c in line.chars() {
+        if c == '\t' {
+            chars.extend(std::iter::repeat_n(' ', 8 - chars.len() % 8));
+        } else {
+            chars.push(c);
+        }
+    }
+    chars
+}
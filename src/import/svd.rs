@@ -0,0 +1,444 @@
+//! Import the registers of a CMSIS-SVD device description
+
+use roxmltree::{Document, Node};
+
+use crate::descriptor::{
+    Access, ElementsDescriptor, FieldDescriptor, FieldLength, FillPattern, ProtoDescriptor,
+    ResetValue,
+};
+use crate::errors::Error;
+
+const DEFAULT_REGISTER_SIZE: u64 = 32;
+const DEFAULT_ROW_BITS: u64 = 16;
+const MAX_REGISTER_SIZE: u64 = 64;
+const RESERVED_NAME: &str = "Reserved";
+
+/// Register imported from a CMSIS-SVD file
+#[derive(Debug)]
+pub struct SvdRegister {
+    pub peripheral: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub descriptor: ProtoDescriptor,
+}
+
+/// Register properties, inherited from the device, peripheral and cluster levels
+#[derive(Debug, Clone, Copy, Default)]
+struct Properties {
+    size: Option<u64>,
+    access: Option<Access>,
+    reset: Option<u64>,
+}
+
+/// Field of a register, with its bit range
+#[derive(Debug, Clone)]
+struct SvdField {
+    name: String,
+    description: Option<String>,
+    msb: u64,
+    lsb: u64,
+    access: Option<Access>,
+    reset: Option<u64>,
+    reserved: bool,
+}
+
+/// Import every register of a CMSIS-SVD device, filling the gaps between fields with reserved fields
+pub fn from_svd(xml: &str) -> Result<Vec<SvdRegister>, Error> {
+    let document =
+        Document::parse(xml).map_err(|e| Error::FormatError(format!("Invalid SVD file: {}", e)))?;
+
+    let device = document.root_element();
+    if !device.has_tag_name("device") {
+        return Err(Error::FormatError(
+            "SVD file must have a device element".to_string(),
+        ));
+    }
+
+    let properties = Properties::default().inherit(device)?;
+    let peripherals = child(device, "peripherals")
+        .map(|node| children(node, "peripheral").collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut registers = Vec::new();
+    for peripheral in &peripherals {
+        let name = required_text(*peripheral, "name")?;
+
+        // Derived peripherals reuse the registers of the original one
+        let source = match peripheral.attribute("derivedFrom") {
+            Some(base) if child(*peripheral, "registers").is_none() => *peripherals
+                .iter()
+                .find(|p| text(**p, "name") == Some(base))
+                .ok_or_else(|| Error::FormatError(format!("Unknown peripheral {}", base)))?,
+            _ => *peripheral,
+        };
+
+        let properties = properties.inherit(source)?.inherit(*peripheral)?;
+        if let Some(node) = child(source, "registers") {
+            import_registers(node, name, "", properties, &mut registers)?;
+        }
+    }
+
+    Ok(registers)
+}
+
+/// Import the registers and clusters of a node, with the cluster names as prefix
+fn import_registers(
+    node: Node,
+    peripheral: &str,
+    prefix: &str,
+    properties: Properties,
+    registers: &mut Vec<SvdRegister>,
+) -> Result<(), Error> {
+    for element in node.children().filter(|n| n.is_element()) {
+        let properties = properties.inherit(element)?;
+        let names = dim_names(element, required_text(element, "name")?)?;
+
+        if element.has_tag_name("cluster") {
+            for name in names {
+                let prefix = format!("{}{}.", prefix, name);
+                import_registers(element, peripheral, &prefix, properties, registers)?;
+            }
+        } else if element.has_tag_name("register") {
+            for name in names {
+                registers.push(SvdRegister {
+                    peripheral: peripheral.to_owned(),
+                    name: format!("{}{}", prefix, name),
+                    description: text(element, "description").map(clean_text),
+                    descriptor: register_descriptor(element, &name, properties)?,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Create the descriptor of a register, laid out from its most significant bit
+fn register_descriptor(
+    register: Node,
+    name: &str,
+    properties: Properties,
+) -> Result<ProtoDescriptor, Error> {
+    let size = properties.size.unwrap_or(DEFAULT_REGISTER_SIZE);
+    if size == 0 {
+        return Err(Error::FormatError(format!("Register {} has no size", name)));
+    }
+    if size > MAX_REGISTER_SIZE {
+        return Err(Error::FormatError(format!(
+            "Register {} has a size of {} bits, larger than {} bits",
+            name, size, MAX_REGISTER_SIZE
+        )));
+    }
+
+    let mut fields = Vec::new();
+    for field in child(register, "fields")
+        .into_iter()
+        .flat_map(|node| children(node, "field"))
+    {
+        let field_properties = properties.inherit(field)?;
+        let (msb, lsb) = bit_range(field)?;
+
+        // The elements of a field array are placed one increment apart
+        let increment = text(field, "dimIncrement").map_or(Ok(0), parse_number)?;
+
+        for (i, name) in dim_names(field, required_text(field, "name")?)?
+            .into_iter()
+            .enumerate()
+        {
+            let (msb, lsb) = (msb + i as u64 * increment, lsb + i as u64 * increment);
+
+            fields.push(SvdField {
+                name,
+                description: text(field, "description").map(clean_text),
+                msb,
+                lsb,
+                access: field_properties.access,
+                reset: properties
+                    .reset
+                    .map(|reset| reset.checked_shr(lsb as u32).unwrap_or(0) & mask(msb - lsb + 1)),
+                reserved: false,
+            });
+        }
+    }
+
+    // A register without fields is drawn as a single field
+    if fields.is_empty() {
+        fields.push(SvdField {
+            name: name.to_owned(),
+            description: None,
+            msb: size - 1,
+            lsb: 0,
+            access: properties.access,
+            reset: properties.reset.map(|reset| reset & mask(size)),
+            reserved: false,
+        });
+    }
+
+    fields.sort_by_key(|field| std::cmp::Reverse(field.lsb));
+
+    // Fill the gaps with reserved fields, starting from the most significant bit
+    let mut layout = Vec::new();
+    let mut next = size;
+    for field in fields {
+        if field.msb >= next {
+            return Err(Error::FormatError(format!(
+                "Field {} of register {} overlaps another field or exceeds the register size",
+                field.name, name
+            )));
+        }
+
+        if field.msb + 1 < next {
+            layout.push(reserved(next - 1, field.msb + 1));
+        }
+
+        next = field.lsb;
+        layout.push(field);
+    }
+
+    if next > 0 {
+        layout.push(reserved(next - 1, 0));
+    }
+
+    // Split the fields crossing the row boundaries, so each row has the same number of bits
+    let row_bits = size.min(DEFAULT_ROW_BITS);
+    let mut descriptor_fields = Vec::new();
+    for field in layout {
+        let mut msb = field.msb;
+
+        while msb >= field.lsb {
+            let lsb = field.lsb.max(msb - msb % row_bits);
+
+            let name = if (msb == field.msb && lsb == field.lsb) || field.reserved {
+                field.name.clone()
+            } else if msb == lsb {
+                format!("{}[{}]", field.name, msb - field.lsb)
+            } else {
+                format!("{}[{}:{}]", field.name, msb - field.lsb, lsb - field.lsb)
+            };
+
+            let mut descriptor_field =
+                FieldDescriptor::new(name, FieldLength::Fixed((msb - lsb + 1) as usize));
+            descriptor_field.wrap = lsb % row_bits == 0 && lsb != 0;
+            descriptor_field.description = field.description.clone();
+            descriptor_field.access = field.access;
+            descriptor_field.reset = field.reset.map(|reset| {
+                ResetValue::Number((reset >> (lsb - field.lsb)) & mask(msb - lsb + 1))
+            });
+            if field.reserved {
+                descriptor_field.pattern = Some(FillPattern::Hatched);
+            }
+
+            descriptor_fields.push(descriptor_field);
+
+            if lsb == 0 {
+                break;
+            }
+            msb = lsb - 1;
+        }
+    }
+
+    Ok(ProtoDescriptor {
+        elements: ElementsDescriptor {
+            field_length: false,
+            register: true,
+            ..Default::default()
+        },
+        fields: descriptor_fields,
+        ..Default::default()
+    })
+}
+
+/// Create a reserved field covering the given bits
+fn reserved(msb: u64, lsb: u64) -> SvdField {
+    SvdField {
+        name: RESERVED_NAME.to_owned(),
+        description: None,
+        msb,
+        lsb,
+        access: None,
+        reset: None,
+        reserved: true,
+    }
+}
+
+impl Properties {
+    /// Override the properties with the ones defined by a node
+    fn inherit(&self, node: Node) -> Result<Self, Error> {
+        let mut properties = *self;
+
+        if let Some(size) = text(node, "size") {
+            properties.size = Some(parse_number(size)?);
+        }
+
+        if let Some(reset) = text(node, "resetValue") {
+            properties.reset = Some(parse_number(reset)?);
+        }
+
+        if let Some(access) = text(node, "access") {
+            properties.access = Some(match access.trim() {
+                "read-only" => Access::ReadOnly,
+                "write-only" | "writeOnce" => Access::WriteOnly,
+                "read-write" | "read-writeOnce" => Access::ReadWrite,
+                access => {
+                    return Err(Error::FormatError(format!(
+                        "Unknown access type {}",
+                        access
+                    )))
+                }
+            });
+        }
+
+        // Side effects of reads and writes take precedence over the access type
+        match text(node, "modifiedWriteValues").map(str::trim) {
+            Some("oneToClear") => properties.access = Some(Access::WriteOneToClear),
+            Some("oneToSet") => properties.access = Some(Access::WriteOneToSet),
+            _ => {}
+        }
+
+        if text(node, "readAction").map(str::trim) == Some("clear") {
+            properties.access = Some(Access::ReadToClear);
+        }
+
+        Ok(properties)
+    }
+}
+
+/// Bit range of a field, defined by an offset and width, the LSB and MSB or a "[msb:lsb]" string
+fn bit_range(field: Node) -> Result<(u64, u64), Error> {
+    let (msb, lsb) = if let Some(offset) = text(field, "bitOffset") {
+        let offset = parse_number(offset)?;
+        let width = text(field, "bitWidth").map_or(Ok(1), parse_number)?;
+
+        if width == 0 {
+            return Err(Error::FormatError("Field width cannot be zero".to_string()));
+        }
+
+        let msb = offset.checked_add(width - 1).ok_or_else(|| {
+            Error::FormatError(format!(
+                "Field bit offset {} and width {} overflow",
+                offset, width
+            ))
+        })?;
+
+        (msb, offset)
+    } else if let (Some(lsb), Some(msb)) = (text(field, "lsb"), text(field, "msb")) {
+        (parse_number(msb)?, parse_number(lsb)?)
+    } else if let Some(range) = text(field, "bitRange") {
+        let (msb, lsb) = range
+            .trim()
+            .strip_prefix('[')
+            .and_then(|range| range.strip_suffix(']'))
+            .and_then(|range| range.split_once(':'))
+            .ok_or_else(|| Error::FormatError(format!("Invalid bit range {}", range)))?;
+
+        (parse_number(msb)?, parse_number(lsb)?)
+    } else {
+        return Err(Error::FormatError(format!(
+            "Field {} has no bit range",
+            text(field, "name").unwrap_or_default()
+        )));
+    };
+
+    if msb < lsb {
+        return Err(Error::FormatError(format!(
+            "Invalid bit range [{}:{}]",
+            msb, lsb
+        )));
+    }
+
+    Ok((msb, lsb))
+}
+
+/// Names of an element, expanded if it is an array ("%s" is replaced by each index)
+fn dim_names(node: Node, name: &str) -> Result<Vec<String>, Error> {
+    let dim = match text(node, "dim") {
+        Some(dim) => parse_number(dim)?,
+        None => return Ok(vec![name.to_owned()]),
+    };
+
+    let indexes = match text(node, "dimIndex") {
+        Some(indexes) => match indexes.split_once('-') {
+            Some((start, end)) if !indexes.contains(',') => (parse_number(start)?
+                ..=parse_number(end)?)
+                .map(|index| index.to_string())
+                .collect::<Vec<_>>(),
+            _ => indexes
+                .split(',')
+                .map(|index| index.trim().to_owned())
+                .collect(),
+        },
+        None => (0..dim).map(|index| index.to_string()).collect(),
+    };
+
+    if indexes.len() as u64 != dim {
+        return Err(Error::FormatError(format!(
+            "Array {} has {} indexes but a dimension of {}",
+            name,
+            indexes.len(),
+            dim
+        )));
+    }
+
+    Ok(indexes
+        .iter()
+        .map(|index| name.replace("[%s]", index).replace("%s", index))
+        .collect())
+}
+
+/// Parse a scaled non-negative integer ("0x", "#" binary or decimal)
+fn parse_number(value: &str) -> Result<u64, Error> {
+    let value = value.trim();
+
+    let result = if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(binary) = value.strip_prefix('#') {
+        // Don't care bits are taken as zero
+        u64::from_str_radix(&binary.replace(['x', 'X'], "0"), 2)
+    } else {
+        value.parse()
+    };
+
+    result.map_err(|_| Error::FormatError(format!("Invalid number {}", value)))
+}
+
+/// Mask covering the given number of bits
+fn mask(bits: u64) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// Collapse the whitespace of a description
+fn clean_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |n| n.has_tag_name(name))
+}
+
+fn text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|n| n.text())
+}
+
+fn required_text<'a>(node: Node<'a, '_>, name: &str) -> Result<&'a str, Error> {
+    text(node, name).ok_or_else(|| {
+        Error::FormatError(format!(
+            "Element {} has no {}",
+            node.tag_name().name(),
+            name
+        ))
+    })
+}
@@ -4,16 +4,98 @@
 //!
 //! For an example of how to use this library, check the examples folder or the [ProtoViz Website](https://protoviz.stu.art.br).
 
+pub mod codegen;
+pub mod compat;
+pub mod decode;
 pub mod descriptor;
+pub mod diff;
 pub mod errors;
+pub mod import;
+pub mod lint;
+pub mod pcap;
 mod template;
 
+// Lets the derived implementations refer to the crate by name, including in its own tests
+#[cfg(feature = "derive")]
+extern crate self as protoviz;
+
+#[cfg(feature = "derive")]
+pub use protoviz_derive::ProtoViz;
+
+#[cfg(feature = "derive")]
+#[doc(hidden)]
+pub mod __private {
+    pub use hex_color::HexColor;
+}
+
 use errors::Error;
-use template::generate_data;
+use template::{generate_data, generate_stack_data, TemplateData};
 use tera::{Context, Tera};
 
+/// Types with a protocol descriptor, usually implemented with `#[derive(ProtoViz)]`
+pub trait ProtoViz {
+    /// Descriptor of the type, with a field per struct field
+    fn descriptor() -> descriptor::ProtoDescriptor;
+}
+
 /// Render the SVG image of the protocol
 pub fn render(descriptor: &descriptor::ProtoDescriptor) -> Result<String, Error> {
+    let (data, _) = prepare(descriptor, None)?;
+
+    render_data(&data)
+}
+
+/// Render the SVG image of the protocol, with its styles and pattern ids scoped by a class
+///
+/// Used to embed several images in the same page (e.g. `pv-packet-1`, `pv-packet-2`, ...) without them sharing
+/// their definitions.
+pub fn render_scoped(
+    descriptor: &descriptor::ProtoDescriptor,
+    scope: &str,
+) -> Result<String, Error> {
+    let (data, _) = prepare(descriptor, Some(scope.to_owned()))?;
+
+    render_data(&data)
+}
+
+/// Render the SVG image of an encapsulation stack, with each layer below the one carrying it
+pub fn render_stack(descriptor: &descriptor::StackDescriptor) -> Result<String, Error> {
+    if descriptor.layers.is_empty() {
+        return Err(Error::FormatError("No layers provided".to_string()));
+    }
+
+    let mut layers = Vec::new();
+    for (i, layer) in descriptor.layers.iter().enumerate() {
+        let (data, flat) = prepare(&layer.protocol, Some(format!("pv-layer-{}", i)))?;
+
+        let payload = match &layer.payload {
+            Some(payload) => Some(flat.spans[layer.protocol.field_index(payload)?]),
+            None => None,
+        };
+
+        layers.push((data, payload));
+    }
+
+    let stack = generate_stack_data(descriptor, &mut layers);
+
+    let layers = layers
+        .iter()
+        .map(|(data, _)| render_data(data))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut context = Context::new();
+
+    context.insert("stack", &stack);
+    context.insert("layers", &layers);
+
+    Tera::one_off(include_str!("../stack.svg"), &context, false).map_err(Error::TeraError)
+}
+
+/// Validate the protocol and generate the template data
+fn prepare(
+    descriptor: &descriptor::ProtoDescriptor,
+    scope: Option<String>,
+) -> Result<(TemplateData, descriptor::FlatDescriptor), Error> {
     if descriptor.style.unit_width < 10 {
         return Err(Error::FormatError(
             "Unit width cannot be less than 10".to_string(),
@@ -30,27 +112,72 @@ pub fn render(descriptor: &descriptor::ProtoDescriptor) -> Result<String, Error>
         return Err(Error::FormatError("No fields provided".to_string()));
     }
 
-    for field in &descriptor.fields {
-        if let descriptor::FieldLength::Fixed(0) = field.length {
+    let flat = descriptor.flatten()?;
+
+    for field in &flat.fields {
+        validate_field(field)?;
+
+        // Bit ranges can only be computed if every offset is known
+        if descriptor.elements.bit_numbering().is_some()
+            && !matches!(field.total_length()?, descriptor::FieldLength::Fixed(_))
+        {
+            return Err(Error::FormatError(
+                "Bit numbering requires fields with fixed lengths".to_string(),
+            ));
+        }
+
+        for alternative in &field.alternatives {
+            for sub_field in &alternative.fields {
+                validate_field(sub_field)?;
+            }
+        }
+    }
+
+    let data = generate_data(descriptor, &flat.fields, &flat.groups, scope)?;
+
+    Ok((data, flat))
+}
+
+/// Validate the length and repeat count of a field
+fn validate_field(field: &descriptor::FieldDescriptor) -> Result<(), Error> {
+    if let descriptor::FieldLength::Fixed(0) = field.length {
+        return Err(Error::FormatError(
+            "Field length cannot be zero".to_string(),
+        ));
+    }
+
+    if let descriptor::FieldLength::Variable(name) = &field.length {
+        if name.is_empty() {
             return Err(Error::FormatError(
-                "Field length cannot be zero".to_string(),
+                "Field length cannot be empty".to_string(),
             ));
         }
+    }
 
-        if let descriptor::FieldLength::Variable(name) = &field.length {
-            if name.is_empty() {
+    if let Some(count) = &field.repeat {
+        match count {
+            descriptor::FieldLength::Fixed(0) => {
+                return Err(Error::FormatError(
+                    "Field repeat count cannot be zero".to_string(),
+                ));
+            }
+            descriptor::FieldLength::Variable(name) if name.is_empty() => {
                 return Err(Error::FormatError(
-                    "Field length cannot be empty".to_string(),
+                    "Field repeat count cannot be empty".to_string(),
                 ));
             }
+            _ => {}
         }
     }
 
-    let data = generate_data(descriptor);
+    Ok(())
+}
 
+/// Render the template data into an SVG image
+fn render_data(data: &TemplateData) -> Result<String, Error> {
     let mut context = Context::new();
 
-    context.insert("data", &data);
+    context.insert("data", data);
 
     Tera::one_off(include_str!("../template.svg"), &context, false).map_err(|e| Error::TeraError(e))
 }
@@ -71,51 +198,1317 @@ mod tests {
                 field_length: true,
                 wrap_line: true,
                 start_symbol: true,
+                bit_numbering: None,
+                register: false,
             },
             style: descriptor::StyleDescriptor {
+                theme: descriptor::Theme::Light,
                 background_color: HexColor::rgb(255, 255, 255),
                 field_color: HexColor::rgb(255, 255, 255),
                 text_color: HexColor::rgb(0, 0, 0),
                 subtitle_color: HexColor::rgb(0, 0, 0),
                 unit_width: 50,
                 dyn_units: 3,
+                dark_theme: None,
+                css_classes: false,
             },
             fields: vec![
-                descriptor::FieldDescriptor {
-                    name: "field0".to_string(),
-                    length: descriptor::FieldLength::Fixed(1),
-                    wrap: false,
-                    color: None,
-                },
-                descriptor::FieldDescriptor {
-                    name: "field1".to_string(),
-                    length: descriptor::FieldLength::Fixed(2),
-                    wrap: false,
-                    color: None,
-                },
-                descriptor::FieldDescriptor {
-                    name: "field2".to_string(),
-                    length: descriptor::FieldLength::Fixed(1),
-                    wrap: false,
-                    color: None,
-                },
-                descriptor::FieldDescriptor {
-                    name: "field3".to_string(),
-                    length: descriptor::FieldLength::Variable("N".to_string()),
-                    wrap: false,
-                    color: None,
-                },
-                descriptor::FieldDescriptor {
-                    name: "field4".to_string(),
-                    length: descriptor::FieldLength::Fixed(1),
-                    wrap: false,
-                    color: None,
-                },
+                descriptor::FieldDescriptor::new("field0", descriptor::FieldLength::Fixed(1)),
+                descriptor::FieldDescriptor::new("field1", descriptor::FieldLength::Fixed(2)),
+                descriptor::FieldDescriptor::new("field2", descriptor::FieldLength::Fixed(1)),
+                descriptor::FieldDescriptor::new(
+                    "field3",
+                    descriptor::FieldLength::Variable("N".to_string()),
+                ),
+                descriptor::FieldDescriptor::new("field4", descriptor::FieldLength::Fixed(1)),
             ],
+            definitions: Default::default(),
+            groups: Vec::new(),
         };
 
         let result = render(&descriptor).unwrap();
         assert!(result.contains("field1"));
         assert!(result.contains("field3"));
     }
+
+    #[test]
+    fn test_theme_override() {
+        let style: descriptor::StyleDescriptor = serde_json::from_str(
+            r##"{"theme": "dark", "text_color": "#ff0000", "dark_theme": "high-contrast"}"##,
+        )
+        .unwrap();
+
+        let colors = descriptor::Theme::Dark.colors();
+        assert_eq!(style.theme, descriptor::Theme::Dark);
+        assert_eq!(style.background_color, colors.background_color);
+        assert_eq!(style.field_color, colors.field_color);
+        assert_eq!(style.text_color, HexColor::rgb(255, 0, 0));
+        assert_eq!(style.dark_theme, Some(descriptor::Theme::HighContrast));
+    }
+
+    #[test]
+    fn test_render_dark_theme() {
+        let mut descriptor: descriptor::ProtoDescriptor =
+            serde_json::from_str(r#"{"fields": [{"name": "field0", "length": 1}]}"#).unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert!(!result.contains("prefers-color-scheme"));
+
+        descriptor.style.dark_theme = Some(descriptor::Theme::Dark);
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains("prefers-color-scheme: dark"));
+    }
+
+    #[test]
+    fn test_render_css_classes() {
+        let mut descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r##"{"fields": [{"name": "Src Port", "length": 2, "color": "#ff0000"}, {"name": "data", "length": "N"}]}"##,
+        )
+        .unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains("class=\"pv-field pv-field--src-port pv-colored\""));
+        assert!(result.contains("fill=\"#FF0000\""));
+
+        descriptor.style.css_classes = true;
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(".pv-field.pv-color-0 { fill: #FF0000; }"));
+        assert!(result.contains("class=\"pv-field pv-field--src-port pv-colored pv-color-0\""));
+        assert!(result.contains("class=\"pv-field pv-field-dynamic pv-field--data\""));
+        assert!(!result.contains("style=\"stroke"));
+    }
+
+    #[test]
+    fn test_render_css_classes_colors() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r##"{
+                "style": {"css_classes": true},
+                "fields": [
+                    {"name": "Src Port", "length": 8, "color": "#ff0000"},
+                    {"name": "src-port", "length": 8, "color": "#00ff00"},
+                    {"name": "???", "length": 8, "color": "#0000ff"},
+                    {"name": "dst port", "length": 8, "color": "#ff0000"}
+                ]
+            }"##,
+        )
+        .unwrap();
+
+        // Fields with the same name class keep their own color, and fields without one get theirs
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(".pv-field.pv-color-0 { fill: #FF0000; }"));
+        assert!(result.contains(".pv-field.pv-color-1 { fill: #00FF00; }"));
+        assert!(result.contains(".pv-field.pv-color-2 { fill: #0000FF; }"));
+        assert!(!result.contains("pv-color-3"));
+        assert!(result.contains("class=\"pv-field pv-field--src-port pv-colored pv-color-1\""));
+        assert!(result.contains("class=\"pv-field pv-colored pv-color-2\""));
+        assert!(result.contains("class=\"pv-field pv-field--dst-port pv-colored pv-color-0\""));
+    }
+
+    #[test]
+    fn test_render_patterns() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{"fields": [
+                {"name": "reserved", "length": 2, "pattern": "hatched"},
+                {"name": "flags", "length": 1},
+                {"name": "padding", "length": "N", "pattern": "hatched"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert_eq!(result.matches("<pattern ").count(), 1);
+        assert_eq!(result.matches("fill=\"url(#pv-pattern-0)\"").count(), 3);
+    }
+
+    #[test]
+    fn test_render_optional() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{"fields": [
+                {"name": "header", "length": 2},
+                {"name": "options", "length": 4, "condition": "flags & OPT"},
+                {"name": "data", "length": 1}
+            ]}"#,
+        )
+        .unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert_eq!(result.matches("stroke-dasharray").count(), 1);
+        assert!(result.contains(">flags &amp; OPT</text>"));
+        assert!(result.contains(">6 / 2</text>"));
+    }
+
+    #[test]
+    fn test_render_repeat() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{"fields": [
+                {"name": "count", "length": 1},
+                {"name": "entry", "length": 4, "repeat": "N"},
+                {"name": "data", "length": 2}
+            ]}"#,
+        )
+        .unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">×N</text>"));
+        assert!(result.contains(">4N</text>"));
+        assert!(result.contains(">1+4N</text>"));
+    }
+
+    #[test]
+    fn test_repeat_length() {
+        use descriptor::FieldLength::{Fixed, Variable};
+
+        assert_eq!(Fixed(4).repeat(&Fixed(3)).unwrap(), Fixed(12));
+        assert_eq!(
+            Fixed(4).repeat(&Variable("N".to_string())).unwrap(),
+            Variable("4N".to_string())
+        );
+        assert_eq!(
+            Variable("2L".to_string()).repeat(&Fixed(3)).unwrap(),
+            Variable("6L".to_string())
+        );
+        assert_eq!(
+            Fixed(1).repeat(&Variable("N".to_string())).unwrap(),
+            Variable("N".to_string())
+        );
+        assert_eq!(
+            Variable("L".to_string())
+                .repeat(&Variable("N".to_string()))
+                .unwrap(),
+            Variable("N×L".to_string())
+        );
+
+        // Lengths that don't fit are errors instead of panics
+        assert!(Fixed(usize::MAX).repeat(&Fixed(2)).is_err());
+        assert!(Fixed(usize::MAX).add(&Fixed(1)).is_err());
+        assert!(Variable("99999999999999999999".to_string())
+            .terms()
+            .is_err());
+        assert!(Variable("99999999999999999999N".to_string())
+            .terms()
+            .is_err());
+
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{"fields": [{"name": "items", "length": 8, "repeat": 9223372036854775807}]}"#,
+        )
+        .unwrap();
+        assert!(matches!(render(&descriptor), Err(Error::FormatError(_))));
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{"fields": [{"name": "data", "length": "99999999999999999999"}]}"#,
+        )
+        .unwrap();
+        assert!(matches!(render(&descriptor), Err(Error::FormatError(_))));
+        assert!(
+            import::c::from_c_header("struct big { uint64_t x[0xFFFFFFFFFFFFFFFF]; };", "big")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_render_structures() {
+        let mut descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "definitions": {
+                    "Option": [
+                        {"name": "type", "length": 1},
+                        {"name": "len", "length": 1},
+                        {"name": "value", "length": "L"}
+                    ]
+                },
+                "fields": [
+                    {"name": "header", "length": 2},
+                    {"name": "option", "struct": "Option"},
+                    {"name": "data", "length": 1}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">2+L</text>"));
+        assert!(!result.contains("pv-group"));
+
+        descriptor.fields[1].expand = true;
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains("class=\"pv-group\""));
+        assert!(result.contains(">value</text>"));
+
+        descriptor.fields[1].structure = Some("Unknown".to_string());
+        assert!(matches!(render(&descriptor), Err(Error::FormatError(_))));
+    }
+
+    #[test]
+    fn test_recursive_structure() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "definitions": {"Node": [{"name": "next", "struct": "Node"}]},
+                "fields": [{"name": "root", "struct": "Node"}]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(render(&descriptor), Err(Error::FormatError(_))));
+    }
+
+    #[test]
+    fn test_render_groups() {
+        let mut descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "groups": [
+                    {"name": "Header", "start": 0, "end": "flags"},
+                    {"name": "Checksummed", "start": "flags", "end": 3, "position": "below"}
+                ],
+                "fields": [
+                    {"name": "type", "length": 1},
+                    {"name": "flags", "length": 1, "wrap": true},
+                    {"name": "payload", "length": "N"},
+                    {"name": "checksum", "length": 2}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">Header</text>"));
+        assert!(result.contains(">Checksummed</text>"));
+        // A segment for the header, one per row for the checksummed region, plus both labels
+        assert_eq!(result.matches("class=\"pv-group\"").count(), 3 + 2);
+
+        descriptor.groups[0].end = descriptor::FieldRef::Name("unknown".to_string());
+        assert!(matches!(render(&descriptor), Err(Error::FormatError(_))));
+    }
+
+    #[test]
+    fn test_render_stack() {
+        let mut descriptor: descriptor::StackDescriptor = serde_json::from_str(
+            r#"{
+                "layers": [
+                    {
+                        "payload": "payload",
+                        "style": {"css_classes": true},
+                        "fields": [
+                            {"name": "header", "length": 4},
+                            {"name": "payload", "length": "N", "pattern": "hatched"}
+                        ]
+                    },
+                    {
+                        "payload": 1,
+                        "fields": [
+                            {"name": "type", "length": 1},
+                            {"name": "data", "length": "L", "pattern": "dotted"}
+                        ]
+                    },
+                    {"fields": [{"name": "value", "length": 2}]}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let result = render_stack(&descriptor).unwrap();
+        assert!(result.contains("class=\"pv-diagram pv-layer-2\""));
+        assert!(result.contains(".pv-layer-0 .pv-field {"));
+        // Each layer has its own pattern definitions
+        assert!(result.contains("id=\"pv-layer-0-pattern-0\""));
+        assert!(result.contains("id=\"pv-layer-1-pattern-0\""));
+        // Two connector lines between each pair of layers
+        assert_eq!(result.matches("class=\"pv-connector\"").count(), 4);
+
+        descriptor.layers[1].payload = Some(descriptor::FieldRef::Name("unknown".to_string()));
+        assert!(matches!(
+            render_stack(&descriptor),
+            Err(Error::FormatError(_))
+        ));
+    }
+
+    #[test]
+    fn test_render_alternatives() {
+        let mut descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "type", "length": 1, "wrap": true},
+                    {"name": "rest", "alternatives": [
+                        {"name": "type = 0", "fields": [
+                            {"name": "id", "length": 2},
+                            {"name": "seq", "length": 2}
+                        ]},
+                        {"name": "type = 5", "fields": [{"name": "gateway", "length": 4}]}
+                    ]}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">type = 0</text>"));
+        assert!(result.contains(">type = 5</text>"));
+        assert_eq!(result.matches("pv-variant\"").count(), 3 + 2);
+        // The field length is derived from its alternatives
+        assert!(result.contains(">4</text>"));
+
+        descriptor.fields[1].alternatives[1].fields[0].length = descriptor::FieldLength::Fixed(2);
+        assert!(matches!(render(&descriptor), Err(Error::FormatError(_))));
+    }
+
+    #[test]
+    fn test_render_byte_order() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "header", "length": 2, "endianness": "le"},
+                    {"name": "payload", "length": 4, "endianness": "big", "bit_order": "lsb-first"},
+                    {"name": "crc", "length": 2}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let [header, payload, crc] = &descriptor.fields[..] else {
+            panic!("Unexpected number of fields");
+        };
+        assert_eq!(
+            header.effective_endianness(&descriptor.elements),
+            descriptor::Endianness::Little
+        );
+        // Fields without an explicit order follow the protocol order
+        assert_eq!(
+            crc.effective_endianness(&descriptor.elements),
+            descriptor::Endianness::Big
+        );
+        assert_eq!(
+            payload.effective_bit_order(&descriptor.elements),
+            descriptor::BitOrder::LsbFirst
+        );
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">LE</text>"));
+        assert!(result.contains(">BE</text>"));
+        assert!(result.contains(">LSB→</text>"));
+        assert_eq!(result.matches("<rect").count(), 3);
+    }
+
+    #[test]
+    fn test_render_bit_numbering() {
+        let mut descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "elements": {"bit_numbering": "lsb0"},
+                "fields": [
+                    {"name": "version", "length": 4},
+                    {"name": "flags", "length": 3},
+                    {"name": "ack", "length": 1}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">[7:4]</text>"));
+        assert!(result.contains(">[3:1]</text>"));
+        assert!(result.contains(">[0]</text>"));
+
+        descriptor.elements.bit_numbering = Some(descriptor::BitNumbering::Msb0);
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">[0:3]</text>"));
+        assert!(result.contains(">[7]</text>"));
+
+        descriptor.fields[1].length = descriptor::FieldLength::Variable("N".to_string());
+        assert!(matches!(render(&descriptor), Err(Error::FormatError(_))));
+    }
+
+    #[test]
+    fn test_render_register() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "elements": {"register": true},
+                "fields": [
+                    {"name": "MODE", "length": 4, "access": "rw", "reset": 3},
+                    {"name": "STATUS", "length": 4, "access": "W1C", "reset": "undefined"},
+                    {"name": "ID", "length": 8, "access": "ro"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            descriptor.elements.bit_numbering(),
+            Some(descriptor::BitNumbering::Lsb0)
+        );
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">[15:12]</text>"));
+        assert!(result.contains(">[7:0]</text>"));
+        assert!(result.contains(">RW · 0x3</text>"));
+        assert!(result.contains(">W1C · undefined</text>"));
+        assert!(result.contains("class=\"pv-access pv-access--ro\""));
+    }
+
+    #[cfg(feature = "svd")]
+    #[test]
+    fn test_import_svd() {
+        let registers = import::svd::from_svd(
+            r#"<device>
+                <name>MCU</name>
+                <size>32</size>
+                <access>read-write</access>
+                <peripherals>
+                    <peripheral>
+                        <name>UART0</name>
+                        <registers>
+                            <register>
+                                <name>CTRL</name>
+                                <description>Control register</description>
+                                <resetValue>0x00030001</resetValue>
+                                <fields>
+                                    <field><name>EN</name><bitOffset>0</bitOffset><bitWidth>1</bitWidth></field>
+                                    <field><name>BAUD</name><bitRange>[19:12]</bitRange></field>
+                                    <field>
+                                        <name>ERR</name><lsb>31</lsb><msb>31</msb>
+                                        <modifiedWriteValues>oneToClear</modifiedWriteValues>
+                                    </field>
+                                </fields>
+                            </register>
+                        </registers>
+                    </peripheral>
+                    <peripheral derivedFrom="UART0"><name>UART1</name></peripheral>
+                </peripherals>
+            </device>"#,
+        )
+        .unwrap();
+
+        assert_eq!(registers.len(), 2);
+        assert_eq!(registers[1].peripheral, "UART1");
+
+        let fields = &registers[0].descriptor.fields;
+        let names = fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+        // BAUD crosses the boundary between the two rows of 16 bits
+        assert_eq!(
+            names,
+            [
+                "ERR",
+                "Reserved",
+                "BAUD[7:4]",
+                "BAUD[3:0]",
+                "Reserved",
+                "EN"
+            ]
+        );
+        assert_eq!(fields[0].access, Some(descriptor::Access::WriteOneToClear));
+        assert_eq!(fields[2].reset, Some(descriptor::ResetValue::Number(3)));
+        assert!(fields[2].wrap);
+
+        let result = render(&registers[0].descriptor).unwrap();
+        assert!(result.contains(">[31]</text>"));
+        assert!(result.contains(">[11:1]</text>"));
+
+        // Sizes and bit ranges are bounded
+        let register = |size: &str, field: &str| {
+            import::svd::from_svd(&format!(
+                "<device><name>MCU</name><size>{}</size><peripherals><peripheral><name>P</name><registers>\
+                 <register><name>R</name><fields><field><name>F</name>{}</field></fields></register>\
+                 </registers></peripheral></peripherals></device>",
+                size, field
+            ))
+        };
+        assert!(matches!(
+            register("0xFFFFFFFFFFFFFFFF", "<bitOffset>0</bitOffset>"),
+            Err(Error::FormatError(message))
+                if message == "Register R has a size of 18446744073709551615 bits, larger than 64 bits"
+        ));
+        assert!(matches!(
+            register("32", "<bitOffset>0xFFFFFFFFFFFFFFFF</bitOffset><bitWidth>2</bitWidth>"),
+            Err(Error::FormatError(message))
+                if message == "Field bit offset 18446744073709551615 and width 2 overflow"
+        ));
+    }
+
+    #[cfg(feature = "kaitai")]
+    #[test]
+    fn test_import_kaitai() {
+        let descriptor = import::kaitai::from_ksy(
+            r#"
+meta:
+  id: packet
+  endian: le
+seq:
+  - id: magic
+    contents: [0x50, 0x4b]
+  - id: len_body
+    type: u2
+    doc: Length of the body
+  - id: kind
+    type: u1
+  - id: checksum
+    type: u4be
+  - id: header
+    type: header
+    if: kind == 1
+  - id: body
+    size: len_body
+  - id: entries
+    type: entry
+    repeat: expr
+    repeat-expr: 3
+  - id: value
+    type:
+      switch-on: kind
+      cases:
+        0: u1
+        1: u4
+types:
+  header:
+    seq:
+      - id: version
+        type: u1
+      - id: flags
+        type: u1
+  entry:
+    seq:
+      - id: key
+        type: u2
+"#,
+        )
+        .unwrap();
+
+        assert!(!descriptor.elements.network_order);
+        assert_eq!(descriptor.definitions.len(), 2);
+
+        let fields = &descriptor.fields;
+        assert_eq!(fields[0].length, descriptor::FieldLength::Fixed(2));
+        assert_eq!(fields[1].description.as_deref(), Some("Length of the body"));
+        // Only the types overriding the default endianness are marked
+        assert_eq!(fields[1].endianness, None);
+        assert_eq!(fields[3].endianness, Some(descriptor::Endianness::Big));
+        assert_eq!(fields[4].structure.as_deref(), Some("header"));
+        assert_eq!(fields[4].condition.as_deref(), Some("kind == 1"));
+        assert_eq!(
+            fields[5].length,
+            descriptor::FieldLength::Variable("len_body".to_string())
+        );
+        assert_eq!(fields[6].repeat, Some(descriptor::FieldLength::Fixed(3)));
+        assert_eq!(fields[7].alternatives[1].name, "kind == 1");
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">kind == 0</text>"));
+    }
+
+    #[test]
+    fn test_import_c_header() {
+        let header = r#"
+#include <stdint.h>
+
+#define MAC_LEN 6
+
+struct address {
+    uint8_t mac[MAC_LEN];
+    uint16_t vlan;
+};
+
+#pragma pack(push, 1)
+typedef struct {
+    uint8_t version:4;  /* Protocol version */
+    uint8_t flags:3;
+    uint8_t :1;
+    uint32_t sequence;
+    struct address source;
+    /** Either a counter or a timestamp */
+    union {
+        uint16_t counter;
+        uint32_t timestamp;
+    } value;
+    const char *name;
+    uint8_t data[];
+} __attribute__((packed)) packet_t;
+#pragma pack(pop)
+
+struct aligned {
+    uint8_t kind;
+    uint32_t length;
+};
+"#;
+
+        let descriptor = import::c::from_c_header(header, "packet_t").unwrap();
+
+        // The bitfields make the lengths in bits
+        let fields = &descriptor.fields;
+        assert_eq!(fields[0].length, descriptor::FieldLength::Fixed(4));
+        assert_eq!(fields[0].description.as_deref(), Some("Protocol version"));
+        assert_eq!(fields[1].length, descriptor::FieldLength::Fixed(3));
+        assert_eq!(fields[2].pattern, Some(descriptor::FillPattern::Hatched));
+        assert_eq!(fields[3].length, descriptor::FieldLength::Fixed(32));
+        assert_eq!(fields[4].structure.as_deref(), Some("address"));
+        assert_eq!(fields[5].alternatives.len(), 2);
+        assert_eq!(fields[5].length, descriptor::FieldLength::Fixed(32));
+        assert_eq!(
+            fields[5].description.as_deref(),
+            Some("Either a counter or a timestamp")
+        );
+        assert_eq!(
+            fields[6].length,
+            descriptor::FieldLength::Variable("sizeof(void*)".to_string())
+        );
+        assert_eq!(fields[7].repeat, None);
+        assert_eq!(
+            fields[7].length,
+            descriptor::FieldLength::Variable("8len_data".to_string())
+        );
+
+        let address = &descriptor.definitions["address"];
+        assert_eq!(address[0].length, descriptor::FieldLength::Fixed(48));
+
+        // Structs without packing are padded to the alignment of their members, and the lengths are in bytes
+        // unless the imported structs have bitfields
+        let aligned = import::c::from_c_header(header, "aligned").unwrap();
+        assert_eq!(aligned.fields.len(), 3);
+        assert_eq!(aligned.fields[1].name, "padding");
+        assert_eq!(aligned.fields[1].length, descriptor::FieldLength::Fixed(3));
+
+        // Bitfields need a type with a size
+        assert!(matches!(
+            import::c::from_c_header("struct e {}; struct a { struct e x : 3; };", "a"),
+            Err(Error::FormatError(message)) if message == "Bitfield x of struct a has an empty type"
+        ));
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">timestamp</text>"));
+    }
+
+    #[test]
+    fn test_import_c_forward_typedef() {
+        let header = r#"
+typedef struct node node_t;
+static const float SCALE = 1.5f;
+static const double LIMIT = 1e-3;
+
+struct node {
+    node_t *next;
+    uint16_t value;
+};
+
+struct list {
+    node_t head;
+    uint8_t count;
+};
+"#;
+
+        // Typedefs of structs defined later resolve through their tag
+        let descriptor = import::c::from_c_header(header, "node_t").unwrap();
+        assert_eq!(descriptor.fields[1].name, "value");
+        assert_eq!(
+            descriptor.fields[1].length,
+            descriptor::FieldLength::Fixed(2)
+        );
+
+        // Float constants only fail where an integer is expected
+        let list = import::c::from_c_header(header, "list").unwrap();
+        assert_eq!(list.fields[0].structure.as_deref(), Some("node"));
+        let header = format!("{}struct bad {{ uint8_t weights[2.0]; }};", header);
+        assert!(matches!(
+            import::c::from_c_header(&header, "list"),
+            Err(Error::FormatError(message)) if message == "Invalid number 2.0"
+        ));
+    }
+
+    #[test]
+    fn test_import_rfc_ascii() {
+        let descriptor = descriptor::ProtoDescriptor::from_rfc_ascii(
+            r#"
+    0                   1                   2                   3
+    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |          Source Port          |       Destination Port        |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |  Data |       |C|E|U|A|P|R|S|F|                               |
+   | Offset| Rsrvd |W|C|R|C|S|S|Y|I|            Window             |
+   |       |       |R|E|G|K|H|T|N|N|                               |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |                                                               |
+   +                         Source Address                        +
+   |                                                               |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   /                            Options                            /
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |     Type      |
+   +-+-+-+-+-+-+-+-+
+"#,
+        )
+        .unwrap();
+
+        let fields = &descriptor.fields;
+        let names = fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "Source Port",
+                "Destination Port",
+                "Data Offset",
+                "Rsrvd",
+                "CWR",
+                "ECE",
+                "URG",
+                "ACK",
+                "PSH",
+                "RST",
+                "SYN",
+                "FIN",
+                "Window",
+                "Source Address",
+                "Options",
+                "Type"
+            ]
+        );
+        assert_eq!(fields[2].length, descriptor::FieldLength::Fixed(4));
+        assert_eq!(fields[4].length, descriptor::FieldLength::Fixed(1));
+        // Fields spanning several rows keep their whole length
+        assert_eq!(fields[13].length, descriptor::FieldLength::Fixed(64));
+        assert_eq!(
+            fields[14].length,
+            descriptor::FieldLength::Variable("len_options".to_string())
+        );
+        assert!(fields[1].wrap && !fields[15].wrap);
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">Source Address</text>"));
+
+        // A missing closing border is an error, instead of dropping the last row
+        let truncated = descriptor::ProtoDescriptor::from_rfc_ascii(
+            r#"
+    0                   1                   2                   3
+    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |          Source Port          |       Destination Port        |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |                        Sequence Number                        |
+"#,
+        );
+        assert!(matches!(
+            truncated,
+            Err(Error::FormatError(message)) if message == "Diagram has no closing border"
+        ));
+
+        // Diagrams without bits have no fields
+        assert!(matches!(
+            descriptor::ProtoDescriptor::from_rfc_ascii("+--+\n|x |\n+--+"),
+            Err(Error::FormatError(message)) if message == "No fields found in the diagram"
+        ));
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive() {
+        #[derive(ProtoViz)]
+        #[repr(C, packed)]
+        #[allow(dead_code)]
+        struct Header {
+            /// Protocol version
+            #[protoviz(bits = 4, color = "#ff0")]
+            version: u8,
+            #[protoviz(bits = 4)]
+            flags: u8,
+            length: u16,
+            address: [u8; 6],
+            #[protoviz(variable = "N")]
+            payload: [u8; 0],
+        }
+
+        let descriptor = Header::descriptor();
+
+        // The bit-sized fields make the lengths in bits
+        let fields = &descriptor.fields;
+        assert_eq!(fields[0].length, descriptor::FieldLength::Fixed(4));
+        assert_eq!(fields[0].description.as_deref(), Some("Protocol version"));
+        assert_eq!(fields[0].color, Some(HexColor::rgb(255, 255, 0)));
+        assert_eq!(fields[2].length, descriptor::FieldLength::Fixed(16));
+        assert_eq!(fields[3].length, descriptor::FieldLength::Fixed(48));
+        assert_eq!(
+            fields[4].length,
+            descriptor::FieldLength::Variable("N".to_string())
+        );
+
+        let result = render(&descriptor).unwrap();
+        assert!(result.contains(">address</text>"));
+    }
+
+    #[test]
+    fn test_codegen_rust() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "elements": {"network_order": false},
+                "fields": [
+                    {"name": "Version", "length": 4},
+                    {"name": "flags", "length": 4},
+                    {"name": "length", "length": 16, "endianness": "be"},
+                    {"name": "ext", "length": 32, "condition": "flags & 1"},
+                    {"name": "entries", "struct": "entry", "repeat": 2},
+                    {"name": "body", "length": "length"}
+                ],
+                "definitions": {"entry": [{"name": "type", "length": 8}]}
+            }"#,
+        )
+        .unwrap();
+
+        let code = codegen::rust::to_rust(&descriptor, "header", codegen::Unit::Bits).unwrap();
+        assert!(code.contains("pub struct Header {"));
+        assert!(code.contains("pub struct Entry {"));
+        assert!(code.contains("pub r#type: u8,"));
+        // The fields follow the protocol order, unless they override it
+        assert!(code.contains("read_uint(input, offset, 4, true, true, \"Version\")? as u8"));
+        assert!(code.contains("read_uint(input, offset, 16, false, true, \"length\")? as u16"));
+        assert!(code.contains("let ext = if (flags & 1) != 0 {"));
+        assert!(code.contains("pub entries: Vec<Entry>,"));
+        assert!(code.contains("read_bytes(input, offset, length as usize, \"body\")?"));
+        // Only the helpers it calls are emitted
+        assert!(!code.contains("fn read_array"));
+
+        // The generated code builds without warnings
+        let dir = std::env::temp_dir().join(format!("protoviz-codegen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("header.rs");
+        std::fs::write(&source, &code).unwrap();
+        let output = std::process::Command::new(
+            std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()),
+        )
+        .args(["--edition", "2021", "--crate-type", "lib", "-D", "warnings"])
+        .arg("--out-dir")
+        .arg(&dir)
+        .arg(&source)
+        .output()
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        // Lengths have to come from previous fields
+        let mut descriptor = descriptor;
+        descriptor.fields[5].length = descriptor::FieldLength::Variable("N".to_string());
+        descriptor.fields.swap(4, 5);
+        assert!(codegen::rust::to_rust(&descriptor, "header", codegen::Unit::Bits).is_err());
+    }
+
+    #[test]
+    fn test_codegen_c() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "Version", "length": 4},
+                    {"name": "flags", "length": 4},
+                    {"name": "length", "length": 16},
+                    {"name": "ext", "length": 32, "condition": "flags & 1"},
+                    {"name": "body", "length": "length"},
+                    {"name": "crc", "length": 16, "endianness": "le"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let code = codegen::c::to_c(&descriptor, "header", codegen::Unit::Bits).unwrap();
+        assert!(code.contains("#ifndef HEADER_H"));
+        assert!(code.contains("#define HEADER_VERSION_MASK 0xf0u"));
+        assert!(code.contains("#define HEADER_FLAGS_SHIFT 0"));
+        assert!(code.contains("static inline uint16_t header_unpack_length(const uint8_t *buf)"));
+        // Fields after the optional and variable ones take them as parameters
+        assert!(code.contains("#define HEADER_BODY_OFFSET(ext_present) (3 + 4 * (ext_present))"));
+        assert!(code.contains(
+            "#define HEADER_CRC_OFFSET(ext_present, length) (3 + 4 * (ext_present) + (length) / 8)"
+        ));
+        assert!(code.contains(
+            "return (uint16_t)pv_unpack(buf, 24 + 32 * ext_present + length, 16, 1, 0);"
+        ));
+    }
+
+    #[test]
+    fn test_codegen_lua() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "Version", "length": 4},
+                    {"name": "flags", "length": 4},
+                    {"name": "length", "length": 16},
+                    {"name": "ext", "length": 32, "condition": "flags & 1"},
+                    {"name": "entries", "struct": "entry", "repeat": 2},
+                    {"name": "body", "length": "length", "description": "Payload"},
+                    {"name": "crc", "length": 16, "endianness": "le"}
+                ],
+                "definitions": {"entry": [{"name": "end", "length": 8}]}
+            }"#,
+        )
+        .unwrap();
+
+        let code = codegen::lua::to_lua(&descriptor, "header", codegen::Unit::Bits).unwrap();
+        assert!(code.contains("local proto = Proto(\"header\", \"header\")"));
+        assert!(code.contains(
+            "flags = ProtoField.uint8(\"header.flags\", \"flags\", base.DEC, nil, 0xf, nil),"
+        ));
+        assert!(code.contains("[\"end\"] = ProtoField.uint8(\"header.entry.end\""));
+        assert!(code.contains(
+            "body = ProtoField.bytes(\"header.body\", \"body\", base.NONE, \"Payload\"),"
+        ));
+        // The dissector walks the offsets, sizing the variable fields from their length field
+        assert!(code.contains("local length = buffer(offset, 2):uint()"));
+        assert!(code.contains("if bit.band(flags, 1) ~= 0 then"));
+        assert!(code.contains("offset = dissect_entry(buffer, offset, subtree)"));
+        assert!(code
+            .contains("subtree:add(header_fields.body, buffer(offset, math.floor(length / 8)))"));
+        assert!(code.contains("subtree:add_le(header_fields.crc, buffer(offset, 2))"));
+
+        // Optional fields have to start on a byte
+        let mut descriptor = descriptor;
+        descriptor.fields[0].length = descriptor::FieldLength::Fixed(2);
+        assert!(codegen::lua::to_lua(&descriptor, "header", codegen::Unit::Bits).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "version", "length": 4},
+                    {"name": "flags", "length": 4},
+                    {"name": "length", "length": 8},
+                    {"name": "port", "length": 16, "endianness": "le"},
+                    {"name": "ext", "length": 16, "condition": "flags & 2"},
+                    {"name": "entries", "struct": "entry", "repeat": 2},
+                    {"name": "body", "length": "length"},
+                    {"name": "trailer", "length": "eos"}
+                ],
+                "definitions": {"entry": [{"name": "key", "length": 8}]}
+            }"#,
+        )
+        .unwrap();
+
+        let data = [0x41, 0x10, 0x34, 0x12, 0x0a, 0x0b, 0xaa, 0xbb, 0xcc];
+        let fields = decode::decode(&descriptor, &data, codegen::Unit::Bits).unwrap();
+
+        // The absent field is skipped
+        let names = fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["version", "flags", "length", "port", "entries", "body", "trailer"]
+        );
+        assert_eq!(fields[0].value, Some(4));
+        assert_eq!(fields[1].bits, 4..8);
+        assert_eq!(fields[3].value, Some(0x1234));
+        assert_eq!(fields[4].children[1].children[0].value, Some(0x0b));
+        assert_eq!(fields[5].bytes(), 6..8);
+        assert_eq!(fields[5].raw, [0xaa, 0xbb]);
+        assert_eq!(fields[6].raw, [0xcc]);
+
+        let dump = decode::hex_dump(&fields);
+        assert!(dump.contains("000000  41                       flags = 1 (bits 4-7)"));
+        assert!(dump.contains("000002  34 12                    port = 4660 (0x1234)"));
+
+        // Truncated data and unknown lengths are reported
+        assert!(decode::decode(&descriptor, &data[..5], codegen::Unit::Bits).is_err());
+        let mut descriptor = descriptor;
+        descriptor.fields[6].length = descriptor::FieldLength::Variable("size".to_string());
+        assert!(decode::decode(&descriptor, &data, codegen::Unit::Bits).is_err());
+    }
+
+    #[test]
+    fn test_decode_oversized_length() {
+        let parse =
+            |json: &str| -> descriptor::ProtoDescriptor { serde_json::from_str(json).unwrap() };
+        let is_error = |result: Result<Vec<decode::DecodedField>, Error>, expected: &str| matches!(result, Err(Error::FormatError(message)) if message == expected);
+
+        let descriptor = parse(
+            r#"{"fields": [{"name": "len", "length": 8}, {"name": "body", "length": "len"}]}"#,
+        );
+        assert!(is_error(
+            decode::decode(&descriptor, &[0xff; 8], codegen::Unit::Bytes),
+            "Length len of field body overflows"
+        ));
+
+        let descriptor = parse(
+            r#"{"fields": [{"name": "len", "length": 64}, {"name": "body", "length": "len+1"}]}"#,
+        );
+        assert!(is_error(
+            decode::decode(&descriptor, &[0xff; 8], codegen::Unit::Bits),
+            "Length len+1 of field body overflows"
+        ));
+
+        // Numeric lengths too large for the platform are errors as well
+        let descriptor =
+            parse(r#"{"fields": [{"name": "body", "length": "99999999999999999999"}]}"#);
+        assert!(is_error(
+            decode::decode(&descriptor, &[0xff; 8], codegen::Unit::Bits),
+            "Length 99999999999999999999 is too large"
+        ));
+        let descriptor = parse(r#"{"fields": [{"name": "body", "length": 18446744073709551615}]}"#);
+        assert!(is_error(
+            decode::decode(&descriptor, &[0xff; 8], codegen::Unit::Bytes),
+            "Length 18446744073709551615 of field body overflows"
+        ));
+
+        // Huge counts fail before decoding the occurrences
+        let descriptor = parse(
+            r#"{
+                "fields": [
+                    {"name": "count", "length": 32},
+                    {"name": "items", "length": 8, "repeat": "count"},
+                    {"name": "trailer", "length": 8}
+                ]
+            }"#,
+        );
+        assert!(is_error(
+            decode::decode(&descriptor, &[0xff; 6], codegen::Unit::Bits),
+            "Repeat count 4294967295 of field items exceeds the data left"
+        ));
+    }
+
+    #[test]
+    fn test_pcap() {
+        // Little endian pcap with microsecond timestamps, holding a packet of 6 bytes
+        let mut capture = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        capture.extend([0; 8]);
+        capture.extend([0xff, 0xff, 0, 0, 1, 0, 0, 0]);
+        capture.extend([10, 0, 0, 0, 0x20, 0xa1, 0x07, 0, 6, 0, 0, 0, 60, 0, 0, 0]);
+        capture.extend([0xaa, 0xbb, 0x21, 0x08, 0xcc, 0xdd]);
+
+        let packets = pcap::read_capture(&capture).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            (packets[0].seconds, packets[0].nanoseconds),
+            (10, 500_000_000)
+        );
+        assert_eq!((packets[0].link_type, packets[0].length), (1, 60));
+        assert_eq!(packets[0].data, [0xaa, 0xbb, 0x21, 0x08, 0xcc, 0xdd]);
+
+        // Big endian pcapng with an enhanced packet block, in nanoseconds
+        let mut capture = vec![0x0a, 0x0d, 0x0d, 0x0a, 0, 0, 0, 28, 0x1a, 0x2b, 0x3c, 0x4d];
+        capture.extend([
+            0, 1, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 28,
+        ]);
+        capture.extend([0, 0, 0, 1, 0, 0, 0, 28, 0, 1, 0, 0, 0, 0, 0, 0]);
+        capture.extend([0, 9, 0, 1, 9, 0, 0, 0, 0, 0, 0, 28]);
+        capture.extend([0, 0, 0, 6, 0, 0, 0, 40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        capture.extend([0x3b, 0x9a, 0xca, 0x07, 0, 0, 0, 6, 0, 0, 0, 6]);
+        capture.extend([0xaa, 0xbb, 0x21, 0x08, 0xcc, 0xdd, 0, 0, 0, 0, 0, 40]);
+
+        let packets = pcap::read_capture(&capture).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!((packets[0].seconds, packets[0].nanoseconds), (1, 7));
+        assert_eq!(packets[0].data, [0xaa, 0xbb, 0x21, 0x08, 0xcc, 0xdd]);
+
+        // The payload is rendered with its values
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "version", "length": 4},
+                    {"name": "kind", "length": 4},
+                    {"name": "extra", "length": 8, "condition": "kind == 2"},
+                    {"name": "length", "length": 8},
+                    {"name": "data", "length": "length"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let payload = &packets[0].data[2..];
+        assert!(decode::decode(&descriptor, payload, codegen::Unit::Bytes).is_err());
+        let fields = decode::decode(&descriptor, payload, codegen::Unit::Bits).unwrap();
+        let annotated = decode::annotate(&descriptor, &fields, codegen::Unit::Bits);
+        let names = annotated
+            .fields
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["version = 2", "kind = 1", "length = 8", "data = 0xcc"]
+        );
+        assert!(render(&annotated).is_ok());
+    }
+
+    #[test]
+    fn test_diff() {
+        let old: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "version", "length": 8},
+                    {"name": "length", "length": 16},
+                    {"name": "reserved", "length": 8},
+                    {"name": "type", "length": 8},
+                    {"name": "body", "length": "length"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let new: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "version", "length": 8},
+                    {"name": "type", "length": 8},
+                    {"name": "length", "length": 32},
+                    {"name": "session", "length": 16},
+                    {"name": "body", "length": "length"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let diffs = diff::diff(&old, &new).unwrap();
+        let changes = diffs
+            .iter()
+            .map(|d| (d.name.as_str(), d.change))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                ("version", diff::Change::Unchanged),
+                ("reserved", diff::Change::Removed),
+                ("type", diff::Change::Unchanged),
+                ("length", diff::Change::Moved),
+                ("session", diff::Change::Added),
+                ("body", diff::Change::Unchanged),
+            ]
+        );
+        assert_eq!(diffs[2].shift(), Some(-24));
+        assert_eq!(diffs[5].shift(), Some(24));
+
+        let summary = diff::summary(&diffs);
+        assert!(summary.contains("> moved     length, length 16 -> 32, offset 8 -> 16 (+8)"));
+        assert!(summary.contains("  unchanged body, length length, offset 40 -> 64 (+24)"));
+
+        let descriptor = diff::diff_descriptor(&old, &new).unwrap();
+        assert_eq!(descriptor.fields[3].name, "length (moved, was 16)");
+        assert_eq!(
+            descriptor.fields[1].name,
+            "r\u{336}e\u{336}s\u{336}e\u{336}r\u{336}v\u{336}e\u{336}d\u{336}"
+        );
+        assert!(descriptor.fields[1].optional);
+        assert_eq!(
+            descriptor.fields[4].color,
+            Some(HexColor::rgb(0xb7, 0xe1, 0xa1))
+        );
+        assert!(render(&descriptor).is_ok());
+    }
+
+    #[test]
+    fn test_compat() {
+        let parse = |fields: &str| -> descriptor::ProtoDescriptor {
+            serde_json::from_str(&format!(r#"{{"fields": [{}]}}"#, fields)).unwrap()
+        };
+        let old = parse(
+            r#"{"name": "version", "length": 4},
+               {"name": "flags", "length": 3},
+               {"name": "reserved", "length": 9},
+               {"name": "length", "length": 16}"#,
+        );
+
+        let compatible = parse(
+            r#"{"name": "version", "length": 4},
+               {"name": "flags", "length": 3},
+               {"name": "ack", "length": 1},
+               {"name": "reserved", "length": 8},
+               {"name": "length", "length": 16},
+               {"name": "checksum", "length": 16}"#,
+        );
+        let report = compat::check(&old, &compatible).unwrap();
+        let rules = report
+            .findings
+            .iter()
+            .map(|f| (f.field.as_str(), f.rule.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            [
+                ("ack", "reserved-used"),
+                ("reserved", "reserved-changed"),
+                ("checksum", "field-appended"),
+            ]
+        );
+        assert!(!report.is_breaking());
+
+        let breaking = parse(
+            r#"{"name": "version", "length": 4},
+               {"name": "flags", "length": 3},
+               {"name": "reserved", "length": 9},
+               {"name": "type", "length": 8},
+               {"name": "length", "length": 16, "endianness": "little"}"#,
+        );
+        let report = compat::check(&old, &breaking).unwrap();
+        let rules = report
+            .findings
+            .iter()
+            .map(|f| (f.field.as_str(), f.rule.as_str(), f.compatibility))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            [
+                ("type", "field-inserted", compat::Compatibility::Breaking),
+                ("length", "field-shifted", compat::Compatibility::Breaking),
+                (
+                    "length",
+                    "encoding-changed",
+                    compat::Compatibility::Breaking
+                ),
+            ]
+        );
+        assert!(report.is_breaking());
+        assert!(serde_json::to_string(&report)
+            .unwrap()
+            .starts_with(r#"{"compatibility":"breaking","findings":[{"rule":"field-inserted""#));
+    }
+
+    #[test]
+    fn test_lint() {
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{
+                "fields": [
+                    {"name": "version", "length": 4},
+                    {"name": "flags", "length": 3},
+                    {"name": "length", "length": 16},
+                    {"name": "flags", "length": 1},
+                    {"name": "options", "length": "optlen"},
+                    {"name": "payload", "length": "length", "wrap": true}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let lints = lint::lint(
+            &descriptor,
+            codegen::Unit::Bits,
+            &lint::LintConfig::default(),
+        )
+        .unwrap();
+        let rules = lints
+            .iter()
+            .map(|l| (l.rule.id(), l.level, l.field.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            [
+                ("undefined-length", lint::Level::Deny, "options"),
+                ("unaligned-field", lint::Level::Warn, "length"),
+                ("trailing-wrap", lint::Level::Warn, "payload"),
+                ("duplicate-name", lint::Level::Deny, "flags"),
+            ]
+        );
+
+        let config = lint::LintConfig::default()
+            .set("unaligned-field".parse().unwrap(), lint::Level::Allow)
+            .set(lint::Rule::TrailingWrap, lint::Level::Deny);
+        let lints = lint::lint(&descriptor, codegen::Unit::Bits, &config).unwrap();
+        assert_eq!(lints.len(), 3);
+        assert_eq!(lints[1].level, lint::Level::Deny);
+        assert!("unknown".parse::<lint::Rule>().is_err());
+
+        let descriptor: descriptor::ProtoDescriptor = serde_json::from_str(
+            r#"{"fields": [{"name": "type", "length": 1}, {"name": "length", "length": 2}]}"#,
+        )
+        .unwrap();
+        let lints = lint::lint(
+            &descriptor,
+            codegen::Unit::Bytes,
+            &lint::LintConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(lints[0].rule, lint::Rule::UnalignedField);
+        assert_eq!(
+            lints[1].message,
+            "Header length of 24 bits isn't a multiple of 32 bits"
+        );
+    }
 }
@@ -0,0 +1,286 @@
+//! Linter flagging suspicious descriptors, with rules that can be allowed, warned about or denied
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::codegen::Unit;
+use crate::descriptor::{FieldDescriptor, FieldLength, ProtoDescriptor};
+use crate::errors::Error;
+
+/// Enum to hold the lint rules
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    UndefinedLength, // Variable length or repeat count referring to no field
+    UnalignedField,  // Multi-byte field not aligned on its size
+    UnalignedTotal,  // Header length not a multiple of 32 bits
+    TrailingWrap,    // Wrap on the last field, which has no row after it
+    DuplicateName,   // Several fields with the same name at the same level
+}
+
+impl Rule {
+    /// All the rules, in the order they are checked
+    pub const ALL: [Rule; 5] = [
+        Rule::UndefinedLength,
+        Rule::UnalignedField,
+        Rule::UnalignedTotal,
+        Rule::TrailingWrap,
+        Rule::DuplicateName,
+    ];
+
+    /// Identifier of the rule, used to configure its level
+    pub fn id(&self) -> &'static str {
+        match self {
+            Rule::UndefinedLength => "undefined-length",
+            Rule::UnalignedField => "unaligned-field",
+            Rule::UnalignedTotal => "unaligned-total",
+            Rule::TrailingWrap => "trailing-wrap",
+            Rule::DuplicateName => "duplicate-name",
+        }
+    }
+
+    /// Level of the rule when it isn't configured, denying the ones that break the field references
+    pub fn default_level(&self) -> Level {
+        match self {
+            Rule::UndefinedLength | Rule::DuplicateName => Level::Deny,
+            _ => Level::Warn,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        Rule::ALL
+            .into_iter()
+            .find(|rule| rule.id() == id)
+            .ok_or_else(|| Error::FormatError(format!("Unknown lint rule {}", id)))
+    }
+}
+
+/// Enum to hold what happens when a rule matches
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Allow, // Not reported
+    Warn,  // Reported
+    Deny,  // Reported as an error
+}
+
+/// Struct to hold the level of the rules, falling back to their default one
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct LintConfig {
+    #[serde(flatten)]
+    pub levels: BTreeMap<Rule, Level>, // Levels of the configured rules
+}
+
+impl LintConfig {
+    /// Set the level of a rule
+    pub fn set(mut self, rule: Rule, level: Level) -> Self {
+        self.levels.insert(rule, level);
+        self
+    }
+
+    /// Level of a rule, as configured or by default
+    pub fn level(&self, rule: Rule) -> Level {
+        self.levels
+            .get(&rule)
+            .copied()
+            .unwrap_or_else(|| rule.default_level())
+    }
+}
+
+/// Struct to hold a problem found in a descriptor
+#[derive(Debug, Serialize, Clone)]
+pub struct Lint {
+    pub rule: Rule,
+    pub level: Level, // Warn or deny, as allowed rules aren't reported
+    pub field: String,
+    pub message: String,
+}
+
+/// Check a descriptor against the lint rules, with its lengths in the given unit
+///
+/// Returns the problems of the rules that aren't allowed. The alignment rules only look at the fields with a fixed
+/// offset, and the header length is the one of the whole protocol, or of the fields before a trailing variable one
+/// (such as a payload).
+pub fn lint(
+    descriptor: &ProtoDescriptor,
+    unit: Unit,
+    config: &LintConfig,
+) -> Result<Vec<Lint>, Error> {
+    let fields = descriptor.flatten()?.fields;
+    let mut lints = Vec::new();
+    let mut report = |rule: Rule, field: &str, message: String| {
+        let level = config.level(rule);
+        if level != Level::Allow {
+            lints.push(Lint {
+                rule,
+                level,
+                field: field.to_owned(),
+                message,
+            });
+        }
+    };
+
+    // Variable lengths and repeat counts refer to fields, at any level
+    let mut names = Vec::new();
+    collect_names(&fields, &mut names);
+    let mut undefined = Vec::new();
+    check_lengths(&fields, &names, &mut undefined)?;
+    for (field, symbol) in undefined {
+        report(
+            Rule::UndefinedLength,
+            &field,
+            format!(
+                "Length of field {} refers to {}, which no field defines",
+                field, symbol
+            ),
+        );
+    }
+
+    let bits = |length: usize| {
+        length
+            .checked_mul(unit.bits())
+            .ok_or_else(|| Error::FormatError(format!("Length {} overflows", length)))
+    };
+
+    let mut lengths = Vec::new();
+    let mut offset = FieldLength::Fixed(0);
+    for field in &fields {
+        let length = field.total_length()?;
+        if let (FieldLength::Fixed(offset), FieldLength::Fixed(length)) = (&offset, &length) {
+            let (offset, bits) = (bits(*offset)?, bits(*length)?);
+            // Integers of 2, 4 and 8 bytes are aligned on their size, and longer fields on bytes
+            let alignment = match bits {
+                16 | 32 | 64 if field.repeat.is_none() => bits,
+                _ => 8,
+            };
+            if bits > 8 && !offset.is_multiple_of(alignment) {
+                report(
+                    Rule::UnalignedField,
+                    &field.name,
+                    format!(
+                        "Field {} of {} bits starts at bit {}, which isn't aligned on {} bits",
+                        field.name, bits, offset, alignment
+                    ),
+                );
+            }
+        }
+        offset = offset.add(&length)?;
+        lengths.push(length);
+    }
+
+    // The header stops before a trailing variable field, such as a payload
+    let header = match lengths.split_last() {
+        Some((FieldLength::Variable(_), rest)) => rest
+            .iter()
+            .try_fold(FieldLength::Fixed(0), |offset, length| offset.add(length))?,
+        _ => offset,
+    };
+    if let FieldLength::Fixed(length) = header {
+        let length = bits(length)?;
+        if length > 0 && !length.is_multiple_of(32) {
+            report(
+                Rule::UnalignedTotal,
+                "",
+                format!(
+                    "Header length of {} bits isn't a multiple of 32 bits",
+                    length
+                ),
+            );
+        }
+    }
+
+    if let Some(last) = fields.last().filter(|field| field.wrap) {
+        report(
+            Rule::TrailingWrap,
+            &last.name,
+            format!(
+                "Field {} wraps, but it is the last field so it has no effect",
+                last.name
+            ),
+        );
+    }
+
+    // Names are unique among the fields of the protocol, of each definition and of each alternative
+    let mut duplicates = Vec::new();
+    check_names(&descriptor.fields, &mut duplicates);
+    for fields in descriptor.definitions.values() {
+        check_names(fields, &mut duplicates);
+    }
+    for (name, count) in duplicates {
+        report(
+            Rule::DuplicateName,
+            &name,
+            format!("Field name {} is used by {} fields", name, count),
+        );
+    }
+
+    Ok(lints)
+}
+
+/// Names of the fields, including the ones of the alternatives
+fn collect_names<'a>(fields: &'a [FieldDescriptor], names: &mut Vec<&'a str>) {
+    for field in fields {
+        names.push(&field.name);
+        for alternative in &field.alternatives {
+            collect_names(&alternative.fields, names);
+        }
+    }
+}
+
+/// Variable length symbols referring to no field, with the field using them
+fn check_lengths(
+    fields: &[FieldDescriptor],
+    names: &[&str],
+    undefined: &mut Vec<(String, String)>,
+) -> Result<(), Error> {
+    for field in fields {
+        for length in [Some(&field.length), field.repeat.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            for (_, symbol) in length.terms()?.1 {
+                if !names.contains(&symbol.as_str()) {
+                    undefined.push((field.name.clone(), symbol));
+                }
+            }
+        }
+        for alternative in &field.alternatives {
+            check_lengths(&alternative.fields, names, undefined)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Names used by several fields, with their number of fields, including in the alternatives
+fn check_names(fields: &[FieldDescriptor], duplicates: &mut Vec<(String, usize)>) {
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for field in fields {
+        *counts.entry(&field.name).or_default() += 1;
+    }
+    duplicates.extend(
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(name, count)| (name.to_owned(), count)),
+    );
+
+    for field in fields {
+        for alternative in &field.alternatives {
+            check_names(&alternative.fields, duplicates);
+        }
+    }
+}
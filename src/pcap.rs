@@ -0,0 +1,270 @@
+//! Reader of pcap and pcapng capture files, with no dependency on libpcap
+
+use crate::errors::Error;
+
+/// Packet of a capture file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub seconds: u64,     // Timestamp, in seconds since the Unix epoch
+    pub nanoseconds: u32, // Fraction of second of the timestamp
+    pub link_type: u16,   // Link layer of the interface the packet was captured on
+    pub length: usize,    // Length of the packet on the wire, which may have been truncated
+    pub data: Vec<u8>,    // Captured bytes, starting with the link layer header
+}
+
+/// Read the packets of a pcap or pcapng file, detected from its magic number
+pub fn read_capture(data: &[u8]) -> Result<Vec<Packet>, Error> {
+    match data.get(..4) {
+        Some([0x0a, 0x0d, 0x0d, 0x0a]) => read_pcapng(data),
+        Some(_) => read_pcap(data),
+        None => Err(Error::FormatError("Capture file is empty".to_string())),
+    }
+}
+
+/// Reader of integers in the byte order of the file
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    little: bool,
+}
+
+impl Reader<'_> {
+    fn u16(&self, offset: usize) -> Result<u16, Error> {
+        let bytes = self.bytes(offset, 2)?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(if self.little {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, Error> {
+        let bytes = self.bytes(offset, 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(if self.little {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn bytes(&self, offset: usize, length: usize) -> Result<&[u8], Error> {
+        offset
+            .checked_add(length)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| Error::FormatError("Capture file is truncated".to_string()))
+    }
+}
+
+/// Read a pcap file, with microsecond or nanosecond timestamps in either byte order
+fn read_pcap(data: &[u8]) -> Result<Vec<Packet>, Error> {
+    let (little, nanoseconds) = match data.get(..4) {
+        Some([0xd4, 0xc3, 0xb2, 0xa1]) => (true, false),
+        Some([0xa1, 0xb2, 0xc3, 0xd4]) => (false, false),
+        Some([0x4d, 0x3c, 0xb2, 0xa1]) => (true, true),
+        Some([0xa1, 0xb2, 0x3c, 0x4d]) => (false, true),
+        _ => {
+            return Err(Error::FormatError(
+                "Unknown capture file format".to_string(),
+            ))
+        }
+    };
+
+    let reader = Reader { data, little };
+    // The FCS length may be stored in the upper bits of the link type
+    let link_type = reader.u32(20)? as u16;
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let seconds = reader.u32(offset)?;
+        let fraction = reader.u32(offset + 4)?;
+        let captured = reader.u32(offset + 8)? as usize;
+        let length = reader.u32(offset + 12)? as usize;
+
+        packets.push(Packet {
+            seconds: u64::from(seconds),
+            nanoseconds: if nanoseconds {
+                fraction
+            } else {
+                fraction.saturating_mul(1000)
+            },
+            link_type,
+            length,
+            data: reader.bytes(offset + 16, captured)?.to_vec(),
+        });
+
+        offset += 16 + captured;
+    }
+
+    Ok(packets)
+}
+
+/// Interface of a pcapng file
+struct Interface {
+    link_type: u16,
+    snap_length: usize,
+    resolution: Resolution,
+}
+
+/// Units of the timestamps of an interface
+#[derive(Clone, Copy)]
+enum Resolution {
+    Decimal(u32), // Negative power of 10 of a second
+    Binary(u32),  // Negative power of 2 of a second
+}
+
+impl Resolution {
+    /// Split a timestamp into seconds and nanoseconds
+    fn timestamp(&self, timestamp: u64) -> (u64, u32) {
+        match *self {
+            Resolution::Decimal(exponent) => {
+                let units = 10u64.checked_pow(exponent).unwrap_or(u64::MAX);
+                let fraction = timestamp % units;
+                let nanoseconds = if exponent <= 9 {
+                    fraction * 10u64.pow(9 - exponent)
+                } else {
+                    fraction / 10u64.pow((exponent - 9).min(19))
+                };
+                (timestamp / units, nanoseconds as u32)
+            }
+            Resolution::Binary(exponent) => {
+                let exponent = exponent.min(63);
+                let fraction = u128::from(timestamp & ((1u64 << exponent) - 1));
+                let nanoseconds = (fraction * 1_000_000_000) >> exponent;
+                (timestamp >> exponent, nanoseconds as u32)
+            }
+        }
+    }
+}
+
+/// Read a pcapng file, made of blocks in the byte order of their section
+fn read_pcapng(data: &[u8]) -> Result<Vec<Packet>, Error> {
+    let mut reader = Reader { data, little: true };
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut packets = Vec::new();
+
+    let mut offset = 0;
+    while offset < data.len() {
+        let block_type = reader.u32(offset)?;
+
+        // Each section header gives the byte order of its blocks
+        if block_type == 0x0a0d0d0a {
+            reader.little = match reader.bytes(offset + 8, 4)? {
+                [0x4d, 0x3c, 0x2b, 0x1a] => true,
+                [0x1a, 0x2b, 0x3c, 0x4d] => false,
+                _ => return Err(Error::FormatError("Invalid pcapng byte order".to_string())),
+            };
+            interfaces.clear();
+        }
+
+        let block_length = reader.u32(offset + 4)? as usize;
+        if block_length < 12 || !block_length.is_multiple_of(4) {
+            return Err(Error::FormatError(format!(
+                "Invalid pcapng block length {}",
+                block_length
+            )));
+        }
+        let body = Reader {
+            data: reader.bytes(offset + 8, block_length - 12)?,
+            little: reader.little,
+        };
+
+        let interface = |id: usize| {
+            interfaces
+                .get(id)
+                .ok_or_else(|| Error::FormatError(format!("Packet of unknown interface {}", id)))
+        };
+
+        match block_type {
+            // Interface description
+            1 => {
+                interfaces.push(Interface {
+                    link_type: body.u16(0)?,
+                    snap_length: body.u32(4)? as usize,
+                    resolution: interface_resolution(body)?,
+                });
+            }
+            // Enhanced packet
+            6 => {
+                let interface = interface(body.u32(0)? as usize)?;
+                let timestamp = (u64::from(body.u32(4)?) << 32) | u64::from(body.u32(8)?);
+                let captured = body.u32(12)? as usize;
+                let (seconds, nanoseconds) = interface.resolution.timestamp(timestamp);
+
+                packets.push(Packet {
+                    seconds,
+                    nanoseconds,
+                    link_type: interface.link_type,
+                    length: body.u32(16)? as usize,
+                    data: body.bytes(20, captured)?.to_vec(),
+                });
+            }
+            // Simple packet, captured on the first interface without timestamp
+            3 => {
+                let interface = interface(0)?;
+                let length = body.u32(0)? as usize;
+                let mut captured = length.min(body.data.len() - 4);
+                if interface.snap_length > 0 {
+                    captured = captured.min(interface.snap_length);
+                }
+
+                packets.push(Packet {
+                    seconds: 0,
+                    nanoseconds: 0,
+                    link_type: interface.link_type,
+                    length,
+                    data: body.bytes(4, captured)?.to_vec(),
+                });
+            }
+            // Obsolete packet
+            2 => {
+                let interface = interface(usize::from(body.u16(0)?))?;
+                let timestamp = (u64::from(body.u32(4)?) << 32) | u64::from(body.u32(8)?);
+                let captured = body.u32(12)? as usize;
+                let (seconds, nanoseconds) = interface.resolution.timestamp(timestamp);
+
+                packets.push(Packet {
+                    seconds,
+                    nanoseconds,
+                    link_type: interface.link_type,
+                    length: body.u32(16)? as usize,
+                    data: body.bytes(20, captured)?.to_vec(),
+                });
+            }
+            _ => {}
+        }
+
+        offset += block_length;
+    }
+
+    Ok(packets)
+}
+
+/// Timestamp resolution of an interface, from its `if_tsresol` option (microseconds by default)
+fn interface_resolution(body: Reader) -> Result<Resolution, Error> {
+    let mut offset = 8;
+    while offset + 4 <= body.data.len() {
+        let code = body.u16(offset)?;
+        let length = usize::from(body.u16(offset + 2)?);
+
+        match code {
+            0 => break,
+            9 if length >= 1 => {
+                let value = body.bytes(offset + 4, 1)?[0];
+                return Ok(if value & 0x80 == 0 {
+                    Resolution::Decimal(u32::from(value))
+                } else {
+                    Resolution::Binary(u32::from(value & 0x7f))
+                });
+            }
+            _ => {}
+        }
+
+        // Option values are padded to 32 bits
+        offset += 4 + length.div_ceil(4) * 4;
+    }
+
+    Ok(Resolution::Decimal(6))
+}
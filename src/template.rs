@@ -1,9 +1,10 @@
-use std::{collections::HashMap, vec};
+use std::{cmp::Reverse, collections::HashMap, vec};
 
 use hex_color::HexColor;
 use serde::Serialize;
 
 use crate::descriptor;
+use crate::errors::Error;
 
 #[derive(Debug, Serialize, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
@@ -32,6 +33,12 @@ struct ComponentsDynamic {
 #[derive(Debug, Serialize)]
 struct StaticFields {
     background: HexColor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+    class: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    dashed: bool,
     coordinates: Components,
     size: Components,
     stroke_color: HexColor,
@@ -41,6 +48,12 @@ struct StaticFields {
 #[derive(Debug, Serialize)]
 struct DynamicFields {
     background: HexColor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+    class: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    dashed: bool,
     coordinates: Components,
     size: ComponentsDynamic,
     stroke_color: HexColor,
@@ -50,6 +63,7 @@ struct DynamicFields {
 #[derive(Debug, Serialize)]
 struct FieldText {
     text: String,
+    class: String,
     coordinates: Components,
     color: HexColor,
     baseline: TextBaseline,
@@ -81,6 +95,36 @@ struct WrapLine {
     color: HexColor,
 }
 
+#[derive(Debug, Serialize)]
+struct RepeatBracket {
+    coordinates: Components,
+    size: Components,
+    serif: f64,
+    stroke: f64,
+    color: HexColor,
+}
+
+#[derive(Debug, Serialize)]
+struct GroupBracket {
+    coordinates: Components,
+    size: Components,
+    open_start: bool,
+    open_end: bool,
+    stroke: f64,
+    color: HexColor,
+}
+
+#[derive(Debug, Serialize)]
+struct FieldBadge {
+    text: String,
+    coordinates: Components,
+    size: Components,
+    text_size: f64,
+    stroke: f64,
+    background: HexColor,
+    color: HexColor,
+}
+
 #[derive(Debug, Serialize)]
 struct StartSymbol {
     coordinates: Components,
@@ -88,18 +132,80 @@ struct StartSymbol {
     color: HexColor,
 }
 
+#[derive(Debug, Serialize)]
+struct FieldColor {
+    class: String,
+    color: HexColor,
+}
+
+#[derive(Debug, Serialize)]
+struct FillPattern {
+    id: String,
+    kind: descriptor::FillPattern,
+    background: HexColor,
+    colored: bool,
+    color: HexColor,
+    size: f64,
+    stroke: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct LayerConnector {
+    start: (Components, Components),
+    end: (Components, Components),
+    stroke: f64,
+    color: HexColor,
+}
+
+/// Area covered by a field, used to connect the layers of a stack
+#[derive(Debug, Clone, Copy)]
+struct FieldBounds {
+    start: Components,
+    end: Components,
+}
+
 #[derive(Debug, Serialize)]
 pub struct TemplateData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<Components>,
     size: Components,
     background: HexColor,
+    css_classes: bool,
+    colors: descriptor::ThemeColors,
+    stroke_width: f64,
+    field_colors: Vec<FieldColor>,
+    patterns: Vec<FillPattern>,
+    dash_array: &'static str,
     static_fields: Vec<StaticFields>,
     dynamic_fields: Vec<DynamicFields>,
     field_texts: Vec<FieldText>,
     field_ticks: Vec<FieldTicks>,
     field_lengths: Vec<FieldLength>,
     wrap_lines: Vec<WrapLine>,
+    repeat_brackets: Vec<RepeatBracket>,
+    group_brackets: Vec<GroupBracket>,
+    field_badges: Vec<FieldBadge>,
     #[serde(skip_serializing_if = "Option::is_none")]
     start_symbol: Option<StartSymbol>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dark_colors: Option<descriptor::ThemeColors>,
+    #[serde(skip)]
+    field_bounds: Vec<FieldBounds>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StackData {
+    size: Components,
+    background: HexColor,
+    css_classes: bool,
+    colors: descriptor::ThemeColors,
+    stroke_width: f64,
+    dash_array: &'static str,
+    connectors: Vec<LayerConnector>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dark_colors: Option<descriptor::ThemeColors>,
 }
 
 const DEFAULT_PADDING: f64 = 50.0;
@@ -109,9 +215,25 @@ const DEFAULT_TICK_SIZE: f64 = 20.0;
 const DEFAULT_SUB_PADDING: f64 = 10.0;
 const DEFAULT_LENGTH_SIZE: f64 = 10.0;
 const DEFAULT_TEXT_SIZE: f64 = 16.0;
+const DEFAULT_CONDITION_SIZE: f64 = 12.0;
 const DEFAULT_START_SYMBOL_X: f64 = 10.0;
 const DEFAULT_START_SYMBOL_Y: f64 = 20.0;
 const DEFAULT_DYN_SPACING_VALUE: f64 = 10.0;
+const DEFAULT_PATTERN_SIZE: f64 = 8.0;
+const DEFAULT_PATTERN_STROKE: f64 = 1.5;
+const DEFAULT_DASH_ARRAY: &str = "6,4";
+const DEFAULT_BRACKET_INSET: f64 = 6.0;
+const DEFAULT_BRACKET_SERIF: f64 = 6.0;
+const DEFAULT_GROUP_SIZE: f64 = DEFAULT_LENGTH_SIZE + DEFAULT_TEXT_SIZE + DEFAULT_SUB_PADDING;
+const DEFAULT_BADGE_SIZE: f64 = 14.0;
+const DEFAULT_BADGE_TEXT_SIZE: f64 = 10.0;
+const DEFAULT_BADGE_CHAR_WIDTH: f64 = 6.5;
+const DEFAULT_BADGE_INSET: f64 = 4.0;
+const DEFAULT_VARIANT_SIZE_Y: f64 = 40.0;
+const DEFAULT_VARIANT_SIZE: f64 = DEFAULT_SUB_PADDING
+    + DEFAULT_CONDITION_SIZE
+    + DEFAULT_SUB_PADDING / 2.0
+    + DEFAULT_VARIANT_SIZE_Y;
 
 // PERCENTAGE FROM UNIT_WIDTH
 const DEFAULT_DYN_LENGTH_1: f64 = 2.0 / 3.0;
@@ -120,15 +242,28 @@ const DEFAULT_DYN_SPACING_UPPER: f64 = 0.2;
 const DEFAULT_DYN_SPACING_LOWER: f64 = 0.5;
 const DEFAULT_DYN_DELTA: f64 = 0.5;
 
-/// Generate the data consumed by the SVG template
-pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
+/// Generate the data consumed by the SVG template (the scope is a class used to isolate the styles of stacked diagrams)
+pub fn generate_data(
+    descriptor: &descriptor::ProtoDescriptor,
+    fields: &[descriptor::FieldDescriptor],
+    groups: &[descriptor::FieldGroup],
+    scope: Option<String>,
+) -> Result<TemplateData, Error> {
     let mut static_fields_rows = vec![Vec::new()];
     let mut dynamic_fields_rows = vec![Vec::new()];
     let mut field_texts_rows = vec![Vec::new()];
     let mut field_ticks_rows = vec![Vec::new()];
     let mut wrap_lines_rows = vec![Vec::new()];
+    let mut repeat_brackets_rows = vec![Vec::new()];
+    let mut field_badges_rows = vec![Vec::new()];
     let mut start_symbol: Option<StartSymbol> = None;
 
+    // Used to style the fields with a custom color through CSS classes
+    let mut field_colors: Vec<FieldColor> = Vec::new();
+
+    // Fill patterns used by the fields, emitted as SVG definitions
+    let mut patterns: Vec<FillPattern> = Vec::new();
+
     // Used to create the field position subtitles
     let mut positions_rows = vec![Vec::new()];
 
@@ -138,32 +273,57 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
     // Used to offset the X coord if not in network order
     let mut row_sizes = Vec::new();
 
+    // Used to draw the group brackets over the rows
+    let mut row_ys = Vec::new();
+    let mut field_spans = vec![(0, 0.0, 0.0); fields.len()];
+
+    // Space reserved above and below each row for the group brackets
+    let group_levels = group_levels(groups);
+    let max_level = |position| {
+        groups
+            .iter()
+            .zip(&group_levels)
+            .filter(|(group, _)| group.position == position)
+            .map(|(_, level)| *level)
+            .max()
+    };
+    let max_above = max_level(descriptor::GroupPosition::Above);
+    let max_below = max_level(descriptor::GroupPosition::Below);
+    let group_bands = (
+        max_above.map_or(0.0, |level| (level + 1) as f64 * DEFAULT_GROUP_SIZE),
+        max_below.map_or(0.0, |level| (level + 1) as f64 * DEFAULT_GROUP_SIZE),
+    );
+
+    // Space reserved below the current row for the field alternatives
+    let mut variant_band = 0.0;
+
     let mut x = DEFAULT_PADDING;
-    let mut y = DEFAULT_PADDING;
+    let mut y = DEFAULT_PADDING + group_bands.0;
+    let first_row_y = y;
 
     let mut max_x = 0.0;
     let mut row_max_x = 0.0;
-    let mut last_row_y = DEFAULT_PADDING;
+    let mut last_row_y = y;
+    row_ys.push(y);
 
     // Reverse the fields if the elements are not in network order (big-endian)
-    let fields: Box<dyn Iterator<Item = _>> = if descriptor.elements.network_order {
-        Box::new(descriptor.fields.iter())
+    let ordered_fields: Box<dyn Iterator<Item = _>> = if descriptor.elements.network_order {
+        Box::new(fields.iter())
     } else {
-        Box::new(descriptor.fields.iter().rev())
+        Box::new(fields.iter().rev())
     };
 
     // Default width of a field unit
     let unit_width = descriptor.style.unit_width as f64;
 
-    // Default units of a dynamic field
-    let dyn_units = descriptor.style.dyn_units as f64;
-
-    for (i, field) in fields.into_iter().enumerate() {
+    for (i, field) in ordered_fields.enumerate() {
         // Wrap line before field if not in network order and wrap is enabled
         if !descriptor.elements.network_order && field.wrap && i != 0 {
-            if let Some(wrap_line) = wrap_line(descriptor, &mut x, &mut y) {
+            let bands = (group_bands.0, group_bands.1 + variant_band);
+            if let Some(wrap_line) = wrap_line(descriptor, bands, &mut x, &mut y) {
                 wrap_lines_rows.last_mut().unwrap().push(wrap_line);
             }
+            variant_band = 0.0;
         }
 
         // If the field is in a new row, save the previous row size
@@ -171,19 +331,47 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
             row_sizes.push(row_max_x);
             row_max_x = 0.0;
             last_row_y = y;
+            row_ys.push(y);
 
             static_fields_rows.push(Vec::new());
             dynamic_fields_rows.push(Vec::new());
             field_texts_rows.push(Vec::new());
             field_ticks_rows.push(Vec::new());
             wrap_lines_rows.push(Vec::new());
+            repeat_brackets_rows.push(Vec::new());
+            field_badges_rows.push(Vec::new());
             positions_rows.push(Vec::new());
             lengths_rows.push(Vec::new());
         }
 
         let coordinates = Components { x, y };
 
-        let length = match &field.length {
+        let (classes, name_class, background, pattern) = field_style(
+            descriptor,
+            scope.as_deref(),
+            field,
+            &mut field_colors,
+            &mut patterns,
+        );
+        let optional = field.is_optional();
+
+        // Annotations drawn under the field name (its condition and register access/reset)
+        let mut annotations = Vec::new();
+        if let Some(condition) = &field.condition {
+            annotations.push((condition.clone(), "pv-condition".to_owned()));
+        }
+        if let Some(info) = field.register_info() {
+            let class = match field.access {
+                Some(access) => format!("pv-access pv-access--{}", access).to_lowercase(),
+                None => "pv-access".to_owned(),
+            };
+            annotations.push((info, class));
+        }
+
+        // Move the name up to make room for the annotations
+        let name_delta = -(annotations.len() as f64) * DEFAULT_CONDITION_SIZE / 2.0;
+
+        let (length, name_x) = match &field.length {
             descriptor::FieldLength::Fixed(length) => {
                 // Add field ticks
                 for i in 1..*length {
@@ -210,7 +398,11 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
                 };
 
                 static_fields_rows.last_mut().unwrap().push(StaticFields {
-                    background: field.color.unwrap_or(descriptor.style.field_color),
+                    background,
+                    pattern,
+                    description: field.description.clone(),
+                    class: classes,
+                    dashed: optional,
                     coordinates,
                     size: size,
                     stroke_color: descriptor.style.text_color,
@@ -219,39 +411,27 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
 
                 field_texts_rows.last_mut().unwrap().push(FieldText {
                     text: field.name.clone(),
+                    class: format!("pv-name {}", name_class).trim_end().to_owned(),
                     coordinates: Components {
                         x: x + size.x / 2.0,
-                        y: y + DEFAULT_SIZE_Y / 2.0,
+                        y: y + DEFAULT_SIZE_Y / 2.0 + name_delta,
                     },
                     color: descriptor.style.text_color,
                     baseline: TextBaseline::Middle,
                     height: DEFAULT_TEXT_SIZE,
                 });
 
-                size.x
+                (size.x, x + size.x / 2.0)
             }
             descriptor::FieldLength::Variable(_length) => {
-                let spacing = if DEFAULT_DYN_SPACING_UPPER * unit_width < DEFAULT_DYN_SPACING_VALUE
-                {
-                    if unit_width >= DEFAULT_DYN_SPACING_VALUE * 2.0 {
-                        DEFAULT_DYN_SPACING_VALUE
-                    } else {
-                        DEFAULT_DYN_SPACING_LOWER * unit_width
-                    }
-                } else {
-                    DEFAULT_DYN_SPACING_UPPER * unit_width
-                };
-
-                let size = ComponentsDynamic {
-                    x1: (DEFAULT_DYN_LENGTH_1 * dyn_units).ceil() * unit_width - spacing / 2.0,
-                    x2: (DEFAULT_DYN_LENGTH_2 * dyn_units).floor() * unit_width - spacing / 2.0,
-                    spacing: spacing,
-                    delta: DEFAULT_DYN_DELTA * unit_width,
-                    y: DEFAULT_SIZE_Y,
-                };
+                let size = dynamic_size(descriptor, DEFAULT_SIZE_Y);
 
                 dynamic_fields_rows.last_mut().unwrap().push(DynamicFields {
-                    background: field.color.unwrap_or(descriptor.style.field_color),
+                    background,
+                    pattern,
+                    description: field.description.clone(),
+                    class: classes.replacen("pv-field", "pv-field pv-field-dynamic", 1),
+                    dashed: optional,
                     coordinates: coordinates,
                     size,
                     stroke_color: descriptor.style.text_color,
@@ -260,19 +440,84 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
 
                 field_texts_rows.last_mut().unwrap().push(FieldText {
                     text: field.name.clone(),
+                    class: format!("pv-name {}", name_class).trim_end().to_owned(),
                     coordinates: Components {
                         x: x + size.x1 / 2.0,
-                        y: y + DEFAULT_SIZE_Y / 2.0,
+                        y: y + DEFAULT_SIZE_Y / 2.0 + name_delta,
                     },
                     color: descriptor.style.text_color,
                     baseline: TextBaseline::Middle,
                     height: DEFAULT_TEXT_SIZE,
                 });
 
-                size.x1 + size.spacing + size.x2
+                (size.x1 + size.spacing + size.x2, x + size.x1 / 2.0)
             }
         };
 
+        // Add the annotations, one per line under the field name
+        for (i, (text, class)) in annotations.into_iter().enumerate() {
+            field_texts_rows.last_mut().unwrap().push(FieldText {
+                text,
+                class,
+                coordinates: Components {
+                    x: name_x,
+                    y: y + DEFAULT_SIZE_Y / 2.0
+                        + DEFAULT_TEXT_SIZE / 2.0
+                        + name_delta
+                        + i as f64 * DEFAULT_CONDITION_SIZE,
+                },
+                color: descriptor.style.subtitle_color,
+                baseline: TextBaseline::Hanging,
+                height: DEFAULT_CONDITION_SIZE,
+            });
+        }
+
+        // Enclose repeated fields in brackets, labelled with the repeat count
+        if let Some(count) = &field.repeat {
+            repeat_brackets_rows
+                .last_mut()
+                .unwrap()
+                .push(RepeatBracket {
+                    coordinates: Components {
+                        x: x + DEFAULT_BRACKET_INSET,
+                        y: y + DEFAULT_BRACKET_INSET,
+                    },
+                    size: Components {
+                        x: length - 2.0 * DEFAULT_BRACKET_INSET,
+                        y: DEFAULT_SIZE_Y - 2.0 * DEFAULT_BRACKET_INSET,
+                    },
+                    serif: DEFAULT_BRACKET_SERIF,
+                    stroke: DEFAULT_STROKE_WIDTH,
+                    color: descriptor.style.text_color,
+                });
+
+            field_texts_rows.last_mut().unwrap().push(FieldText {
+                text: format!("×{}", count),
+                class: "pv-repeat".to_owned(),
+                // Centered on the last unit, so it doesn't overlap the ticks
+                coordinates: Components {
+                    x: x + length - unit_width / 2.0,
+                    y: y + DEFAULT_BRACKET_INSET,
+                },
+                color: descriptor.style.subtitle_color,
+                baseline: TextBaseline::Hanging,
+                height: DEFAULT_CONDITION_SIZE,
+            });
+        }
+
+        // Badges for the byte and bit order, moved right of the repeat bracket
+        let inset = if field.repeat.is_some() {
+            DEFAULT_BRACKET_INSET + DEFAULT_STROKE_WIDTH
+        } else {
+            0.0
+        };
+        field_badges_rows.last_mut().unwrap().extend(create_badges(
+            descriptor,
+            field,
+            x + inset,
+            y,
+        ));
+
         // If position subtitles are enabled, add them to the positions vector
         if descriptor.elements.field_position {
             let pos_x = if descriptor.elements.network_order {
@@ -287,10 +532,12 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
                 y + DEFAULT_SIZE_Y + DEFAULT_SUB_PADDING
             };
 
-            positions_rows
-                .last_mut()
-                .unwrap()
-                .push((field.length.clone(), Components { x: pos_x, y: pos_y }));
+            positions_rows.last_mut().unwrap().push((
+                field.total_length()?,
+                optional,
+                Components { x: pos_x, y: pos_y },
+                x + length / 2.0,
+            ));
         }
 
         // If field length subtitles are enabled, add them
@@ -318,7 +565,8 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
             };
 
             let length_text = FieldText {
-                text: field.length.to_string(),
+                text: field.total_length()?.to_string(),
+                class: "pv-length".to_owned(),
                 coordinates: Components {
                     x: x + length / 2.0,
                     y: pos_y,
@@ -334,6 +582,19 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
                 .push((length_sub, length_text));
         }
 
+        // Save the field span, indexed by its position in the descriptor
+        let index = if descriptor.elements.network_order {
+            i
+        } else {
+            fields.len() - 1 - i
+        };
+        field_spans[index] = (row_ys.len() - 1, x, x + length);
+
+        let alternatives = field.alternatives.len() as f64 * DEFAULT_VARIANT_SIZE;
+        if alternatives > variant_band {
+            variant_band = alternatives;
+        }
+
         x += length;
 
         if x > row_max_x {
@@ -345,10 +606,12 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
         }
 
         // Wrap line after field if in network order and wrap is enabled
-        if descriptor.elements.network_order && field.wrap && i != descriptor.fields.len() - 1 {
-            if let Some(wrap_line) = wrap_line(descriptor, &mut x, &mut y) {
+        if descriptor.elements.network_order && field.wrap && i != fields.len() - 1 {
+            let bands = (group_bands.0, group_bands.1 + variant_band);
+            if let Some(wrap_line) = wrap_line(descriptor, bands, &mut x, &mut y) {
                 wrap_lines_rows.last_mut().unwrap().push(wrap_line);
             }
+            variant_band = 0.0;
         }
     }
 
@@ -361,7 +624,7 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
             start_symbol = Some(StartSymbol {
                 coordinates: Components {
                     x: DEFAULT_PADDING - DEFAULT_SUB_PADDING,
-                    y: DEFAULT_PADDING + DEFAULT_SIZE_Y / 2.0,
+                    y: first_row_y + DEFAULT_SIZE_Y / 2.0,
                 },
                 size: Components {
                     x: -DEFAULT_START_SYMBOL_X,
@@ -385,7 +648,7 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
     }
 
     // Flatten the rows and apply the offset if needed (align to the right if not in network order)
-    let static_fields = static_fields_rows
+    let mut static_fields = static_fields_rows
         .into_iter()
         .enumerate()
         .flat_map(|(i, row)| {
@@ -399,7 +662,7 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
         })
         .collect::<Vec<_>>();
 
-    let dynamic_fields = dynamic_fields_rows
+    let mut dynamic_fields = dynamic_fields_rows
         .into_iter()
         .enumerate()
         .flat_map(|(i, row)| {
@@ -413,7 +676,7 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
         })
         .collect::<Vec<_>>();
 
-    let field_ticks = field_ticks_rows
+    let mut field_ticks = field_ticks_rows
         .into_iter()
         .enumerate()
         .flat_map(|(i, row)| {
@@ -442,6 +705,34 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
         })
         .collect::<Vec<_>>();
 
+    let repeat_brackets = repeat_brackets_rows
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, row)| {
+            let row_sizes = &row_sizes;
+            row.into_iter().map(move |mut field| {
+                if !descriptor.elements.network_order {
+                    field.coordinates.x += max_x - row_sizes[i];
+                }
+                field
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut field_badges = field_badges_rows
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, row)| {
+            let row_sizes = &row_sizes;
+            row.into_iter().map(move |mut field| {
+                if !descriptor.elements.network_order {
+                    field.coordinates.x += max_x - row_sizes[i];
+                }
+                field
+            })
+        })
+        .collect::<Vec<_>>();
+
     let mut lengths = lengths_rows
         .into_iter()
         .enumerate()
@@ -463,12 +754,14 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
         .enumerate()
         .flat_map(|(i, row)| {
             let row_sizes = &row_sizes;
-            row.into_iter().map(move |(length, mut position)| {
-                if !descriptor.elements.network_order {
-                    position.x += max_x - row_sizes[i];
-                }
-                (length, position)
-            })
+            row.into_iter()
+                .map(move |(length, optional, mut position, mut center_x)| {
+                    if !descriptor.elements.network_order {
+                        position.x += max_x - row_sizes[i];
+                        center_x += max_x - row_sizes[i];
+                    }
+                    (length, optional, position, center_x)
+                })
         })
         .collect::<Vec<_>>();
 
@@ -486,6 +779,273 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
         })
         .collect::<Vec<_>>();
 
+    // Apply the offsets to the field spans
+    let field_bounds = field_spans
+        .iter()
+        .map(|&(row, start, end)| {
+            let offset = if descriptor.elements.network_order {
+                0.0
+            } else {
+                max_x - row_sizes[row]
+            };
+
+            FieldBounds {
+                start: Components {
+                    x: start + offset,
+                    y: row_ys[row],
+                },
+                end: Components {
+                    x: end + offset,
+                    y: row_ys[row] + DEFAULT_SIZE_Y,
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut group_brackets = Vec::new();
+
+    // Add the group brackets, with a segment for each row the group spans
+    for (group, level) in groups.iter().zip(&group_levels) {
+        let spans = &field_spans[group.first..=group.last];
+        let first_row = spans.iter().map(|(row, _, _)| *row).min().unwrap();
+        let last_row = spans.iter().map(|(row, _, _)| *row).max().unwrap();
+        let above = group.position == descriptor::GroupPosition::Above;
+
+        // Outer groups (lower levels) are drawn farther from the fields
+        let max_level = if above { max_above } else { max_below };
+        let distance = (max_level.unwrap() - level) as f64 * DEFAULT_GROUP_SIZE;
+
+        for row in first_row..=last_row {
+            let row_spans = spans.iter().filter(|(r, _, _)| *r == row);
+            let start = row_spans
+                .clone()
+                .map(|(_, start, _)| *start)
+                .fold(f64::MAX, f64::min);
+            let end = row_spans.map(|(_, _, end)| *end).fold(f64::MIN, f64::max);
+
+            let offset = if descriptor.elements.network_order {
+                0.0
+            } else {
+                max_x - row_sizes[row]
+            };
+
+            let subtitles = subtitles_size(descriptor, row, row_ys.len(), above);
+
+            // The bracket ticks point towards the fields
+            let (line_y, tick_size, label_y, baseline) = if above {
+                let line_y = row_ys[row]
+                    - subtitles
+                    - DEFAULT_SUB_PADDING / 2.0
+                    - distance
+                    - DEFAULT_LENGTH_SIZE;
+
+                (
+                    line_y,
+                    DEFAULT_LENGTH_SIZE,
+                    line_y - DEFAULT_SUB_PADDING / 2.0,
+                    TextBaseline::Auto,
+                )
+            } else {
+                let line_y = row_ys[row]
+                    + DEFAULT_SIZE_Y
+                    + subtitles
+                    + DEFAULT_SUB_PADDING / 2.0
+                    + distance
+                    + DEFAULT_LENGTH_SIZE;
+
+                (
+                    line_y,
+                    -DEFAULT_LENGTH_SIZE,
+                    line_y + DEFAULT_SUB_PADDING / 2.0,
+                    TextBaseline::Hanging,
+                )
+            };
+
+            group_brackets.push(GroupBracket {
+                coordinates: Components {
+                    x: start + offset,
+                    y: line_y,
+                },
+                size: Components {
+                    x: end - start,
+                    y: tick_size,
+                },
+                open_start: row != first_row,
+                open_end: row != last_row,
+                stroke: DEFAULT_STROKE_WIDTH,
+                color: descriptor.style.subtitle_color,
+            });
+
+            // The label is only shown on the first segment
+            if row == first_row {
+                field_texts.push(FieldText {
+                    text: group.name.clone(),
+                    class: "pv-group".to_owned(),
+                    coordinates: Components {
+                        x: start + offset + (end - start) / 2.0,
+                        y: label_y,
+                    },
+                    color: descriptor.style.subtitle_color,
+                    baseline,
+                    height: DEFAULT_TEXT_SIZE,
+                });
+            }
+        }
+    }
+
+    let mut size_x = max_x;
+
+    // Add the field alternatives, stacked below the row of the field
+    for (field, &(row, start, end)) in fields.iter().zip(&field_spans) {
+        let offset = if descriptor.elements.network_order {
+            0.0
+        } else {
+            max_x - row_sizes[row]
+        };
+
+        let mut y = row_ys[row]
+            + DEFAULT_SIZE_Y
+            + subtitles_size(descriptor, row, row_ys.len(), false)
+            + group_bands.1;
+
+        for alternative in &field.alternatives {
+            let widths = alternative
+                .fields
+                .iter()
+                .map(|sub_field| match &sub_field.length {
+                    descriptor::FieldLength::Fixed(length) => *length as f64 * unit_width,
+                    descriptor::FieldLength::Variable(_) => {
+                        let size = dynamic_size(descriptor, DEFAULT_VARIANT_SIZE_Y);
+                        size.x1 + size.spacing + size.x2
+                    }
+                })
+                .collect::<Vec<_>>();
+            let width = widths.iter().sum::<f64>();
+
+            // Aligned to the start of the field in network order, and to its end otherwise
+            let mut x = if descriptor.elements.network_order {
+                start + offset
+            } else {
+                end + offset - width
+            };
+
+            // Label the alternative with its discriminator value
+            field_texts.push(FieldText {
+                text: alternative.name.clone(),
+                class: "pv-variant".to_owned(),
+                coordinates: Components {
+                    x: x + width / 2.0,
+                    y: y + DEFAULT_SUB_PADDING,
+                },
+                color: descriptor.style.subtitle_color,
+                baseline: TextBaseline::Hanging,
+                height: DEFAULT_CONDITION_SIZE,
+            });
+
+            let box_y =
+                y + DEFAULT_SUB_PADDING + DEFAULT_CONDITION_SIZE + DEFAULT_SUB_PADDING / 2.0;
+
+            let ordered_sub_fields: Box<dyn Iterator<Item = _>> =
+                if descriptor.elements.network_order {
+                    Box::new(alternative.fields.iter().zip(widths))
+                } else {
+                    Box::new(alternative.fields.iter().zip(widths).rev())
+                };
+
+            for (sub_field, width) in ordered_sub_fields {
+                let (classes, name_class, background, pattern) = field_style(
+                    descriptor,
+                    scope.as_deref(),
+                    sub_field,
+                    &mut field_colors,
+                    &mut patterns,
+                );
+                let coordinates = Components { x, y: box_y };
+
+                let name_x = match &sub_field.length {
+                    descriptor::FieldLength::Fixed(length) => {
+                        for i in 1..*length {
+                            field_ticks.extend(
+                                [0.0, DEFAULT_VARIANT_SIZE_Y - DEFAULT_TICK_SIZE / 2.0]
+                                    .into_iter()
+                                    .map(|y_delta| FieldTicks {
+                                        coordinates: Components {
+                                            x: x + i as f64 * unit_width,
+                                            y: box_y + y_delta,
+                                        },
+                                        size: Components {
+                                            x: DEFAULT_STROKE_WIDTH,
+                                            y: DEFAULT_TICK_SIZE / 2.0,
+                                        },
+                                        color: descriptor.style.text_color,
+                                    }),
+                            );
+                        }
+
+                        static_fields.push(StaticFields {
+                            background,
+                            pattern,
+                            description: sub_field.description.clone(),
+                            class: format!("{} pv-variant", classes),
+                            dashed: sub_field.is_optional(),
+                            coordinates,
+                            size: Components {
+                                x: width,
+                                y: DEFAULT_VARIANT_SIZE_Y,
+                            },
+                            stroke_color: descriptor.style.text_color,
+                            stroke_width: DEFAULT_STROKE_WIDTH,
+                        });
+
+                        x + width / 2.0
+                    }
+                    descriptor::FieldLength::Variable(_) => {
+                        let size = dynamic_size(descriptor, DEFAULT_VARIANT_SIZE_Y);
+
+                        dynamic_fields.push(DynamicFields {
+                            background,
+                            pattern,
+                            description: sub_field.description.clone(),
+                            class: format!(
+                                "{} pv-variant",
+                                classes.replacen("pv-field", "pv-field pv-field-dynamic", 1)
+                            ),
+                            dashed: sub_field.is_optional(),
+                            coordinates,
+                            size,
+                            stroke_color: descriptor.style.text_color,
+                            stroke_width: DEFAULT_STROKE_WIDTH,
+                        });
+
+                        x + size.x1 / 2.0
+                    }
+                };
+
+                field_badges.extend(create_badges(descriptor, sub_field, x, box_y));
+
+                field_texts.push(FieldText {
+                    text: sub_field.name.clone(),
+                    class: format!("pv-name {}", name_class).trim_end().to_owned(),
+                    coordinates: Components {
+                        x: name_x,
+                        y: box_y + DEFAULT_VARIANT_SIZE_Y / 2.0,
+                    },
+                    color: descriptor.style.text_color,
+                    baseline: TextBaseline::Middle,
+                    height: DEFAULT_TEXT_SIZE,
+                });
+
+                x += width;
+            }
+
+            if x > size_x {
+                size_x = x;
+            }
+
+            y += DEFAULT_VARIANT_SIZE;
+        }
+    }
+
     // Apply offset to the start symbol if needed
     if !descriptor.elements.network_order {
         if let Some(start_symbol) = start_symbol.as_mut() {
@@ -501,8 +1061,21 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
 
         let mut var_length = HashMap::new();
         let mut fixed_length = 0;
-        let start_y = positions.first().map(|(_, pos)| pos.y).unwrap_or(0.0);
-        for (length, position) in positions {
+
+        // Offset without the optional fields
+        let mut req_var_length = HashMap::new();
+        let mut req_fixed_length = 0;
+
+        // Total length, used to number the bits from the end of the protocol
+        let mut total_length = 0usize;
+        for field in fields {
+            total_length = total_length
+                .checked_add(field.total_length()?.terms()?.0)
+                .ok_or_else(|| Error::FormatError("Protocol length overflows".to_string()))?;
+        }
+
+        let start_y = positions.first().map(|(_, _, pos, _)| pos.y).unwrap_or(0.0);
+        for (length, optional, mut position, center_x) in positions {
             // If only outer subtitles are enabled, break if the Y position changes
             if !descriptor.elements.inner_subtitles && start_y != position.y {
                 break;
@@ -514,38 +1087,38 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
                 TextBaseline::Hanging
             };
 
+            // Show the offset both with and without the previous optional fields
+            let mut text = create_position_sub(&mut var_length, fixed_length);
+            if let Some(numbering) = descriptor.elements.bit_numbering() {
+                // Show the bit range centered on the field instead
+                text = create_range_sub(
+                    numbering,
+                    descriptor.elements.network_order,
+                    fixed_length,
+                    length.terms()?.0,
+                    total_length,
+                );
+                position.x = center_x;
+            } else if req_fixed_length != fixed_length || req_var_length != var_length {
+                text = format!(
+                    "{} / {}",
+                    text,
+                    create_position_sub(&mut req_var_length, req_fixed_length)
+                );
+            }
+
             field_texts.push(FieldText {
-                text: create_position_sub(&mut var_length, fixed_length),
+                text,
+                class: "pv-position".to_owned(),
                 coordinates: position,
                 color: descriptor.style.subtitle_color,
                 baseline: baseline,
                 height: DEFAULT_TEXT_SIZE,
             });
 
-            match length {
-                descriptor::FieldLength::Variable(length) => {
-                    let length = length.trim();
-                    let first_letter = length.chars().position(|c| !c.is_digit(10)).unwrap_or(0);
-
-                    let (length_name, length_count) = if first_letter == 0 {
-                        (length, 1)
-                    } else {
-                        let count = length[..first_letter]
-                            .parse::<usize>()
-                            .expect("Failed to parse dynamic length");
-
-                        (&length[first_letter..], count)
-                    };
-
-                    if let Some(value) = var_length.get_mut(length_name) {
-                        *value += length_count;
-                    } else {
-                        var_length.insert(length_name.to_owned(), length_count);
-                    }
-                }
-                descriptor::FieldLength::Fixed(length) => {
-                    fixed_length += length;
-                }
+            add_length(&mut var_length, &mut fixed_length, &length)?;
+            if !optional {
+                add_length(&mut req_var_length, &mut req_fixed_length, &length)?;
             }
         }
     }
@@ -573,22 +1146,148 @@ pub fn generate_data(descriptor: &descriptor::ProtoDescriptor) -> TemplateData {
         }
     }
 
-    max_x += DEFAULT_PADDING;
-    y += DEFAULT_SIZE_Y + DEFAULT_PADDING;
+    y += DEFAULT_SIZE_Y + group_bands.1 + variant_band + DEFAULT_PADDING;
 
-    TemplateData {
-        size: Components { x: max_x, y },
+    Ok(TemplateData {
+        scope,
+        offset: None,
+        size: Components {
+            x: size_x + DEFAULT_PADDING,
+            y,
+        },
         background: descriptor.style.background_color,
+        css_classes: descriptor.style.css_classes,
+        colors: descriptor.style.colors(),
+        stroke_width: DEFAULT_STROKE_WIDTH,
+        field_colors,
+        patterns,
+        dash_array: DEFAULT_DASH_ARRAY,
         static_fields,
         dynamic_fields,
         field_texts,
         field_ticks,
         field_lengths,
         wrap_lines,
+        repeat_brackets,
+        group_brackets,
+        field_badges,
         start_symbol,
+        dark_colors: descriptor.style.dark_theme.map(|theme| theme.colors()),
+        field_bounds,
+    })
+}
+
+/// Place the layers of a stack one below the other, connecting each payload to the next layer
+pub fn generate_stack_data(
+    descriptor: &descriptor::StackDescriptor,
+    layers: &mut [(TemplateData, Option<(usize, usize)>)],
+) -> StackData {
+    let mut connectors = Vec::new();
+    let mut size = Components { x: 0.0, y: 0.0 };
+
+    for (data, _) in layers.iter_mut() {
+        data.offset = Some(Components { x: 0.0, y: size.y });
+
+        size.y += data.size.y;
+        if data.size.x > size.x {
+            size.x = data.size.x;
+        }
+    }
+
+    for (i, window) in layers.windows(2).enumerate() {
+        let ((upper, payload), (lower, _)) = (&window[0], &window[1]);
+        let style = &descriptor.layers[i].protocol.style;
+
+        let Some((first, last)) = payload else {
+            continue;
+        };
+
+        // Bottom edge of the payload (its last row, if it spans several)
+        let payload = &upper.field_bounds[*first..=*last];
+        let bottom = payload
+            .iter()
+            .map(|bounds| bounds.end.y)
+            .fold(f64::MIN, f64::max);
+        let (start_x, end_x) = row_extent(payload.iter().filter(|b| b.end.y == bottom));
+
+        // Top edge of the next layer (its first row)
+        let top = lower
+            .field_bounds
+            .iter()
+            .map(|bounds| bounds.start.y)
+            .fold(f64::MAX, f64::min);
+        let (lower_start_x, lower_end_x) =
+            row_extent(lower.field_bounds.iter().filter(|b| b.start.y == top));
+
+        let upper_y = upper.offset.unwrap().y + bottom;
+        let lower_y = lower.offset.unwrap().y + top;
+
+        connectors.push(LayerConnector {
+            start: (
+                Components {
+                    x: start_x,
+                    y: upper_y,
+                },
+                Components {
+                    x: end_x,
+                    y: upper_y,
+                },
+            ),
+            end: (
+                Components {
+                    x: lower_start_x,
+                    y: lower_y,
+                },
+                Components {
+                    x: lower_end_x,
+                    y: lower_y,
+                },
+            ),
+            stroke: DEFAULT_STROKE_WIDTH,
+            color: style.subtitle_color,
+        });
+    }
+
+    // The stack is styled after its outermost layer
+    let style = &descriptor.layers[0].protocol.style;
+
+    StackData {
+        size,
+        background: style.background_color,
+        css_classes: style.css_classes,
+        colors: style.colors(),
+        stroke_width: DEFAULT_STROKE_WIDTH,
+        dash_array: DEFAULT_DASH_ARRAY,
+        connectors,
+        dark_colors: style.dark_theme.map(|theme| theme.colors()),
     }
 }
 
+/// Horizontal extent of a set of fields in the same row
+fn row_extent<'a>(bounds: impl Iterator<Item = &'a FieldBounds>) -> (f64, f64) {
+    bounds.fold((f64::MAX, f64::MIN), |(start, end), bounds| {
+        (start.min(bounds.start.x), end.max(bounds.end.x))
+    })
+}
+
+/// Add a field length to the position counters
+fn add_length(
+    var_length: &mut HashMap<String, usize>,
+    fixed_length: &mut usize,
+    length: &descriptor::FieldLength,
+) -> Result<(), Error> {
+    let overflow = || Error::FormatError(format!("Position after length {} overflows", length));
+    let (fixed, terms) = length.terms()?;
+
+    *fixed_length = fixed_length.checked_add(fixed).ok_or_else(overflow)?;
+    for (count, name) in terms {
+        let total = var_length.entry(name).or_insert(0);
+        *total = total.checked_add(count).ok_or_else(overflow)?;
+    }
+
+    Ok(())
+}
+
 /// Create the position subtitle string
 fn create_position_sub(var_length: &mut HashMap<String, usize>, fixed_length: usize) -> String {
     let mut result = String::new();
@@ -612,9 +1311,243 @@ fn create_position_sub(var_length: &mut HashMap<String, usize>, fixed_length: us
     result
 }
 
-/// Create a wrap line if needed
+/// Space taken by the subtitles on one side of a row (positions go above in network order)
+fn subtitles_size(
+    descriptor: &descriptor::ProtoDescriptor,
+    row: usize,
+    rows: usize,
+    above: bool,
+) -> f64 {
+    let has_subtitles = if above {
+        row == 0 || descriptor.elements.inner_subtitles
+    } else {
+        row == rows - 1 || descriptor.elements.inner_subtitles
+    };
+
+    if above == descriptor.elements.network_order {
+        if descriptor.elements.field_position && has_subtitles {
+            DEFAULT_TEXT_SIZE + DEFAULT_SUB_PADDING
+        } else {
+            0.0
+        }
+    } else if descriptor.elements.field_length && has_subtitles {
+        DEFAULT_LENGTH_SIZE + DEFAULT_TEXT_SIZE + DEFAULT_SUB_PADDING
+    } else {
+        0.0
+    }
+}
+
+/// Create the classes, background and fill pattern of a field
+fn field_style(
+    descriptor: &descriptor::ProtoDescriptor,
+    scope: Option<&str>,
+    field: &descriptor::FieldDescriptor,
+    field_colors: &mut Vec<FieldColor>,
+    patterns: &mut Vec<FillPattern>,
+) -> (String, String, HexColor, Option<String>) {
+    // Class unique to the field, derived from its name
+    let name_class = field_class(&field.name);
+    let mut classes = String::from("pv-field");
+    if !name_class.is_empty() {
+        classes.push(' ');
+        classes.push_str(&name_class);
+    }
+
+    if let Some(color) = field.color {
+        classes.push_str(" pv-colored");
+
+        // The fill is set through a class per color, as different fields may share a name class
+        if descriptor.style.css_classes {
+            let class = match field_colors.iter().find(|c| c.color == color) {
+                Some(field_color) => field_color.class.clone(),
+                None => {
+                    let class = format!("pv-color-{}", field_colors.len());
+                    field_colors.push(FieldColor {
+                        class: class.clone(),
+                        color,
+                    });
+                    class
+                }
+            };
+            classes.push(' ');
+            classes.push_str(&class);
+        }
+    }
+
+    let background = field.color.unwrap_or(descriptor.style.field_color);
+
+    // Reuse the pattern definition if another field has the same fill
+    let pattern = field.pattern.map(|kind| {
+        let id = match patterns
+            .iter()
+            .find(|p| p.kind == kind && p.background == background)
+        {
+            Some(pattern) => pattern.id.clone(),
+            None => {
+                let id = format!("{}-pattern-{}", scope.unwrap_or("pv"), patterns.len());
+                patterns.push(FillPattern {
+                    id: id.clone(),
+                    kind,
+                    background,
+                    colored: field.color.is_some(),
+                    color: descriptor.style.text_color,
+                    size: DEFAULT_PATTERN_SIZE,
+                    stroke: DEFAULT_PATTERN_STROKE,
+                });
+                id
+            }
+        };
+
+        classes.push_str(" pv-patterned ");
+        classes.push_str(&id);
+        id
+    });
+
+    if field.is_optional() {
+        classes.push_str(" pv-optional");
+    }
+
+    (classes, name_class, background, pattern)
+}
+
+/// Create the badges showing the byte and bit order set on a field, stacked from its top left corner
+fn create_badges(
+    descriptor: &descriptor::ProtoDescriptor,
+    field: &descriptor::FieldDescriptor,
+    x: f64,
+    y: f64,
+) -> Vec<FieldBadge> {
+    let endianness = field.endianness.map(|endianness| match endianness {
+        descriptor::Endianness::Big => "BE",
+        descriptor::Endianness::Little => "LE",
+    });
+    let bit_order = field.bit_order.map(|bit_order| match bit_order {
+        descriptor::BitOrder::MsbFirst => "MSB→",
+        descriptor::BitOrder::LsbFirst => "LSB→",
+    });
+
+    endianness
+        .into_iter()
+        .chain(bit_order)
+        .enumerate()
+        .map(|(i, text)| FieldBadge {
+            text: text.to_owned(),
+            coordinates: Components {
+                x: x + DEFAULT_BADGE_INSET,
+                y: y + DEFAULT_BADGE_INSET
+                    + i as f64 * (DEFAULT_BADGE_SIZE + DEFAULT_BADGE_INSET / 2.0),
+            },
+            size: Components {
+                x: text.chars().count() as f64 * DEFAULT_BADGE_CHAR_WIDTH + DEFAULT_BADGE_INSET,
+                y: DEFAULT_BADGE_SIZE,
+            },
+            text_size: DEFAULT_BADGE_TEXT_SIZE,
+            stroke: DEFAULT_STROKE_WIDTH / 2.0,
+            background: descriptor.style.background_color,
+            color: descriptor.style.subtitle_color,
+        })
+        .collect()
+}
+
+/// Size of a dynamic field with the given height
+fn dynamic_size(descriptor: &descriptor::ProtoDescriptor, height: f64) -> ComponentsDynamic {
+    let unit_width = descriptor.style.unit_width as f64;
+    let dyn_units = descriptor.style.dyn_units as f64;
+
+    let spacing = if DEFAULT_DYN_SPACING_UPPER * unit_width < DEFAULT_DYN_SPACING_VALUE {
+        if unit_width >= DEFAULT_DYN_SPACING_VALUE * 2.0 {
+            DEFAULT_DYN_SPACING_VALUE
+        } else {
+            DEFAULT_DYN_SPACING_LOWER * unit_width
+        }
+    } else {
+        DEFAULT_DYN_SPACING_UPPER * unit_width
+    };
+
+    ComponentsDynamic {
+        x1: (DEFAULT_DYN_LENGTH_1 * dyn_units).ceil() * unit_width - spacing / 2.0,
+        x2: (DEFAULT_DYN_LENGTH_2 * dyn_units).floor() * unit_width - spacing / 2.0,
+        spacing,
+        delta: DEFAULT_DYN_DELTA * unit_width,
+        y: height,
+    }
+}
+
+/// Create the bit range subtitle string, from the offset of the field in the protocol
+fn create_range_sub(
+    numbering: descriptor::BitNumbering,
+    network_order: bool,
+    offset: usize,
+    length: usize,
+    total_length: usize,
+) -> String {
+    // Significance of the leftmost bit of the field (the first field is on the left in network order)
+    let msb = if network_order {
+        total_length - 1 - offset
+    } else {
+        offset + length - 1
+    };
+    let lsb = msb + 1 - length;
+
+    let (left, right) = match numbering {
+        descriptor::BitNumbering::Lsb0 => (msb, lsb),
+        descriptor::BitNumbering::Msb0 => (total_length - 1 - msb, total_length - 1 - lsb),
+    };
+
+    if left == right {
+        format!("[{}]", left)
+    } else {
+        format!("[{}:{}]", left, right)
+    }
+}
+
+/// Create the CSS class of a field from its name
+fn field_class(name: &str) -> String {
+    let mut slug = String::new();
+
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        String::new()
+    } else {
+        format!("pv-field--{}", slug)
+    }
+}
+
+/// Assign a level to each group, so overlapping groups on the same side are drawn apart (outer groups get the lowest levels)
+fn group_levels(groups: &[descriptor::FieldGroup]) -> Vec<usize> {
+    let mut order = (0..groups.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| (groups[i].first, Reverse(groups[i].last)));
+
+    let mut levels = vec![0; groups.len()];
+    for (n, &i) in order.iter().enumerate() {
+        let used = order[..n]
+            .iter()
+            .filter(|&&j| {
+                groups[j].position == groups[i].position
+                    && groups[j].first <= groups[i].last
+                    && groups[i].first <= groups[j].last
+            })
+            .map(|&j| levels[j])
+            .collect::<Vec<_>>();
+
+        levels[i] = (0..).find(|level| !used.contains(level)).unwrap();
+    }
+
+    levels
+}
+
+/// Create a wrap line if needed (the bands are the space reserved above and below the rows)
 fn wrap_line(
     descriptor: &descriptor::ProtoDescriptor,
+    bands: (f64, f64),
     x: &mut f64,
     y: &mut f64,
 ) -> Option<WrapLine> {
@@ -625,6 +1558,10 @@ fn wrap_line(
     // Delta from start Y to center of the line
     let mut center_delta = DEFAULT_SUB_PADDING + DEFAULT_SIZE_Y / 2.0;
 
+    // Leave room for the group brackets and alternatives below the current row
+    *y += bands.1;
+    center_delta += bands.1;
+
     // Add new line with subtitle spacing if needed
     *y += DEFAULT_SIZE_Y;
     if descriptor.elements.wrap_line
@@ -654,6 +1591,9 @@ fn wrap_line(
         }
     }
 
+    // Leave room for the group brackets above the next row
+    *y += bands.0;
+
     *x = DEFAULT_PADDING;
 
     let end = Components {